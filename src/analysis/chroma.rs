@@ -0,0 +1,253 @@
+use crate::analysis::scan::{scan, MonoStft};
+use crate::cancel::CancelFlag;
+use crate::ffmpeg::decode::{DecodeRange, DecodeSource};
+use anyhow::Context;
+use ffmpeg_next::frame;
+use num_complex::Complex32;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+
+pub const PITCH_CLASSES: usize = 12;
+const PITCH_CLASS_NAMES: [&str; PITCH_CLASSES] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+/// Chromagrams with less energy than this, in squared linear amplitude, are treated as silence.
+const SILENCE: f32 = 1e-10;
+const KEY_FFT_SIZE: usize = 8192;
+
+/// The Krumhansl-Kessler key profiles, starting from the tonic.
+const MAJOR_PROFILE: [f32; PITCH_CLASSES] = [
+    6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88,
+];
+const MINOR_PROFILE: [f32; PITCH_CLASSES] = [
+    6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17,
+];
+
+/// How the FFT is folded into pitch classes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChromaConfig {
+    /// The lowest frequency in Hz that counts towards the chromagram.
+    pub min_frequency: f32,
+    /// The highest frequency in Hz that counts towards the chromagram.
+    pub max_frequency: f32,
+    /// Whether to estimate the key of the input in a pass before the render.
+    pub estimate_key: bool,
+}
+
+impl Default for ChromaConfig {
+    fn default() -> Self {
+        ChromaConfig {
+            min_frequency: 80.0,
+            max_frequency: 5000.0,
+            estimate_key: false,
+        }
+    }
+}
+
+/// Folds FFT bins into the energies of the 12 pitch classes, starting from C.
+#[derive(Debug, Clone)]
+pub struct Chroma {
+    /// The first bin that counts towards the chromagram.
+    start_bin: usize,
+    /// The pitch class of every bin from `start_bin` on.
+    classes: Vec<u8>,
+}
+
+impl Chroma {
+    pub fn new(config: &ChromaConfig, fft_size: usize, sample_rate: u32) -> Chroma {
+        let bin_count = fft_size / 2 + 1;
+        let bin_width = sample_rate as f32 / fft_size as f32;
+
+        // the DC bin has no pitch
+        let start_bin = ((config.min_frequency / bin_width).ceil() as usize).clamp(1, bin_count);
+        let end_bin =
+            ((config.max_frequency / bin_width).floor() as usize + 1).clamp(start_bin, bin_count);
+
+        let classes = (start_bin..end_bin)
+            .map(|bin| {
+                let midi_note = 69.0 + 12.0 * (bin as f32 * bin_width / 440.0).log2();
+                (midi_note.round() as i64).rem_euclid(PITCH_CLASSES as i64) as u8
+            })
+            .collect();
+
+        Chroma { start_bin, classes }
+    }
+
+    /// Adds the energy of each pitch class in `bins` onto `chroma`.
+    pub fn accumulate(&self, bins: &[Complex32], chroma: &mut [f32; PITCH_CLASSES]) {
+        for (bin, &class) in bins[self.start_bin..].iter().zip(self.classes.iter()) {
+            chroma[class as usize] += bin.norm_sqr();
+        }
+    }
+
+    /// Computes the chromagram of all channels together, scaled so the strongest pitch class is 1.
+    pub fn process<'a>(
+        &self,
+        channels: impl IntoIterator<Item = &'a [Complex32]>,
+        amplitude_scale: f32,
+        chroma: &mut [f32; PITCH_CLASSES],
+    ) {
+        chroma.fill(0.0);
+        for bins in channels {
+            self.accumulate(bins, chroma);
+        }
+
+        let max = chroma.iter().copied().fold(0.0, f32::max);
+        if max * amplitude_scale * amplitude_scale > SILENCE {
+            chroma.iter_mut().for_each(|energy| *energy /= max);
+        } else {
+            chroma.fill(0.0);
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub enum Mode {
+    Major,
+    Minor,
+}
+
+/// The estimated key of a whole track.
+#[derive(Debug, Copy, Clone, Serialize)]
+pub struct Key {
+    /// The pitch class of the tonic, from 0 for C up to 11 for B.
+    pub tonic: u8,
+    pub mode: Mode,
+    /// How well the track's chromagram matches the key's profile, from -1 to 1.
+    pub confidence: f32,
+}
+
+impl Key {
+    /// Finds the key whose profile correlates best with a chromagram.
+    pub fn estimate(chroma: &[f32; PITCH_CLASSES]) -> Option<Key> {
+        if chroma.iter().all(|&energy| energy <= 0.0) {
+            return None;
+        }
+
+        (0..PITCH_CLASSES)
+            .flat_map(|tonic| [(tonic, Mode::Major), (tonic, Mode::Minor)])
+            .map(|(tonic, mode)| {
+                let profile = match mode {
+                    Mode::Major => &MAJOR_PROFILE,
+                    Mode::Minor => &MINOR_PROFILE,
+                };
+                let rotated: Vec<f32> = (0..PITCH_CLASSES)
+                    .map(|class| profile[(class + PITCH_CLASSES - tonic) % PITCH_CLASSES])
+                    .collect();
+
+                Key {
+                    tonic: tonic as u8,
+                    mode,
+                    confidence: correlation(chroma, &rotated),
+                }
+            })
+            .max_by(|a, b| a.confidence.total_cmp(&b.confidence))
+    }
+
+    /// Decodes the given part of the input and estimates its key.
+    pub async fn analyze(
+        source: &DecodeSource,
+        range: DecodeRange,
+        cancel: CancelFlag,
+        config: &ChromaConfig,
+    ) -> anyhow::Result<Option<Key>> {
+        let mut analyzer: Option<KeyAnalyzer> = None;
+
+        scan(source, range, cancel, |audio| {
+            analyzer
+                .get_or_insert_with(|| KeyAnalyzer::new(config, audio.rate()))
+                .push(audio);
+            Ok(())
+        })
+        .await
+        .context("Scanning input for key")?;
+
+        Ok(analyzer.and_then(KeyAnalyzer::finish))
+    }
+}
+
+/// Builds the chromagram of a whole track to estimate its key from.
+pub struct KeyAnalyzer {
+    chroma: Chroma,
+    stft: MonoStft,
+    energies: [f32; PITCH_CLASSES],
+}
+
+impl KeyAnalyzer {
+    pub fn new(config: &ChromaConfig, sample_rate: u32) -> KeyAnalyzer {
+        KeyAnalyzer {
+            chroma: Chroma::new(config, KEY_FFT_SIZE, sample_rate),
+            stft: MonoStft::new(KEY_FFT_SIZE, KEY_FFT_SIZE / 2),
+            energies: [0.0; PITCH_CLASSES],
+        }
+    }
+
+    pub fn push(&mut self, audio: &frame::Audio) {
+        let (chroma, energies) = (&self.chroma, &mut self.energies);
+        self.stft
+            .push(audio, |bins| chroma.accumulate(bins, energies));
+    }
+
+    pub fn finish(mut self) -> Option<Key> {
+        let (chroma, energies) = (&self.chroma, &mut self.energies);
+        self.stft.finish(|bins| chroma.accumulate(bins, energies));
+
+        Key::estimate(&self.energies)
+    }
+}
+
+impl Display for Key {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mode = match self.mode {
+            Mode::Major => "major",
+            Mode::Minor => "minor",
+        };
+        write!(f, "{} {}", PITCH_CLASS_NAMES[self.tonic as usize], mode)
+    }
+}
+
+/// The Pearson correlation of two equally long sequences.
+fn correlation(a: &[f32], b: &[f32]) -> f32 {
+    let mean_a = a.iter().sum::<f32>() / a.len() as f32;
+    let mean_b = b.iter().sum::<f32>() / b.len() as f32;
+
+    let (mut covariance, mut variance_a, mut variance_b) = (0.0, 0.0, 0.0);
+    for (a, b) in a.iter().zip(b.iter()) {
+        covariance += (a - mean_a) * (b - mean_b);
+        variance_a += (a - mean_a) * (a - mean_a);
+        variance_b += (b - mean_b) * (b - mean_b);
+    }
+
+    if variance_a > 0.0 && variance_b > 0.0 {
+        covariance / (variance_a * variance_b).sqrt()
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    #[test]
+    fn triad_chromagrams_find_their_keys() {
+        // the notes of an A minor and a D major triad, with the tonic strongest
+        let mut a_minor = [0.0; PITCH_CLASSES];
+        a_minor[9] = 1.0;
+        a_minor[0] = 0.6;
+        a_minor[4] = 0.8;
+        let key = Key::estimate(&a_minor).unwrap();
+        assert_eq!((key.tonic, key.mode), (9, Mode::Minor));
+        assert_eq!(key.to_string(), "A minor");
+
+        let mut d_major = [0.0; PITCH_CLASSES];
+        d_major[2] = 1.0;
+        d_major[6] = 0.6;
+        d_major[9] = 0.8;
+        let key = Key::estimate(&d_major).unwrap();
+        assert_eq!((key.tonic, key.mode), (2, Mode::Major));
+
+        assert!(Key::estimate(&[0.0; PITCH_CLASSES]).is_none());
+    }
+}
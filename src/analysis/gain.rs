@@ -0,0 +1,146 @@
+use crate::analysis::spectrum::{db_to_amplitude, smoothing_coefficient};
+use crate::util::FrameRate;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+/// How band amplitudes are scaled before they are mapped into the 0..1 range.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GainConfig {
+    pub mode: GainMode,
+    /// The time in seconds the adaptive gain takes to drop when the input gets louder.
+    pub attack: f32,
+    /// The time in seconds the adaptive gain takes to rise when the input gets quieter.
+    pub release: f32,
+    /// The most the input is ever amplified, in dB.
+    pub max_gain_db: f32,
+}
+
+impl Default for GainConfig {
+    fn default() -> Self {
+        GainConfig {
+            mode: GainMode::Off,
+            attack: 0.05,
+            release: 5.0,
+            max_gain_db: 30.0,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub enum GainMode {
+    /// Band amplitudes are used as they are.
+    #[default]
+    Off,
+    /// The loudest band is followed over time and brought up to full scale.
+    Adaptive,
+    /// The whole track is scaled by the same amount, so its loudest band reaches full scale.
+    ///
+    /// Needs the track to be analyzed before the render.
+    Static,
+}
+
+/// Finds the gain that brings the loudest band of each frame up to full scale.
+#[derive(Debug, Clone)]
+pub struct AutoGain {
+    mode: GainMode,
+    attack: f32,
+    release: f32,
+    max_gain: f32,
+    /// The followed peak amplitude of the adaptive mode.
+    envelope: f32,
+    /// The gain of the static mode.
+    static_gain: f32,
+}
+
+impl AutoGain {
+    /// Creates the gain stage, given the largest band amplitude of the whole track if it is known.
+    pub fn new(config: &GainConfig, frame_rate: FrameRate, track_peak: Option<f32>) -> AutoGain {
+        let fps = frame_rate.as_f64() as f32;
+        let max_gain = db_to_amplitude(config.max_gain_db.max(0.0));
+
+        AutoGain {
+            mode: config.mode,
+            attack: smoothing_coefficient(config.attack, fps),
+            release: smoothing_coefficient(config.release, fps),
+            max_gain,
+            envelope: 0.0,
+            static_gain: track_peak.map_or(1.0, |peak| gain_for(peak, max_gain)),
+        }
+    }
+
+    /// The gain of the static mode, or 1 for the other modes.
+    pub fn static_gain(&self) -> f32 {
+        match self.mode {
+            GainMode::Static => self.static_gain,
+            _ => 1.0,
+        }
+    }
+
+    /// Gets the gain for a frame whose loudest band has the given amplitude.
+    pub fn process(&mut self, peak: f32) -> f32 {
+        match self.mode {
+            GainMode::Off => 1.0,
+            GainMode::Static => self.static_gain,
+            GainMode::Adaptive => {
+                let coefficient = if peak > self.envelope {
+                    self.attack
+                } else {
+                    self.release
+                };
+                self.envelope = peak + coefficient * (self.envelope - peak);
+
+                gain_for(self.envelope, self.max_gain)
+            }
+        }
+    }
+}
+
+fn gain_for(peak: f32, max_gain: f32) -> f32 {
+    if peak * max_gain > 1.0 {
+        1.0 / peak
+    } else {
+        max_gain
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    #[test]
+    fn quiet_input_is_brought_up_to_full_scale() {
+        let frame_rate = FrameRate::new(30, 1).unwrap();
+        let config = GainConfig {
+            mode: GainMode::Adaptive,
+            ..Default::default()
+        };
+
+        let mut gain = AutoGain::new(&config, frame_rate, None);
+        let mut last = 0.0;
+        for _ in 0..30 {
+            last = gain.process(0.1);
+        }
+        assert!((last - 10.0).abs() < 0.01, "adaptive gain is {}", last);
+
+        // a sudden jump is caught within the attack time, and released slowly afterwards
+        for _ in 0..15 {
+            last = gain.process(0.5);
+        }
+        assert!((last - 2.0).abs() < 0.01, "adaptive gain is {}", last);
+        last = gain.process(0.1);
+        assert!(last < 2.1, "adaptive gain is {}", last);
+
+        // silence is never amplified more than the maximum
+        let mut gain = AutoGain::new(&config, frame_rate, None);
+        assert!((gain.process(0.0) - db_to_amplitude(30.0)).abs() < 1e-3);
+
+        let config = GainConfig {
+            mode: GainMode::Static,
+            ..Default::default()
+        };
+        let mut gain = AutoGain::new(&config, frame_rate, Some(0.25));
+        assert_eq!(gain.process(1.0), 4.0);
+        assert_eq!(gain.static_gain(), 4.0);
+    }
+}
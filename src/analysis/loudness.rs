@@ -0,0 +1,517 @@
+use crate::analysis::scan::scan;
+use crate::cancel::CancelFlag;
+use crate::ffmpeg::decode::{DecodeRange, DecodeSource};
+use crate::util::Timestamp;
+use anyhow::Context;
+use ffmpeg_next::frame;
+use serde::Serialize;
+use std::f64::consts::PI;
+use std::fmt::{Display, Formatter};
+
+/// Levels are never reported below this many dB, so silence stays finite.
+pub const MIN_DB: f32 = -120.0;
+/// The length in seconds of the momentary loudness window.
+pub const MOMENTARY_WINDOW: f64 = 0.4;
+/// The length in seconds of the short-term loudness window.
+const SHORT_TERM_WINDOW: f64 = 3.0;
+/// The time in seconds between the gating blocks of the loudness report.
+const BLOCK_STEP: f64 = 0.1;
+const ABSOLUTE_GATE: f64 = -70.0;
+/// How far below the ungated loudness blocks are dropped from the integrated loudness.
+const INTEGRATED_RELATIVE_GATE: f64 = -10.0;
+/// How far below the ungated loudness blocks are dropped from the loudness range.
+const RANGE_RELATIVE_GATE: f64 = -20.0;
+const OVERSAMPLING: usize = 4;
+const TAPS_PER_PHASE: usize = 12;
+
+/// The loudness of a single video frame.
+#[derive(Debug, Clone, Default)]
+pub struct Loudness {
+    /// The levels of each channel on its own.
+    pub channels: Vec<ChannelLoudness>,
+    /// The loudness of all channels together over the last 400 ms, in LUFS.
+    pub momentary: f32,
+    /// The loudness of all channels together over the last 3 s, in LUFS.
+    pub short_term: f32,
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct ChannelLoudness {
+    /// The root mean square of the frame's samples, as a linear amplitude.
+    pub rms: f32,
+    /// The largest sample in the frame, as a linear amplitude.
+    pub peak: f32,
+    /// The largest value between the samples of the frame, as a linear amplitude. At least `peak`.
+    pub true_peak: f32,
+    /// The loudness of this channel over the last 400 ms, in LUFS.
+    #[allow(dead_code)]
+    pub momentary: f32,
+    /// The loudness of this channel over the last 3 s, in LUFS.
+    #[allow(dead_code)]
+    pub short_term: f32,
+}
+
+/// Measures the levels and R128 loudness of a single channel, one block of samples at a time.
+pub struct ChannelMeter {
+    k_weighting: KWeighting,
+    true_peak: TruePeak,
+    /// The K-weighted squares of the last 3 s of samples.
+    squares: Vec<f64>,
+    position: usize,
+    momentary_len: usize,
+    momentary_sum: f64,
+    short_term_sum: f64,
+}
+
+impl ChannelMeter {
+    pub fn new(sample_rate: u32) -> ChannelMeter {
+        let short_term_len = ((SHORT_TERM_WINDOW * sample_rate as f64).round() as usize).max(2);
+
+        ChannelMeter {
+            k_weighting: KWeighting::new(sample_rate),
+            true_peak: TruePeak::new(),
+            squares: vec![0.0; short_term_len],
+            position: 0,
+            momentary_len: ((MOMENTARY_WINDOW * sample_rate as f64).round() as usize)
+                .clamp(1, short_term_len - 1),
+            momentary_sum: 0.0,
+            short_term_sum: 0.0,
+        }
+    }
+
+    /// Measures the next block of samples.
+    pub fn process(&mut self, samples: &[f32]) -> ChannelLoudness {
+        let short_term_len = self.squares.len();
+
+        let mut sum = 0.0f64;
+        let mut peak = 0.0f32;
+        let mut true_peak = 0.0f32;
+        for &sample in samples {
+            sum += sample as f64 * sample as f64;
+            peak = peak.max(sample.abs());
+            true_peak = true_peak.max(self.true_peak.process(sample));
+
+            let weighted = self.k_weighting.process(sample as f64);
+            let square = weighted * weighted;
+
+            let momentary_oldest =
+                (self.position + short_term_len - self.momentary_len) % short_term_len;
+            self.momentary_sum += square - self.squares[momentary_oldest];
+            self.short_term_sum += square - self.squares[self.position];
+            self.squares[self.position] = square;
+            self.position = (self.position + 1) % short_term_len;
+        }
+
+        ChannelLoudness {
+            rms: (sum / samples.len().max(1) as f64).sqrt() as f32,
+            peak,
+            true_peak: true_peak.max(peak),
+            momentary: power_to_lufs(self.momentary_power()) as f32,
+            short_term: power_to_lufs(self.short_term_power()) as f32,
+        }
+    }
+
+    /// The mean square of the K-weighted samples of the last 400 ms.
+    pub fn momentary_power(&self) -> f64 {
+        self.momentary_sum.max(0.0) / self.momentary_len as f64
+    }
+
+    /// The mean square of the K-weighted samples of the last 3 s.
+    pub fn short_term_power(&self) -> f64 {
+        self.short_term_sum.max(0.0) / self.squares.len() as f64
+    }
+}
+
+/// Converts the summed K-weighted mean squares of all channels into LUFS.
+///
+/// Every channel has a weight of 1, which is correct for mono and stereo.
+pub fn power_to_lufs(power: f64) -> f64 {
+    if power > 0.0 {
+        (-0.691 + 10.0 * power.log10()).max(MIN_DB as f64)
+    } else {
+        MIN_DB as f64
+    }
+}
+
+fn lufs_to_power(lufs: f64) -> f64 {
+    10f64.powf((lufs + 0.691) / 10.0)
+}
+
+pub fn amplitude_to_db(amplitude: f64) -> f64 {
+    if amplitude > 0.0 {
+        (20.0 * amplitude.log10()).max(MIN_DB as f64)
+    } else {
+        MIN_DB as f64
+    }
+}
+
+/// The two-stage K-weighting filter from ITU-R BS.1770, calculated for any sample rate.
+struct KWeighting {
+    stages: [Biquad; 2],
+}
+
+impl KWeighting {
+    fn new(sample_rate: u32) -> KWeighting {
+        let rate = sample_rate as f64;
+
+        // the high shelf modelling the acoustic effect of the head
+        let f0 = 1681.974450955533;
+        let gain = 3.999843853973347;
+        let q = 0.7071752369554196;
+        let k = (PI * f0 / rate).tan();
+        let vh = 10f64.powf(gain / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Biquad::new(
+            [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        );
+
+        // the high pass of the revised low-frequency B-curve
+        let f0 = 38.13547087602444;
+        let q = 0.5003270373238773;
+        let k = (PI * f0 / rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        let high_pass = Biquad::new(
+            [1.0, -2.0, 1.0],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        );
+
+        KWeighting {
+            stages: [shelf, high_pass],
+        }
+    }
+
+    fn process(&mut self, sample: f64) -> f64 {
+        self.stages
+            .iter_mut()
+            .fold(sample, |sample, stage| stage.process(sample))
+    }
+}
+
+/// A second-order IIR filter in transposed direct form II.
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    state: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Biquad {
+        Biquad {
+            b,
+            a,
+            state: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, input: f64) -> f64 {
+        let output = self.b[0] * input + self.state[0];
+        self.state[0] = self.b[1] * input - self.a[0] * output + self.state[1];
+        self.state[1] = self.b[2] * input - self.a[1] * output;
+        output
+    }
+}
+
+/// Finds the peaks between samples by oversampling with a windowed-sinc interpolator.
+struct TruePeak {
+    phases: [[f32; TAPS_PER_PHASE]; OVERSAMPLING],
+    history: [f32; TAPS_PER_PHASE],
+}
+
+impl TruePeak {
+    fn new() -> TruePeak {
+        let taps = OVERSAMPLING * TAPS_PER_PHASE;
+        let center = (taps - 1) as f64 / 2.0;
+
+        let mut phases = [[0.0; TAPS_PER_PHASE]; OVERSAMPLING];
+        for (phase, coefficients) in phases.iter_mut().enumerate() {
+            for (tap, coefficient) in coefficients.iter_mut().enumerate() {
+                let n = (tap * OVERSAMPLING + phase) as f64;
+                let x = (n - center) / OVERSAMPLING as f64;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    (PI * x).sin() / (PI * x)
+                };
+                let window = 0.5 - 0.5 * (2.0 * PI * (n + 0.5) / taps as f64).cos();
+                *coefficient = (sinc * window) as f32;
+            }
+
+            // every phase passes DC unchanged
+            let sum: f32 = coefficients.iter().sum();
+            coefficients
+                .iter_mut()
+                .for_each(|coefficient| *coefficient /= sum);
+        }
+
+        TruePeak {
+            phases,
+            history: [0.0; TAPS_PER_PHASE],
+        }
+    }
+
+    /// Takes the next sample and returns the largest absolute value of the oversampled signal since the last one.
+    fn process(&mut self, sample: f32) -> f32 {
+        self.history.copy_within(0..TAPS_PER_PHASE - 1, 1);
+        self.history[0] = sample;
+
+        self.phases
+            .iter()
+            .map(|coefficients| {
+                coefficients
+                    .iter()
+                    .zip(self.history.iter())
+                    .map(|(coefficient, sample)| coefficient * sample)
+                    .sum::<f32>()
+                    .abs()
+            })
+            .fold(0.0, f32::max)
+    }
+}
+
+/// The loudness of a whole input, following EBU R128.
+#[derive(Debug, Clone, Serialize)]
+pub struct LoudnessReport {
+    pub duration: Timestamp,
+    /// The gated loudness of the whole input in LUFS, if any of it is louder than the absolute gate.
+    pub integrated: Option<f64>,
+    /// The spread of the short-term loudness in LU, if any of it is louder than the absolute gate.
+    pub range: Option<f64>,
+    pub max_momentary: f64,
+    pub max_short_term: f64,
+    /// The largest true peak of any channel in dBTP.
+    pub true_peak: f64,
+    pub channels: Vec<ChannelReport>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChannelReport {
+    /// The root mean square of the whole channel in dBFS.
+    pub rms: f64,
+    /// The largest sample in dBFS.
+    pub peak: f64,
+    /// The largest true peak in dBTP.
+    pub true_peak: f64,
+}
+
+impl LoudnessReport {
+    /// Decodes the given part of the input and measures its loudness.
+    pub async fn analyze(
+        source: &DecodeSource,
+        range: DecodeRange,
+        cancel: CancelFlag,
+    ) -> anyhow::Result<LoudnessReport> {
+        let mut meter: Option<TrackMeter> = None;
+
+        scan(source, range, cancel, |audio| {
+            meter
+                .get_or_insert_with(|| TrackMeter::new(audio.rate(), audio.planes()))
+                .push(audio);
+            Ok(())
+        })
+        .await
+        .context("Scanning input for loudness")?;
+
+        let meter = meter.unwrap_or_else(|| TrackMeter::new(48000, 0));
+        Ok(meter.finish())
+    }
+}
+
+impl Display for LoudnessReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let optional =
+            |value: Option<f64>| value.map_or("-".to_string(), |value| format!("{:.1}", value));
+
+        writeln!(f, "Duration:            {}", self.duration)?;
+        writeln!(f, "Integrated loudness: {} LUFS", optional(self.integrated))?;
+        writeln!(f, "Loudness range:      {} LU", optional(self.range))?;
+        writeln!(f, "Max momentary:       {:.1} LUFS", self.max_momentary)?;
+        writeln!(f, "Max short-term:      {:.1} LUFS", self.max_short_term)?;
+        writeln!(f, "True peak:           {:.1} dBTP", self.true_peak)?;
+        for (index, channel) in self.channels.iter().enumerate() {
+            writeln!(
+                f,
+                "Channel {}:           {:.1} dBFS RMS, {:.1} dBFS peak, {:.1} dBTP",
+                index, channel.rms, channel.peak, channel.true_peak
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Collects the statistics of a whole input for its [LoudnessReport].
+pub struct TrackMeter {
+    sample_rate: u32,
+    channels: Vec<ChannelMeter>,
+    sums: Vec<f64>,
+    peaks: Vec<f32>,
+    true_peaks: Vec<f32>,
+    samples: usize,
+    step: usize,
+    until_step: usize,
+    momentary_blocks: Vec<f64>,
+    short_term_blocks: Vec<f64>,
+}
+
+impl TrackMeter {
+    pub fn new(sample_rate: u32, channels: usize) -> TrackMeter {
+        let step = ((BLOCK_STEP * sample_rate as f64).round() as usize).max(1);
+
+        TrackMeter {
+            sample_rate,
+            channels: (0..channels)
+                .map(|_| ChannelMeter::new(sample_rate))
+                .collect(),
+            sums: vec![0.0; channels],
+            peaks: vec![0.0; channels],
+            true_peaks: vec![0.0; channels],
+            samples: 0,
+            step,
+            until_step: step,
+            momentary_blocks: vec![],
+            short_term_blocks: vec![],
+        }
+    }
+
+    pub fn push(&mut self, audio: &frame::Audio) {
+        let len = audio.samples();
+        let mut offset = 0;
+        while offset < len {
+            let block = self.until_step.min(len - offset);
+
+            for (index, meter) in self.channels.iter_mut().enumerate() {
+                let levels = meter.process(&audio.plane::<f32>(index)[offset..offset + block]);
+                self.sums[index] += levels.rms as f64 * levels.rms as f64 * block as f64;
+                self.peaks[index] = self.peaks[index].max(levels.peak);
+                self.true_peaks[index] = self.true_peaks[index].max(levels.true_peak);
+            }
+
+            offset += block;
+            self.samples += block;
+            self.until_step -= block;
+
+            if self.until_step == 0 {
+                self.until_step = self.step;
+
+                // only whole windows count
+                let elapsed = self.samples as f64 / self.sample_rate as f64;
+                if elapsed >= MOMENTARY_WINDOW {
+                    self.momentary_blocks.push(
+                        self.channels
+                            .iter()
+                            .map(|meter| meter.momentary_power())
+                            .sum(),
+                    );
+                }
+                if elapsed >= SHORT_TERM_WINDOW {
+                    self.short_term_blocks.push(
+                        self.channels
+                            .iter()
+                            .map(|meter| meter.short_term_power())
+                            .sum(),
+                    );
+                }
+            }
+        }
+    }
+
+    /// The time in seconds between consecutive gating blocks.
+    pub fn block_step(&self) -> f64 {
+        self.step as f64 / self.sample_rate as f64
+    }
+
+    /// The mean square power of every momentary block so far, the first one ending [MOMENTARY_WINDOW] seconds into
+    /// the input.
+    pub fn momentary_blocks(&self) -> &[f64] {
+        &self.momentary_blocks
+    }
+
+    pub fn finish(self) -> LoudnessReport {
+        let max_lufs = |blocks: &[f64]| power_to_lufs(blocks.iter().copied().fold(0.0, f64::max));
+
+        LoudnessReport {
+            duration: Timestamp::from_samples(self.samples as i64, self.sample_rate),
+            integrated: integrated_loudness(&self.momentary_blocks),
+            range: loudness_range(&self.short_term_blocks),
+            max_momentary: max_lufs(&self.momentary_blocks),
+            max_short_term: max_lufs(&self.short_term_blocks),
+            true_peak: amplitude_to_db(self.true_peaks.iter().copied().fold(0.0, f32::max) as f64),
+            channels: (0..self.channels.len())
+                .map(|index| ChannelReport {
+                    rms: amplitude_to_db((self.sums[index] / self.samples.max(1) as f64).sqrt()),
+                    peak: amplitude_to_db(self.peaks[index] as f64),
+                    true_peak: amplitude_to_db(self.true_peaks[index] as f64),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Keeps the blocks above the absolute gate, then those above the relative gate derived from them.
+fn gate(blocks: &[f64], relative_gate: f64) -> Vec<f64> {
+    let absolute = lufs_to_power(ABSOLUTE_GATE);
+    let loud: Vec<f64> = blocks
+        .iter()
+        .copied()
+        .filter(|&power| power > absolute)
+        .collect();
+    if loud.is_empty() {
+        return loud;
+    }
+
+    let mean = loud.iter().sum::<f64>() / loud.len() as f64;
+    let relative = lufs_to_power(power_to_lufs(mean) + relative_gate);
+    loud.into_iter().filter(|&power| power > relative).collect()
+}
+
+fn integrated_loudness(momentary_blocks: &[f64]) -> Option<f64> {
+    let gated = gate(momentary_blocks, INTEGRATED_RELATIVE_GATE);
+    (!gated.is_empty()).then(|| power_to_lufs(gated.iter().sum::<f64>() / gated.len() as f64))
+}
+
+/// The difference between the 10th and 95th percentiles of the gated short-term loudness, following EBU Tech 3342.
+fn loudness_range(short_term_blocks: &[f64]) -> Option<f64> {
+    let mut gated: Vec<f64> = gate(short_term_blocks, RANGE_RELATIVE_GATE)
+        .into_iter()
+        .map(power_to_lufs)
+        .collect();
+    if gated.is_empty() {
+        return None;
+    }
+
+    gated.sort_by(f64::total_cmp);
+    let percentile = |p: f64| gated[((gated.len() - 1) as f64 * p).round() as usize];
+    Some(percentile(0.95) - percentile(0.1))
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    #[test]
+    fn full_scale_sine_reads_as_reference_loudness() {
+        // a 0 dBFS 997 Hz sine in one channel reads as -3.01 LUFS
+        let sample_rate = 48000;
+        let samples: Vec<f32> = (0..sample_rate * 4)
+            .map(|i| (2.0 * PI * 997.0 * i as f64 / sample_rate as f64).sin() as f32)
+            .collect();
+
+        let mut meter = ChannelMeter::new(sample_rate);
+        let levels = meter.process(&samples);
+
+        assert!((levels.momentary + 3.01).abs() < 0.05, "{:?}", levels);
+        assert!((levels.short_term + 3.01).abs() < 0.05, "{:?}", levels);
+        assert!((levels.rms - 0.5f32.sqrt()).abs() < 0.001, "{:?}", levels);
+        assert!(
+            levels.true_peak >= levels.peak && levels.true_peak < 1.01,
+            "{:?}",
+            levels
+        );
+    }
+}
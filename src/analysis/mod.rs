@@ -0,0 +1,360 @@
+//! This module contains the audio analysis that sits between the decoder and the visualizers
+//!
+//! The analysis describes more of the audio than the built-in visualizers draw. Fields that none of them read yet are
+//! kept for new visualizers, and are marked with `#[allow(dead_code)]` one by one.
+
+use crate::analysis::chroma::{Chroma, ChromaConfig, PITCH_CLASSES};
+use crate::analysis::gain::{AutoGain, GainConfig};
+use crate::analysis::loudness::{power_to_lufs, ChannelLoudness, ChannelMeter, Loudness};
+use crate::analysis::rhythm::{spectral_flux, Rhythm, RhythmConfig, RhythmTracker};
+use crate::analysis::ring::SampleRing;
+use crate::analysis::spectrum::{Spectrum, SpectrumConfig};
+use crate::analysis::stereo::{Stereo, StereoAnalyzer};
+use crate::analysis::window::WindowFunction;
+use crate::util::{FrameRate, MultiSlice, Timestamp};
+use anyhow::Context;
+use ffmpeg_next::frame;
+use num_complex::Complex32;
+use rayon::prelude::*;
+use realfft::{RealFftPlanner, RealToComplex};
+use std::sync::Arc;
+
+pub mod chroma;
+pub mod gain;
+pub mod loudness;
+pub mod rhythm;
+pub mod ring;
+pub mod scan;
+pub mod spectrum;
+pub mod stereo;
+pub mod tempo;
+pub mod track;
+pub mod window;
+
+#[derive(Debug, Clone)]
+pub struct AnalyzerArgs {
+    pub channels: usize,
+    pub sample_rate: u32,
+    pub frame_rate: FrameRate,
+    pub fft_size: usize,
+    pub window: WindowFunction,
+    pub spectrum: SpectrumConfig,
+    pub rhythm: RhythmConfig,
+    pub chroma: ChromaConfig,
+    pub gain: GainConfig,
+    /// The largest band amplitude of the whole track, if it was analyzed before the render.
+    pub track_peak: Option<f32>,
+    /// Where in the input the first analyzed sample lies.
+    pub origin: Timestamp,
+    /// How many samples beyond the FFT window need to be kept around for lookahead.
+    pub history: usize,
+}
+
+/// Everything the analysis knows about a single video frame.
+#[derive(Clone)]
+pub struct AnalysisFrame {
+    /// Where in the input this frame starts.
+    pub time: Timestamp,
+    /// The samples belonging to this frame, per channel.
+    pub samples: MultiSlice<f32>,
+    /// The raw FFT of the window centered on this frame, per channel.
+    pub fft: MultiSlice<Complex32>,
+    /// The smoothed band levels in the range 0..1, per channel, after the gain.
+    pub spectrum: MultiSlice<f32>,
+    /// The gain band amplitudes were multiplied by before they were mapped into the 0..1 range.
+    pub gain: f32,
+    /// The energy of each pitch class from C to B across all channels, scaled so the strongest one is 1.
+    pub chroma: [f32; PITCH_CLASSES],
+    /// The levels and loudness of this frame.
+    pub loudness: Loudness,
+    /// The onsets and beats of this frame, detected across all channels.
+    pub rhythm: Rhythm,
+    /// Where the energy of this frame sits between the left and right channels.
+    pub stereo: Stereo,
+}
+
+impl AnalysisFrame {
+    /// Copies this frame with only the given channels, in the given order.
+    ///
+    /// Everything that is analyzed across all channels, like the chromagram and the stereo field, is kept as is.
+    pub fn select_channels(&self, channels: &[usize]) -> AnalysisFrame {
+        AnalysisFrame {
+            time: self.time,
+            samples: self.samples.select(channels),
+            fft: self.fft.select(channels),
+            spectrum: self.spectrum.select(channels),
+            gain: self.gain,
+            chroma: self.chroma,
+            loudness: Loudness {
+                channels: channels
+                    .iter()
+                    .map(|&index| self.loudness.channels[index])
+                    .collect(),
+                ..self.loudness
+            },
+            rhythm: self.rhythm,
+            stereo: self.stereo.clone(),
+        }
+    }
+}
+
+/// Turns the decoded audio stream into per-video-frame sample blocks and spectra.
+///
+/// Each video frame's FFT window is centered on the frame's timestamp, so consecutive windows overlap whenever the
+/// FFT size is larger than the number of samples per frame. Channels are analyzed in parallel on the rayon thread pool.
+pub struct Analyzer {
+    sample_rate: u32,
+    origin: Timestamp,
+    fft_size: usize,
+    channels: Vec<ChannelAnalyzer>,
+    spectrum: Spectrum,
+    chroma: Chroma,
+    rhythm: RhythmTracker,
+    gain: AutoGain,
+    stereo: StereoAnalyzer,
+    frame: AnalysisFrame,
+}
+
+/// The state a single channel needs to be analyzed independently of the others.
+struct ChannelAnalyzer {
+    ring: SampleRing,
+    window: Arc<[f32]>,
+    fft: Arc<dyn RealToComplex<f32>>,
+    fft_input: Vec<f32>,
+    fft_scratch: Vec<Complex32>,
+    /// The compressed bin magnitudes of the previous frame, for the spectral flux.
+    magnitudes: Vec<f32>,
+    /// The band amplitudes of the current frame, before the gain.
+    amplitudes: Vec<f32>,
+    meter: ChannelMeter,
+}
+
+impl ChannelAnalyzer {
+    fn new(
+        window: Arc<[f32]>,
+        fft: Arc<dyn RealToComplex<f32>>,
+        capacity: usize,
+        sample_rate: u32,
+        bands: usize,
+    ) -> ChannelAnalyzer {
+        ChannelAnalyzer {
+            ring: SampleRing::new(capacity),
+            window,
+            fft_input: fft.make_input_vec(),
+            fft_scratch: fft.make_scratch_vec(),
+            magnitudes: vec![0.0; fft.len() / 2 + 1],
+            amplitudes: vec![0.0; bands],
+            meter: ChannelMeter::new(sample_rate),
+            fft,
+        }
+    }
+
+    /// Analyzes one frame of this channel, returning its spectral flux.
+    fn analyze(
+        &mut self,
+        frame_start: i64,
+        spectrum: &Spectrum,
+        samples: &mut [f32],
+        fft_out: &mut [Complex32],
+        loudness: &mut ChannelLoudness,
+    ) -> anyhow::Result<f32> {
+        self.ring.read(frame_start, samples);
+        *loudness = self.meter.process(samples);
+
+        // the window is centered on the start of the frame
+        let window_start = frame_start - self.fft_input.len() as i64 / 2;
+
+        self.ring.read(window_start, &mut self.fft_input);
+        for (sample, coefficient) in self.fft_input.iter_mut().zip(self.window.iter()) {
+            *sample *= coefficient;
+        }
+
+        self.fft
+            .process_with_scratch(&mut self.fft_input, fft_out, &mut self.fft_scratch)
+            .context("Performing Fast Fourier Transform")?;
+
+        spectrum.amplitudes(fft_out, &mut self.amplitudes);
+
+        Ok(spectral_flux(
+            fft_out,
+            &mut self.magnitudes,
+            spectrum.amplitude_scale(),
+        ))
+    }
+}
+
+impl Analyzer {
+    pub fn new(args: AnalyzerArgs) -> Analyzer {
+        let mut fft_planner = RealFftPlanner::<f32>::new();
+        let fft = fft_planner.plan_fft_forward(args.fft_size);
+
+        let spectrum = Spectrum::new(
+            &args.spectrum,
+            args.fft_size,
+            args.sample_rate,
+            args.frame_rate,
+        );
+
+        let frame = AnalysisFrame {
+            time: args.origin,
+            samples: MultiSlice::new((0..args.channels).map(|_| vec![]).collect()),
+            fft: MultiSlice::new((0..args.channels).map(|_| fft.make_output_vec()).collect()),
+            spectrum: MultiSlice::new(
+                (0..args.channels)
+                    .map(|_| vec![0.0; spectrum.band_count()])
+                    .collect(),
+            ),
+            gain: 1.0,
+            chroma: [0.0; PITCH_CLASSES],
+            loudness: Loudness {
+                channels: vec![ChannelLoudness::default(); args.channels],
+                ..Default::default()
+            },
+            rhythm: Rhythm::default(),
+            stereo: Stereo::new(spectrum.band_count()),
+        };
+
+        let window: Arc<[f32]> = args.window.coefficients(args.fft_size).into();
+
+        Analyzer {
+            sample_rate: args.sample_rate,
+            origin: args.origin,
+            fft_size: args.fft_size,
+            channels: (0..args.channels)
+                .map(|_| {
+                    ChannelAnalyzer::new(
+                        window.clone(),
+                        fft.clone(),
+                        args.fft_size + args.history,
+                        args.sample_rate,
+                        spectrum.band_count(),
+                    )
+                })
+                .collect(),
+            spectrum,
+            chroma: Chroma::new(&args.chroma, args.fft_size, args.sample_rate),
+            rhythm: RhythmTracker::new(&args.rhythm, args.frame_rate),
+            gain: AutoGain::new(&args.gain, args.frame_rate, args.track_peak),
+            stereo: StereoAnalyzer::new(args.fft_size / 2 + 1, frame.spectrum[0].len()),
+            frame,
+        }
+    }
+
+    pub fn fft_size(&self) -> usize {
+        self.fft_size
+    }
+
+    /// The number of frequency bins in each channel's spectrum.
+    pub fn bins(&self) -> usize {
+        self.fft_size / 2 + 1
+    }
+
+    pub fn spectrum(&self) -> &Spectrum {
+        &self.spectrum
+    }
+
+    pub fn auto_gain(&self) -> &AutoGain {
+        &self.gain
+    }
+
+    /// The absolute index one past the last sample pushed into this analyzer.
+    pub fn end(&self) -> i64 {
+        self.channels[0].ring.end()
+    }
+
+    /// Gets how far past the start of a frame samples must be available before that frame can be analyzed.
+    pub fn lookahead(&self) -> i64 {
+        self.fft_size as i64 / 2
+    }
+
+    pub fn push(&mut self, audio: &frame::Audio) {
+        // planar audio should always have channels == planes
+        for (plane_index, channel) in self.channels.iter_mut().enumerate() {
+            channel.ring.push(audio.plane::<f32>(plane_index));
+        }
+    }
+
+    /// Like [Analyzer::push], but with the samples of each channel already split into slices.
+    pub fn push_planes(&mut self, planes: &[&[f32]]) {
+        for (samples, channel) in planes.iter().zip(self.channels.iter_mut()) {
+            channel.ring.push(samples);
+        }
+    }
+
+    /// Analyzes the video frame covering the samples `frame_start..frame_end`.
+    pub fn analyze(&mut self, frame_start: i64, frame_end: i64) -> anyhow::Result<&AnalysisFrame> {
+        let frame_end = frame_end.min(self.end()).max(frame_start);
+
+        self.frame.time =
+            self.origin + Timestamp::from_samples(frame_start.max(0), self.sample_rate);
+
+        let samples = self.frame.samples.vecs_mut();
+        for channel_samples in samples.iter_mut() {
+            channel_samples.resize((frame_end - frame_start) as usize, 0.0);
+        }
+
+        let spectrum = &self.spectrum;
+
+        let flux = self
+            .channels
+            .par_iter_mut()
+            .zip(samples.par_iter_mut())
+            .zip(self.frame.fft.vecs_mut().par_iter_mut())
+            .zip(self.frame.loudness.channels.par_iter_mut())
+            .map(|(((channel, samples), fft_out), loudness)| {
+                channel.analyze(frame_start, spectrum, samples, fft_out, loudness)
+            })
+            .try_reduce(|| 0.0, |a, b| Ok(a + b))?;
+
+        // the gain depends on the loudest band of any channel
+        let peak = self
+            .channels
+            .iter()
+            .flat_map(|channel| channel.amplitudes.iter())
+            .copied()
+            .fold(0.0, f32::max);
+        self.frame.gain = self.gain.process(peak);
+
+        for (channel, levels) in self
+            .channels
+            .iter()
+            .zip(self.frame.spectrum.vecs_mut().iter_mut())
+        {
+            self.spectrum
+                .process_amplitudes(&channel.amplitudes, self.frame.gain, levels);
+        }
+
+        self.chroma.process(
+            self.frame.fft.iter(),
+            self.spectrum.amplitude_scale(),
+            &mut self.frame.chroma,
+        );
+
+        self.frame.loudness.momentary = power_to_lufs(
+            self.channels
+                .iter()
+                .map(|channel| channel.meter.momentary_power())
+                .sum(),
+        ) as f32;
+        self.frame.loudness.short_term = power_to_lufs(
+            self.channels
+                .iter()
+                .map(|channel| channel.meter.short_term_power())
+                .sum(),
+        ) as f32;
+
+        self.frame.rhythm = self.rhythm.process(flux / self.channels.len() as f32);
+
+        // mono input is analyzed as if both sides were the same
+        let right = self.channels.len().min(2) - 1;
+        self.stereo.process(
+            &self.spectrum,
+            [&self.frame.samples[0], &self.frame.samples[right]],
+            [&self.frame.fft[0], &self.frame.fft[right]],
+            self.frame.gain,
+            &mut self.frame.stereo,
+        );
+
+        Ok(&self.frame)
+    }
+}
@@ -0,0 +1,377 @@
+use crate::util::FrameRate;
+use num_complex::Complex32;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// Magnitudes are compressed with `ln(1 + LOG_COMPRESSION * amplitude)` before their flux is taken, so quiet and loud
+/// passages produce onsets of similar strength.
+const LOG_COMPRESSION: f32 = 1000.0;
+/// The smallest spectral flux that can ever count as an onset, so silence does not trigger on noise.
+const MIN_ONSET_FLUX: f32 = 0.02;
+/// How many seconds of flux the adaptive onset threshold is taken from.
+const THRESHOLD_WINDOW: f64 = 1.0;
+/// How many seconds of flux the tempo is estimated from.
+const TEMPO_WINDOW: f64 = 8.0;
+/// The tempo estimates are weighted towards this tempo, in BPM.
+const PREFERRED_BPM: f32 = 120.0;
+/// How far, in octaves, the tempo weighting extends around the preferred tempo.
+const TEMPO_OCTAVE_SPREAD: f32 = 1.0;
+/// The furthest multiple of the beat period used to refine the tempo estimate.
+const MAX_PERIOD_MULTIPLE: usize = 4;
+/// How quickly the tracked tempo follows estimates close to it.
+const TEMPO_SMOOTHING: f32 = 0.05;
+/// How long, in seconds, estimates must disagree with the tracked tempo before it jumps to them.
+const TEMPO_SWITCH_TIME: f64 = 2.0;
+/// How close to a predicted beat, as a fraction of a beat, an onset must be to move the beat.
+const BEAT_SNAP: f32 = 0.15;
+/// How much of the distance to a late onset the beat phase is pulled back.
+const PHASE_CORRECTION: f32 = 0.5;
+
+/// How onsets are detected and beats tracked.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RhythmConfig {
+    /// How many times the recent median spectral flux a frame's flux must reach to count as an onset.
+    pub onset_threshold: f32,
+    /// The shortest time in seconds between two onsets.
+    pub min_onset_interval: f32,
+    /// The slowest tempo the beat tracker will lock onto, in BPM.
+    pub min_bpm: f32,
+    /// The fastest tempo the beat tracker will lock onto, in BPM.
+    pub max_bpm: f32,
+}
+
+impl Default for RhythmConfig {
+    fn default() -> Self {
+        RhythmConfig {
+            onset_threshold: 1.5,
+            min_onset_interval: 0.1,
+            min_bpm: 60.0,
+            max_bpm: 200.0,
+        }
+    }
+}
+
+/// The rhythm events of a single video frame.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Rhythm {
+    /// Whether a new sound starts in this frame.
+    #[allow(dead_code)]
+    pub onset: bool,
+    /// How far the spectral flux rose above the onset threshold, relative to the threshold. 0 without an onset.
+    pub onset_strength: f32,
+    /// Whether a beat falls on this frame.
+    #[allow(dead_code)]
+    pub beat: bool,
+    /// How far this frame is between the last beat and the next one, from 0 on a beat up to 1.
+    pub beat_phase: f32,
+    /// The tracked tempo in BPM, if one has been found yet.
+    pub tempo: Option<f32>,
+}
+
+/// Computes the positive change in log-magnitude of every bin since the previous frame, averaged over the bins.
+///
+/// `previous` holds the compressed magnitudes of the previous frame and is updated to those of this one.
+pub fn spectral_flux(bins: &[Complex32], previous: &mut [f32], amplitude_scale: f32) -> f32 {
+    let mut flux = 0.0;
+    for (bin, previous) in bins.iter().zip(previous.iter_mut()) {
+        let magnitude = (LOG_COMPRESSION * amplitude_scale * bin.norm()).ln_1p();
+        flux += (magnitude - *previous).max(0.0);
+        *previous = magnitude;
+    }
+
+    flux / bins.len().max(1) as f32
+}
+
+/// Tracks onsets and beats from the spectral flux of consecutive frames.
+pub struct RhythmTracker {
+    onsets: OnsetDetector,
+    beats: BeatTracker,
+}
+
+impl RhythmTracker {
+    pub fn new(config: &RhythmConfig, frame_rate: FrameRate) -> RhythmTracker {
+        RhythmTracker {
+            onsets: OnsetDetector::new(config, frame_rate),
+            beats: BeatTracker::new(config, frame_rate),
+        }
+    }
+
+    /// Processes the spectral flux of the next frame.
+    pub fn process(&mut self, flux: f32) -> Rhythm {
+        let (onset, onset_strength) = self.onsets.process(flux);
+        let (beat, beat_phase) = self.beats.process(flux, onset);
+
+        Rhythm {
+            onset,
+            onset_strength,
+            beat,
+            beat_phase,
+            tempo: self.beats.tempo(),
+        }
+    }
+}
+
+/// Picks onsets out of the spectral flux with a threshold that adapts to the recent flux.
+struct OnsetDetector {
+    threshold: f32,
+    min_interval: u32,
+    history: VecDeque<f32>,
+    history_len: usize,
+    previous_flux: f32,
+    since_onset: u32,
+    sorted: Vec<f32>,
+}
+
+impl OnsetDetector {
+    fn new(config: &RhythmConfig, frame_rate: FrameRate) -> OnsetDetector {
+        let fps = frame_rate.as_f64();
+        let history_len = ((fps * THRESHOLD_WINDOW).round() as usize).max(3);
+
+        OnsetDetector {
+            threshold: config.onset_threshold,
+            min_interval: (config.min_onset_interval as f64 * fps).round() as u32,
+            history: VecDeque::with_capacity(history_len),
+            history_len,
+            previous_flux: 0.0,
+            since_onset: u32::MAX,
+            sorted: Vec::with_capacity(history_len),
+        }
+    }
+
+    fn process(&mut self, flux: f32) -> (bool, f32) {
+        self.sorted.clear();
+        self.sorted.extend(self.history.iter().copied());
+        let median = if self.sorted.is_empty() {
+            0.0
+        } else {
+            let middle = self.sorted.len() / 2;
+            *self.sorted.select_nth_unstable_by(middle, f32::total_cmp).1
+        };
+        let threshold = (median * self.threshold).max(MIN_ONSET_FLUX);
+
+        self.since_onset = self.since_onset.saturating_add(1);
+        let onset =
+            flux > threshold && flux > self.previous_flux && self.since_onset > self.min_interval;
+        if onset {
+            self.since_onset = 0;
+        }
+
+        if self.history.len() == self.history_len {
+            self.history.pop_front();
+        }
+        self.history.push_back(flux);
+        self.previous_flux = flux;
+
+        let strength = if onset {
+            (flux - threshold) / threshold
+        } else {
+            0.0
+        };
+
+        (onset, strength)
+    }
+}
+
+/// Estimates the tempo from the periodicity of the spectral flux, and keeps a beat phase locked to the onsets.
+struct BeatTracker {
+    fps: f32,
+    estimator: PeriodEstimator,
+    switch_frames: u32,
+    history: VecDeque<f32>,
+    history_len: usize,
+    /// The tracked beat period in frames.
+    period: Option<f32>,
+    disagreeing: u32,
+    phase: f32,
+}
+
+impl BeatTracker {
+    fn new(config: &RhythmConfig, frame_rate: FrameRate) -> BeatTracker {
+        let fps = frame_rate.as_f64();
+        let estimator = PeriodEstimator::new(fps, config.min_bpm, config.max_bpm);
+
+        BeatTracker {
+            fps: fps as f32,
+            switch_frames: (fps * TEMPO_SWITCH_TIME).round() as u32,
+            history: VecDeque::new(),
+            // enough for a couple of the slowest beats, even when the tempo window is short in frames
+            history_len: ((fps * TEMPO_WINDOW).round() as usize).max(estimator.max_lag() * 3),
+            estimator,
+            period: None,
+            disagreeing: 0,
+            phase: 0.0,
+        }
+    }
+
+    fn tempo(&self) -> Option<f32> {
+        self.period.map(|period| self.fps * 60.0 / period)
+    }
+
+    fn process(&mut self, flux: f32, onset: bool) -> (bool, f32) {
+        if self.history.len() == self.history_len {
+            self.history.pop_front();
+        }
+        self.history.push_back(flux);
+
+        if let Some(estimate) = self.estimator.estimate(self.history.make_contiguous()) {
+            self.update_period(estimate);
+        }
+
+        let Some(period) = self.period else {
+            return (false, 0.0);
+        };
+
+        self.phase += 1.0 / period;
+        let mut beat = false;
+        if self.phase >= 1.0 {
+            self.phase = self.phase.fract();
+            beat = true;
+        }
+
+        if onset {
+            if !beat && self.phase > 1.0 - BEAT_SNAP {
+                // the beat came a little early
+                self.phase = 0.0;
+                beat = true;
+            } else if self.phase < BEAT_SNAP {
+                // the beat came a little late
+                self.phase *= 1.0 - PHASE_CORRECTION;
+            }
+        }
+
+        (beat, self.phase)
+    }
+
+    fn update_period(&mut self, estimate: f32) {
+        let Some(period) = self.period else {
+            self.period = Some(estimate);
+            return;
+        };
+
+        if ((estimate - period) / period).abs() < 0.1 {
+            self.disagreeing = 0;
+            self.period = Some(period + (estimate - period) * TEMPO_SMOOTHING);
+        } else {
+            self.disagreeing += 1;
+            if self.disagreeing > self.switch_frames {
+                self.disagreeing = 0;
+                self.period = Some(estimate);
+            }
+        }
+    }
+}
+
+/// Finds the period of the beat in an onset envelope, using its autocorrelation.
+pub struct PeriodEstimator {
+    fps: f32,
+    min_lag: usize,
+    max_lag: usize,
+    centered: Vec<f32>,
+}
+
+impl PeriodEstimator {
+    /// Creates an estimator for an envelope sampled `fps` times per second, that only finds tempos between `min_bpm`
+    /// and `max_bpm`.
+    pub fn new(fps: f64, min_bpm: f32, max_bpm: f32) -> PeriodEstimator {
+        let max_bpm = max_bpm.max(1.0);
+        let min_bpm = min_bpm.clamp(1.0, max_bpm);
+        let min_lag = ((fps * 60.0 / max_bpm as f64).floor() as usize).max(1);
+        let max_lag = ((fps * 60.0 / min_bpm as f64).ceil() as usize).max(min_lag);
+
+        PeriodEstimator {
+            fps: fps as f32,
+            min_lag,
+            max_lag,
+            centered: vec![],
+        }
+    }
+
+    /// The longest period this estimator can find, in envelope samples.
+    pub fn max_lag(&self) -> usize {
+        self.max_lag
+    }
+
+    /// Finds the beat period in envelope samples that best explains `envelope`.
+    ///
+    /// Returns `None` if the envelope is shorter than two of the longest periods, or if it has no periodicity at all.
+    pub fn estimate(&mut self, envelope: &[f32]) -> Option<f32> {
+        let len = envelope.len();
+        if len < self.max_lag * 2 {
+            return None;
+        }
+
+        let mean = envelope.iter().sum::<f32>() / len as f32;
+        self.centered.clear();
+        self.centered
+            .extend(envelope.iter().map(|&flux| flux - mean));
+
+        let centered = &self.centered;
+        let correlation = |lag: usize| {
+            (lag..len)
+                .map(|i| centered[i] * centered[i - lag])
+                .sum::<f32>()
+                / (len - lag) as f32
+        };
+        let weighted = |lag: usize| {
+            let bpm = self.fps * 60.0 / lag as f32;
+            let octaves = (bpm / PREFERRED_BPM).log2() / TEMPO_OCTAVE_SPREAD;
+            correlation(lag) * (-0.5 * octaves * octaves).exp()
+        };
+
+        let lag =
+            (self.min_lag..=self.max_lag).max_by(|&a, &b| weighted(a).total_cmp(&weighted(b)))?;
+        if correlation(lag) <= 0.0 {
+            return None;
+        }
+
+        // the peaks at multiples of the period are just as sharp, so the furthest one pins it down most precisely
+        let multiple = (len / 2 / (lag + 1)).clamp(1, MAX_PERIOD_MULTIPLE);
+        let around = lag * multiple;
+        let peak = ((around - multiple / 2).max(2)..=around + multiple / 2)
+            .max_by(|&a, &b| correlation(a).total_cmp(&correlation(b)))?;
+
+        // parabolic interpolation between the neighboring lags
+        let (before, value, after) = (
+            correlation(peak - 1),
+            correlation(peak),
+            correlation(peak + 1),
+        );
+        let curvature = before - 2.0 * value + after;
+        let offset = if curvature < 0.0 {
+            (0.5 * (before - after) / curvature).clamp(-0.5, 0.5)
+        } else {
+            0.0
+        };
+
+        Some((peak as f32 + offset) / multiple as f32)
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    #[test]
+    fn click_track_tempo_is_tracked() {
+        let frame_rate = FrameRate::new(24, 1).unwrap();
+        let mut tracker = RhythmTracker::new(&RhythmConfig::default(), frame_rate);
+
+        // 100 BPM is 14.4 frames per beat at 24 fps
+        let clicks: Vec<i64> = (0..100)
+            .map(|beat| (beat as f64 * 14.4).round() as i64)
+            .collect();
+        let rhythm: Vec<Rhythm> = (0..1400)
+            .map(|frame| tracker.process(if clicks.contains(&frame) { 1.0 } else { 0.0 }))
+            .collect();
+
+        let tempo = rhythm.last().unwrap().tempo.unwrap();
+        assert!((tempo - 100.0).abs() < 1.0, "tempo was {}", tempo);
+
+        // once locked on, every click is a beat and nothing else is
+        for (frame, rhythm) in rhythm.iter().enumerate().skip(600) {
+            assert_eq!(rhythm.onset, clicks.contains(&(frame as i64)));
+            assert_eq!(rhythm.beat, rhythm.onset, "frame {}", frame);
+        }
+    }
+}
@@ -0,0 +1,76 @@
+/// Keeps the most recent samples of a single audio channel, addressed by their absolute sample index.
+pub struct SampleRing {
+    buffer: Vec<f32>,
+    end: i64,
+}
+
+impl SampleRing {
+    pub fn new(capacity: usize) -> SampleRing {
+        SampleRing {
+            buffer: vec![0.0; capacity],
+            end: 0,
+        }
+    }
+
+    /// The absolute index of the oldest sample still held by this ring.
+    pub fn start(&self) -> i64 {
+        (self.end - self.buffer.len() as i64).max(0)
+    }
+
+    /// The absolute index one past the newest sample pushed into this ring.
+    pub fn end(&self) -> i64 {
+        self.end
+    }
+
+    pub fn push(&mut self, samples: &[f32]) {
+        let capacity = self.buffer.len();
+
+        // anything that would be overwritten in the same push never needs to be copied
+        let skip = samples.len().saturating_sub(capacity);
+        self.end += skip as i64;
+
+        let mut samples = &samples[skip..];
+        while !samples.is_empty() {
+            let offset = self.end.rem_euclid(capacity as i64) as usize;
+            let len = samples.len().min(capacity - offset);
+
+            self.buffer[offset..offset + len].copy_from_slice(&samples[..len]);
+
+            samples = &samples[len..];
+            self.end += len as i64;
+        }
+    }
+
+    /// Copies the samples starting at the absolute index `start` into `out`.
+    ///
+    /// Samples before the start of the stream, samples that have already been dropped and samples that have not been
+    /// pushed yet are all read as silence.
+    pub fn read(&self, start: i64, out: &mut [f32]) {
+        let capacity = self.buffer.len();
+
+        let copy_start = start.max(self.start());
+        let copy_end = (start + out.len() as i64).min(self.end);
+
+        if copy_start >= copy_end {
+            out.fill(0.0);
+            return;
+        }
+
+        let out_start = (copy_start - start) as usize;
+        let out_end = (copy_end - start) as usize;
+        out[..out_start].fill(0.0);
+        out[out_end..].fill(0.0);
+
+        let mut out = &mut out[out_start..out_end];
+        let mut index = copy_start;
+        while !out.is_empty() {
+            let offset = index.rem_euclid(capacity as i64) as usize;
+            let len = out.len().min(capacity - offset);
+
+            out[..len].copy_from_slice(&self.buffer[offset..offset + len]);
+
+            out = &mut out[len..];
+            index += len as i64;
+        }
+    }
+}
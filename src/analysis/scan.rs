@@ -0,0 +1,160 @@
+use crate::analysis::ring::SampleRing;
+use crate::analysis::window::WindowFunction;
+use crate::cancel::CancelFlag;
+use crate::ffmpeg::decode::{DecodeRange, DecodeSource, DecoderHandle};
+use crate::ffmpeg::AudioFormat;
+use crate::recycle::simple::recycler;
+use anyhow::Context;
+use ffmpeg_next::frame;
+use num_complex::Complex32;
+use realfft::{RealFftPlanner, RealToComplex};
+use std::sync::Arc;
+
+const SCAN_FRAMES_IN_FLIGHT: usize = 8;
+
+/// Decodes part of an input ahead of a render, handing every audio frame to `visit` in order.
+///
+/// Frames are decoded into the same format the render uses.
+pub async fn scan(
+    source: &DecodeSource,
+    range: DecodeRange,
+    cancel: CancelFlag,
+    mut visit: impl FnMut(&frame::Audio) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let (producer, mut consumer) = recycler(
+        (0..SCAN_FRAMES_IN_FLIGHT)
+            .map(|_| frame::Audio::empty())
+            .collect(),
+    )
+    .await;
+
+    let decoder_handle = DecoderHandle::spawn(
+        source.clone(),
+        AudioFormat::default(),
+        range,
+        cancel,
+        producer,
+    )
+    .await
+    .context("Spawning decoder handle")?;
+
+    while let Some(mut audio) = consumer.recv_data().await {
+        visit(&audio)?;
+        audio.send().await.ok();
+    }
+
+    decoder_handle
+        .join()
+        .await
+        .context("Waiting for decoder to finish")
+}
+
+/// Splits the mix of all channels of a scanned input into windowed FFTs, one every `hop` samples.
+///
+/// Each window is centered on the time of its FFT, the first one on the first sample.
+pub struct MonoStft {
+    hop: usize,
+    ring: SampleRing,
+    window: Vec<f32>,
+    fft: Arc<dyn RealToComplex<f32>>,
+    fft_input: Vec<f32>,
+    fft_output: Vec<Complex32>,
+    fft_scratch: Vec<Complex32>,
+    mono: Vec<f32>,
+    count: usize,
+}
+
+impl MonoStft {
+    pub fn new(fft_size: usize, hop: usize) -> MonoStft {
+        MonoStft::with_window(fft_size, hop, WindowFunction::Hann)
+    }
+
+    /// Like [MonoStft::new], but with a window function other than Hann.
+    pub fn with_window(fft_size: usize, hop: usize, window: WindowFunction) -> MonoStft {
+        let fft = RealFftPlanner::<f32>::new().plan_fft_forward(fft_size);
+
+        MonoStft {
+            hop: hop.max(1),
+            // enough for a whole window plus a few audio frames
+            ring: SampleRing::new(fft_size * 4),
+            window: window.coefficients(fft_size),
+            fft_input: fft.make_input_vec(),
+            fft_output: fft.make_output_vec(),
+            fft_scratch: fft.make_scratch_vec(),
+            fft,
+            mono: vec![],
+            count: 0,
+        }
+    }
+
+    /// The number of samples between the centers of consecutive windows.
+    pub fn hop(&self) -> usize {
+        self.hop
+    }
+
+    /// The factor that turns FFT bin magnitudes into linear amplitudes.
+    pub fn amplitude_scale(&self) -> f32 {
+        2.0 / self.fft_input.len() as f32
+    }
+
+    fn fft_size(&self) -> i64 {
+        self.fft_input.len() as i64
+    }
+
+    fn next_window_start(&self) -> i64 {
+        (self.count * self.hop) as i64 - self.fft_size() / 2
+    }
+
+    /// Adds the next audio frame, calling `visit` with the bins of every window it completes.
+    pub fn push(&mut self, audio: &frame::Audio, mut visit: impl FnMut(&[Complex32])) {
+        let planes = audio.planes();
+        self.mono.clear();
+        self.mono.resize(audio.samples(), 0.0);
+        for plane in 0..planes {
+            for (mono, sample) in self.mono.iter_mut().zip(audio.plane::<f32>(plane)) {
+                *mono += sample / planes as f32;
+            }
+        }
+        self.ring.push(&self.mono);
+        self.process_windows(&mut visit);
+    }
+
+    /// Like [MonoStft::push], but with samples that are already mono, such as a single channel.
+    pub fn push_samples(&mut self, samples: &[f32], mut visit: impl FnMut(&[Complex32])) {
+        self.ring.push(samples);
+        self.process_windows(&mut visit);
+    }
+
+    fn process_windows(&mut self, visit: &mut impl FnMut(&[Complex32])) {
+        while self.next_window_start() + self.fft_size() <= self.ring.end() {
+            self.process_window(visit);
+        }
+    }
+
+    /// Calls `visit` with the bins of the windows centered on the rest of the audio, padding it with silence.
+    pub fn finish(&mut self, mut visit: impl FnMut(&[Complex32])) {
+        while self.next_window_start() + self.fft_size() / 2 < self.ring.end() {
+            self.process_window(&mut visit);
+        }
+    }
+
+    fn process_window(&mut self, visit: &mut impl FnMut(&[Complex32])) {
+        self.ring
+            .read(self.next_window_start(), &mut self.fft_input);
+        for (sample, coefficient) in self.fft_input.iter_mut().zip(self.window.iter()) {
+            *sample *= coefficient;
+        }
+
+        // the input and output always have the sizes the plan expects
+        self.fft
+            .process_with_scratch(
+                &mut self.fft_input,
+                &mut self.fft_output,
+                &mut self.fft_scratch,
+            )
+            .unwrap();
+
+        self.count += 1;
+        visit(&self.fft_output);
+    }
+}
@@ -0,0 +1,257 @@
+use crate::util::FrameRate;
+use clap::ValueEnum;
+use num_complex::Complex32;
+use serde::{Deserialize, Serialize};
+
+/// How a raw FFT is turned into the band levels handed to visualizers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SpectrumConfig {
+    /// The number of bands to split the frequency range into.
+    pub bands: usize,
+    pub band_scale: BandScale,
+    /// The lower edge of the lowest band in Hz.
+    pub min_frequency: f32,
+    /// The upper edge of the highest band in Hz.
+    pub max_frequency: f32,
+    pub magnitude_scale: MagnitudeScale,
+    /// The level in dBFS that maps to 0.
+    pub floor_db: f32,
+    /// The level in dBFS that maps to 1.
+    pub ceiling_db: f32,
+    /// The time in seconds a band takes to rise towards a louder level.
+    pub attack: f32,
+    /// The time in seconds a band takes to fall towards a quieter level.
+    pub release: f32,
+}
+
+impl Default for SpectrumConfig {
+    fn default() -> Self {
+        SpectrumConfig {
+            bands: 256,
+            band_scale: BandScale::Log,
+            min_frequency: 20.0,
+            max_frequency: 20000.0,
+            magnitude_scale: MagnitudeScale::Decibel,
+            floor_db: -70.0,
+            ceiling_db: 0.0,
+            attack: 0.0,
+            release: 0.15,
+        }
+    }
+}
+
+/// How band edges are spaced across the frequency range.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub enum BandScale {
+    Linear,
+    Log,
+    Mel,
+}
+
+impl BandScale {
+    fn scale_frequency(self, frequency: f32) -> f32 {
+        match self {
+            BandScale::Linear => frequency,
+            BandScale::Log => frequency.max(1.0).ln(),
+            BandScale::Mel => 2595.0 * (1.0 + frequency / 700.0).log10(),
+        }
+    }
+
+    fn unscale_frequency(self, value: f32) -> f32 {
+        match self {
+            BandScale::Linear => value,
+            BandScale::Log => value.exp(),
+            BandScale::Mel => 700.0 * (10f32.powf(value / 2595.0) - 1.0),
+        }
+    }
+}
+
+/// How band amplitudes are mapped between the floor and the ceiling.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub enum MagnitudeScale {
+    /// Proportional to amplitude.
+    Linear,
+    /// Proportional to level in decibels.
+    Decibel,
+    /// Proportional to perceived loudness, following Stevens' power law.
+    Perceptual,
+}
+
+impl MagnitudeScale {
+    fn apply(self, amplitude: f32) -> f32 {
+        match self {
+            MagnitudeScale::Linear => amplitude,
+            MagnitudeScale::Decibel => 20.0 * amplitude.max(1e-10).log10(),
+            MagnitudeScale::Perceptual => amplitude.powf(0.3),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Band {
+    start_bin: usize,
+    end_bin: usize,
+    center_bin: f32,
+    center_frequency: f32,
+}
+
+/// Reduces FFT bins into a fixed set of smoothed, normalized band levels.
+#[derive(Debug, Clone)]
+pub struct Spectrum {
+    bands: Vec<Band>,
+    magnitude_scale: MagnitudeScale,
+    floor: f32,
+    range: f32,
+    amplitude_scale: f32,
+    attack: f32,
+    release: f32,
+}
+
+impl Spectrum {
+    pub fn new(
+        config: &SpectrumConfig,
+        fft_size: usize,
+        sample_rate: u32,
+        frame_rate: FrameRate,
+    ) -> Spectrum {
+        let bin_count = fft_size / 2 + 1;
+        let bin_width = sample_rate as f32 / fft_size as f32;
+        let nyquist = sample_rate as f32 / 2.0;
+
+        let min_frequency = config.min_frequency.clamp(0.0, nyquist);
+        let max_frequency = config.max_frequency.clamp(min_frequency, nyquist);
+        let scale = config.band_scale;
+        let scale_min = scale.scale_frequency(min_frequency);
+        let scale_max = scale.scale_frequency(max_frequency);
+        let edge = |index: usize| {
+            scale.unscale_frequency(
+                scale_min + (scale_max - scale_min) * index as f32 / config.bands as f32,
+            )
+        };
+
+        let bands = (0..config.bands)
+            .map(|index| {
+                let low = edge(index);
+                let high = edge(index + 1);
+                let center_frequency = scale.unscale_frequency(
+                    (scale.scale_frequency(low) + scale.scale_frequency(high)) / 2.0,
+                );
+
+                Band {
+                    start_bin: ((low / bin_width).ceil() as usize).min(bin_count),
+                    end_bin: ((high / bin_width).ceil() as usize).min(bin_count),
+                    center_bin: (center_frequency / bin_width).min((bin_count - 1) as f32),
+                    center_frequency,
+                }
+            })
+            .collect();
+
+        let magnitude_scale = config.magnitude_scale;
+        let (floor, ceiling) = match magnitude_scale {
+            MagnitudeScale::Decibel => (config.floor_db, config.ceiling_db),
+            _ => (
+                magnitude_scale.apply(db_to_amplitude(config.floor_db)),
+                magnitude_scale.apply(db_to_amplitude(config.ceiling_db)),
+            ),
+        };
+
+        let fps = frame_rate.as_f64() as f32;
+
+        Spectrum {
+            bands,
+            magnitude_scale,
+            floor,
+            range: (ceiling - floor).max(f32::EPSILON),
+            // windows are normalized to unity coherent gain, so this makes a full-scale sine read as 1.0
+            amplitude_scale: 2.0 / fft_size as f32,
+            attack: smoothing_coefficient(config.attack, fps),
+            release: smoothing_coefficient(config.release, fps),
+        }
+    }
+
+    pub fn band_count(&self) -> usize {
+        self.bands.len()
+    }
+
+    /// Gets the center frequency in Hz of the given band.
+    pub fn band_frequency(&self, band: usize) -> f32 {
+        self.bands[band].center_frequency
+    }
+
+    /// The factor that turns FFT bin magnitudes into linear amplitudes.
+    pub fn amplitude_scale(&self) -> f32 {
+        self.amplitude_scale
+    }
+
+    /// Gets the linear amplitude of a band, where a full-scale sine reads as 1.0.
+    fn band_amplitude(&self, band: &Band, bins: &[Complex32]) -> f32 {
+        let norm = if band.end_bin > band.start_bin {
+            bins[band.start_bin..band.end_bin]
+                .iter()
+                .map(|bin| bin.norm())
+                .fold(0.0, f32::max)
+        } else {
+            // bands narrower than a bin are interpolated from their neighbors
+            let low = band.center_bin.floor() as usize;
+            let high = (low + 1).min(bins.len() - 1);
+            let fraction = band.center_bin - low as f32;
+            bins[low].norm() * (1.0 - fraction) + bins[high].norm() * fraction
+        };
+
+        norm * self.amplitude_scale
+    }
+
+    /// Computes the unsmoothed linear amplitude of every band of one channel.
+    pub fn amplitudes(&self, bins: &[Complex32], amplitudes: &mut [f32]) {
+        for (band, amplitude) in self.bands.iter().zip(amplitudes.iter_mut()) {
+            *amplitude = self.band_amplitude(band, bins);
+        }
+    }
+
+    /// Maps a linear amplitude into the configured 0..1 range.
+    pub fn normalize(&self, amplitude: f32) -> f32 {
+        ((self.magnitude_scale.apply(amplitude) - self.floor) / self.range).clamp(0.0, 1.0)
+    }
+
+    /// Computes the band levels of one channel, smoothing them against the levels of the previous frame.
+    ///
+    /// Band amplitudes are multiplied by `gain` before they are mapped into the 0..1 range.
+    pub fn process(&self, bins: &[Complex32], gain: f32, levels: &mut [f32]) {
+        for (band, level) in self.bands.iter().zip(levels.iter_mut()) {
+            self.smooth(self.band_amplitude(band, bins) * gain, level);
+        }
+    }
+
+    /// Like [Spectrum::process], but from band amplitudes that have already been computed by
+    /// [Spectrum::amplitudes].
+    pub fn process_amplitudes(&self, amplitudes: &[f32], gain: f32, levels: &mut [f32]) {
+        for (&amplitude, level) in amplitudes.iter().zip(levels.iter_mut()) {
+            self.smooth(amplitude * gain, level);
+        }
+    }
+
+    fn smooth(&self, amplitude: f32, level: &mut f32) {
+        let target = self.normalize(amplitude);
+        let coefficient = if target > *level {
+            self.attack
+        } else {
+            self.release
+        };
+
+        *level = target + coefficient * (*level - target);
+    }
+}
+
+pub fn db_to_amplitude(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+/// Converts a time constant into the per-frame coefficient of a one-pole smoother.
+pub fn smoothing_coefficient(time: f32, fps: f32) -> f32 {
+    if time <= 0.0 {
+        0.0
+    } else {
+        (-1.0 / (time * fps)).exp()
+    }
+}
@@ -0,0 +1,182 @@
+use crate::analysis::spectrum::Spectrum;
+use num_complex::Complex32;
+
+/// Signals with less energy than this, in squared linear amplitude, are treated as silence.
+const SILENCE: f32 = 1e-10;
+
+/// Where the energy of a frame sits in the stereo image.
+///
+/// Mono input is treated as two identical channels, and only the first two channels of anything wider are used.
+#[derive(Debug, Clone, Default)]
+pub struct Stereo {
+    /// The phase correlation of the left and right channels, from -1 for opposite phase through 0 for unrelated
+    /// channels up to 1 for mono. Silence reads as 0.
+    pub correlation: f32,
+    /// How much of the energy lies in the side channel, from 0 for mono through 0.5 for unrelated channels up to 1
+    /// for opposite phase.
+    pub width: f32,
+    /// The smoothed band levels of the mid channel `(L + R) / 2`, in the range 0..1.
+    pub mid: Vec<f32>,
+    /// The smoothed band levels of the side channel `(L - R) / 2`, in the range 0..1.
+    pub side: Vec<f32>,
+    /// The pan position of each band, from -1 for hard left up to 1 for hard right. Silent bands are centered.
+    pub pan: Vec<f32>,
+}
+
+impl Stereo {
+    pub fn new(bands: usize) -> Stereo {
+        Stereo {
+            mid: vec![0.0; bands],
+            side: vec![0.0; bands],
+            pan: vec![0.0; bands],
+            ..Default::default()
+        }
+    }
+}
+
+/// Splits the first two channels of each frame into mid and side, and finds where each band is panned.
+pub struct StereoAnalyzer {
+    mid_bins: Vec<Complex32>,
+    side_bins: Vec<Complex32>,
+    left_amplitudes: Vec<f32>,
+    right_amplitudes: Vec<f32>,
+}
+
+impl StereoAnalyzer {
+    pub fn new(bins: usize, bands: usize) -> StereoAnalyzer {
+        StereoAnalyzer {
+            mid_bins: vec![Complex32::default(); bins],
+            side_bins: vec![Complex32::default(); bins],
+            left_amplitudes: vec![0.0; bands],
+            right_amplitudes: vec![0.0; bands],
+        }
+    }
+
+    /// Analyzes the stereo image of one frame from the samples and FFTs of its left and right channels.
+    ///
+    /// `gain` is applied to the mid and side bands the same way it is applied to the spectrum.
+    pub fn process(
+        &mut self,
+        spectrum: &Spectrum,
+        samples: [&[f32]; 2],
+        fft: [&[Complex32]; 2],
+        gain: f32,
+        stereo: &mut Stereo,
+    ) {
+        let [left, right] = samples;
+        let (mut left_energy, mut right_energy, mut product) = (0.0, 0.0, 0.0);
+        for (&l, &r) in left.iter().zip(right.iter()) {
+            left_energy += l * l;
+            right_energy += r * r;
+            product += l * r;
+        }
+
+        let total = left_energy + right_energy;
+        if total > SILENCE * left.len().max(1) as f32 {
+            stereo.correlation = (product
+                / (left_energy * right_energy).sqrt().max(f32::MIN_POSITIVE))
+            .clamp(-1.0, 1.0);
+            // mid energy is (total + 2 * product) / 4 and side energy is (total - 2 * product) / 4
+            stereo.width = ((total - 2.0 * product) / (2.0 * total)).clamp(0.0, 1.0);
+        } else {
+            stereo.correlation = 0.0;
+            stereo.width = 0.0;
+        }
+
+        // the FFT is linear, so the spectra of mid and side come straight from those of left and right
+        let [left, right] = fft;
+        for (((mid, side), &l), &r) in self
+            .mid_bins
+            .iter_mut()
+            .zip(self.side_bins.iter_mut())
+            .zip(left.iter())
+            .zip(right.iter())
+        {
+            *mid = (l + r) * 0.5;
+            *side = (l - r) * 0.5;
+        }
+        spectrum.process(&self.mid_bins, gain, &mut stereo.mid);
+        spectrum.process(&self.side_bins, gain, &mut stereo.side);
+
+        spectrum.amplitudes(left, &mut self.left_amplitudes);
+        spectrum.amplitudes(right, &mut self.right_amplitudes);
+        for ((pan, &l), &r) in stereo
+            .pan
+            .iter_mut()
+            .zip(self.left_amplitudes.iter())
+            .zip(self.right_amplitudes.iter())
+        {
+            let sum = l + r;
+            *pan = if sum * sum > SILENCE {
+                (r - l) / sum
+            } else {
+                0.0
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::analysis::spectrum::SpectrumConfig;
+    use crate::util::FrameRate;
+
+    #[test]
+    fn panned_sine_is_placed_in_the_stereo_image() {
+        let fft_size = 1024;
+        let sample_rate = 48000;
+        let spectrum = Spectrum::new(
+            &SpectrumConfig::default(),
+            fft_size,
+            sample_rate,
+            FrameRate::new(30, 1).unwrap(),
+        );
+        let mut analyzer = StereoAnalyzer::new(fft_size / 2 + 1, spectrum.band_count());
+        let mut stereo = Stereo::new(spectrum.band_count());
+
+        // a sine exactly on bin 32, at three times the amplitude on the right
+        let bin = 32;
+        let sine: Vec<f32> = (0..fft_size)
+            .map(|n| (std::f32::consts::TAU * (bin * n) as f32 / fft_size as f32).sin())
+            .collect();
+        let left: Vec<f32> = sine.iter().map(|sample| sample * 0.25).collect();
+        let right: Vec<f32> = sine.iter().map(|sample| sample * 0.75).collect();
+        let mut left_fft = vec![Complex32::default(); fft_size / 2 + 1];
+        let mut right_fft = left_fft.clone();
+        left_fft[bin] = Complex32::new(0.0, -0.25 * fft_size as f32 / 2.0);
+        right_fft[bin] = Complex32::new(0.0, -0.75 * fft_size as f32 / 2.0);
+
+        analyzer.process(
+            &spectrum,
+            [&left, &right],
+            [&left_fft, &right_fft],
+            1.0,
+            &mut stereo,
+        );
+
+        assert!((stereo.correlation - 1.0).abs() < 1e-4);
+        // side has half the amplitude of mid, so a fifth of the total energy
+        assert!((stereo.width - 0.2).abs() < 1e-4);
+
+        let band = (0..spectrum.band_count())
+            .min_by(|&a, &b| {
+                let distance = |band: usize| (spectrum.band_frequency(band) - 1500.0).abs();
+                distance(a).total_cmp(&distance(b))
+            })
+            .unwrap();
+        assert!((stereo.pan[band] - 0.5).abs() < 1e-4);
+        assert!(stereo.mid[band] > stereo.side[band]);
+        assert_eq!(stereo.pan[0], 0.0);
+
+        analyzer.process(
+            &spectrum,
+            [&left, &left],
+            [&left_fft, &left_fft],
+            1.0,
+            &mut stereo,
+        );
+        assert_eq!(stereo.width, 0.0);
+        assert_eq!(stereo.pan[band], 0.0);
+    }
+}
@@ -0,0 +1,384 @@
+use crate::analysis::rhythm::{spectral_flux, PeriodEstimator, RhythmConfig};
+use crate::analysis::scan::{scan, MonoStft};
+use crate::cancel::CancelFlag;
+use crate::ffmpeg::decode::{DecodeRange, DecodeSource};
+use crate::util::Timestamp;
+use anyhow::Context;
+use ffmpeg_next::frame;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+
+/// The number of onset envelope values computed per second of audio.
+const ENVELOPE_RATE: u32 = 100;
+const TEMPO_FFT_SIZE: usize = 2048;
+/// The length in seconds of each stretch of audio the tempo is estimated from.
+const MAP_WINDOW: f64 = 8.0;
+/// The time in seconds between the starts of consecutive estimates.
+const MAP_HOP: f64 = 2.0;
+/// How far apart, relative to each other, two tempos must be to count as a tempo change.
+const TEMPO_CHANGE: f32 = 0.04;
+
+/// Whether and how the tempo map of the input is found before rendering.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TempoConfig {
+    /// Whether to analyze the tempo of the input in a pass before the render.
+    pub enabled: bool,
+    /// The number of beats in each bar.
+    pub beats_per_bar: u32,
+}
+
+impl Default for TempoConfig {
+    fn default() -> Self {
+        TempoConfig {
+            enabled: false,
+            beats_per_bar: 4,
+        }
+    }
+}
+
+/// The tempo of a track over time, as a list of stretches with a constant tempo.
+#[derive(Debug, Clone, Serialize)]
+pub struct TempoMap {
+    pub beats_per_bar: u32,
+    /// Where the analyzed audio ends.
+    pub end: Timestamp,
+    /// The stretches of constant tempo, in order. Empty if no tempo could be found.
+    pub segments: Vec<TempoSegment>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TempoSegment {
+    /// Where this tempo starts.
+    pub start: Timestamp,
+    pub bpm: f64,
+    /// The time of the first beat in this segment.
+    pub first_beat: Timestamp,
+    /// The number of the first beat in this segment, counting from the first beat of the track.
+    pub first_beat_number: i64,
+}
+
+/// Where a point in time falls in the musical structure of a track.
+#[derive(Debug, Copy, Clone)]
+pub struct MusicalPosition {
+    /// The number of beats since the first beat of the track, including the fraction of the current beat. Negative
+    /// before the first beat.
+    pub beat: f64,
+    /// The tempo at this point, in BPM.
+    #[allow(dead_code)]
+    pub bpm: f64,
+    #[allow(dead_code)]
+    pub bar: i64,
+    /// Which beat of the bar this is, counting from 0.
+    pub beat_in_bar: u32,
+    /// How far this is between the last beat and the next one, from 0 up to 1.
+    pub beat_phase: f32,
+    /// How far this is between the start of the bar and the next one, from 0 up to 1.
+    #[allow(dead_code)]
+    pub bar_phase: f32,
+}
+
+impl TempoMap {
+    /// Decodes the given part of the input and finds its tempo map.
+    pub async fn analyze(
+        source: &DecodeSource,
+        range: DecodeRange,
+        cancel: CancelFlag,
+        tempo: &TempoConfig,
+        rhythm: &RhythmConfig,
+    ) -> anyhow::Result<TempoMap> {
+        let mut analyzer: Option<TempoAnalyzer> = None;
+
+        scan(source, range, cancel, |audio| {
+            analyzer
+                .get_or_insert_with(|| TempoAnalyzer::new(audio.rate(), range.start))
+                .push(audio);
+            Ok(())
+        })
+        .await
+        .context("Scanning input for tempo")?;
+
+        Ok(match analyzer {
+            Some(analyzer) => analyzer.finish(tempo, rhythm),
+            None => TempoMap {
+                beats_per_bar: tempo.beats_per_bar,
+                end: range.start,
+                segments: vec![],
+            },
+        })
+    }
+
+    /// Gets the tempo of the longest segment, as the tempo of the whole track.
+    pub fn bpm(&self) -> Option<f64> {
+        self.segments
+            .iter()
+            .enumerate()
+            .max_by_key(|&(index, segment)| self.segment_end(index).saturating_sub(segment.start))
+            .map(|(_, segment)| segment.bpm)
+    }
+
+    fn segment_end(&self, index: usize) -> Timestamp {
+        self.segments
+            .get(index + 1)
+            .map_or(self.end, |next| next.start)
+    }
+
+    /// Finds where a point in time falls in the bars and beats of the track.
+    pub fn position(&self, time: Timestamp) -> Option<MusicalPosition> {
+        let segment = self
+            .segments
+            .iter()
+            .rev()
+            .find(|segment| segment.start <= time)
+            .or(self.segments.first())?;
+
+        let beat = segment.beat_at(time);
+        let beats_per_bar = self.beats_per_bar.max(1) as f64;
+
+        Some(MusicalPosition {
+            beat,
+            bpm: segment.bpm,
+            bar: beat.div_euclid(beats_per_bar) as i64,
+            beat_in_bar: beat.floor().rem_euclid(beats_per_bar) as u32,
+            beat_phase: beat.rem_euclid(1.0) as f32,
+            bar_phase: (beat.rem_euclid(beats_per_bar) / beats_per_bar) as f32,
+        })
+    }
+
+    /// Finds the first beat, or the first beat of a bar, at or after a point in time.
+    pub fn next_beat(&self, time: Timestamp, downbeat: bool) -> Option<Timestamp> {
+        let position = self.position(time)?;
+        let step = if downbeat {
+            self.beats_per_bar.max(1) as f64
+        } else {
+            1.0
+        };
+        let beat = (position.beat / step).ceil() * step;
+
+        let segment = self
+            .segments
+            .iter()
+            .rev()
+            .find(|segment| segment.first_beat_number as f64 <= beat)
+            .or(self.segments.first())?;
+        let seconds = segment.first_beat.as_secs_f64()
+            + (beat - segment.first_beat_number as f64) * 60.0 / segment.bpm;
+
+        Some(Timestamp::from_micros(
+            (seconds.max(0.0) * 1e6).round() as u64
+        ))
+    }
+}
+
+impl Display for TempoMap {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let Some(bpm) = self.bpm() else {
+            return writeln!(f, "No tempo found");
+        };
+
+        writeln!(
+            f,
+            "Tempo: {:.1} BPM, {} beats per bar",
+            bpm, self.beats_per_bar
+        )?;
+        for segment in self.segments.iter() {
+            writeln!(
+                f,
+                "{:>12}  {:>6.1} BPM, first beat at {} (beat {})",
+                segment.start.to_string(),
+                segment.bpm,
+                segment.first_beat,
+                segment.first_beat_number
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl TempoSegment {
+    fn beat_at(&self, time: Timestamp) -> f64 {
+        let offset = time.as_secs_f64() - self.first_beat.as_secs_f64();
+        self.first_beat_number as f64 + offset * self.bpm / 60.0
+    }
+}
+
+/// Builds the onset envelope of a whole track, then splits it into stretches of constant tempo.
+pub struct TempoAnalyzer {
+    sample_rate: u32,
+    origin: Timestamp,
+    stft: MonoStft,
+    magnitudes: Vec<f32>,
+    envelope: Vec<f32>,
+}
+
+impl TempoAnalyzer {
+    pub fn new(sample_rate: u32, origin: Timestamp) -> TempoAnalyzer {
+        TempoAnalyzer {
+            sample_rate,
+            origin,
+            stft: MonoStft::new(
+                TEMPO_FFT_SIZE,
+                (sample_rate / ENVELOPE_RATE).max(1) as usize,
+            ),
+            magnitudes: vec![0.0; TEMPO_FFT_SIZE / 2 + 1],
+            envelope: vec![],
+        }
+    }
+
+    /// The number of envelope values per second.
+    fn envelope_rate(&self) -> f64 {
+        self.sample_rate as f64 / self.stft.hop() as f64
+    }
+
+    pub fn push(&mut self, audio: &frame::Audio) {
+        let scale = self.stft.amplitude_scale();
+        let (magnitudes, envelope) = (&mut self.magnitudes, &mut self.envelope);
+        self.stft.push(audio, |bins| {
+            envelope.push(spectral_flux(bins, magnitudes, scale));
+        });
+    }
+
+    pub fn finish(mut self, tempo: &TempoConfig, rhythm: &RhythmConfig) -> TempoMap {
+        let scale = self.stft.amplitude_scale();
+        let (magnitudes, envelope) = (&mut self.magnitudes, &mut self.envelope);
+        self.stft.finish(|bins| {
+            envelope.push(spectral_flux(bins, magnitudes, scale));
+        });
+
+        let rate = self.envelope_rate();
+        let mut estimator = PeriodEstimator::new(rate, rhythm.min_bpm, rhythm.max_bpm);
+
+        let len = self.envelope.len();
+        let window = ((MAP_WINDOW * rate).round() as usize).min(len);
+        let hop = ((MAP_HOP * rate).round() as usize).max(1);
+        let estimates: Vec<(usize, Option<f32>)> = (0..=len.saturating_sub(window))
+            .step_by(hop)
+            .map(|start| {
+                let period = estimator.estimate(&self.envelope[start..start + window]);
+                // each estimate is taken to start halfway between its window's center and the previous one's
+                let center = (start + (window / 2)).saturating_sub(hop / 2);
+                (center, period)
+            })
+            .collect();
+
+        let changes = |a: f32, b: f32| ((a - b) / b).abs() >= TEMPO_CHANGE;
+
+        // each segment's start and the period estimates that belong to it
+        let mut segments: Vec<(usize, Vec<f32>)> = vec![];
+        for (index, &(start, period)) in estimates.iter().enumerate() {
+            let Some(period) = period else {
+                continue;
+            };
+
+            match segments.last_mut() {
+                Some((_, periods)) if !changes(period, median(periods)) => periods.push(period),
+                Some(_) => {
+                    // a single disagreeing estimate is not enough for a tempo change
+                    let confirmed = estimates
+                        .get(index + 1)
+                        .and_then(|&(_, next)| next)
+                        .is_some_and(|next| !changes(next, period));
+                    if confirmed {
+                        segments.push((start, vec![period]));
+                    }
+                }
+                None => segments.push((0, vec![period])),
+            }
+        }
+
+        let seconds =
+            |frames: f64| self.origin + Timestamp::from_micros((frames / rate * 1e6) as u64);
+
+        let mut tempo_segments: Vec<TempoSegment> = vec![];
+        for (index, (start, periods)) in segments.iter().enumerate() {
+            let end = segments.get(index + 1).map_or(len, |&(next, _)| next);
+            let period = median(periods);
+            let offset = self.beat_offset(*start, end, period);
+
+            let mut segment = TempoSegment {
+                start: seconds(*start as f64),
+                bpm: 60.0 * rate / period as f64,
+                first_beat: seconds(*start as f64 + offset),
+                first_beat_number: 0,
+            };
+
+            if let Some(previous) = tempo_segments.last() {
+                segment.first_beat_number = (previous.beat_at(segment.first_beat).round() as i64)
+                    .max(previous.first_beat_number + 1);
+            }
+
+            tempo_segments.push(segment);
+        }
+
+        TempoMap {
+            beats_per_bar: tempo.beats_per_bar,
+            end: seconds(len as f64),
+            segments: tempo_segments,
+        }
+    }
+
+    /// Finds the offset of the beat grid with the given period that lines up best with the onsets of a segment.
+    fn beat_offset(&self, start: usize, end: usize, period: f32) -> f64 {
+        let envelope = &self.envelope[start..end];
+        let score = |offset: usize| {
+            let beats = (0..)
+                .map(|beat| (offset as f32 + beat as f32 * period).round() as usize)
+                .take_while(|&index| index < envelope.len());
+            let (sum, count) = beats.fold((0.0, 0), |(sum, count), index| {
+                (sum + envelope[index], count + 1)
+            });
+            sum / count.max(1) as f32
+        };
+
+        (0..(period.ceil() as usize).min(envelope.len()))
+            .max_by(|&a, &b| score(a).total_cmp(&score(b)))
+            .unwrap_or(0) as f64
+    }
+}
+
+fn median(values: &[f32]) -> f32 {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f32::total_cmp);
+    sorted[sorted.len() / 2]
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    #[test]
+    fn positions_continue_across_segments() {
+        let map = TempoMap {
+            beats_per_bar: 4,
+            end: Timestamp::from_micros(18_000_000),
+            segments: vec![
+                TempoSegment {
+                    start: Timestamp::ZERO,
+                    bpm: 120.0,
+                    first_beat: Timestamp::from_micros(250_000),
+                    first_beat_number: 0,
+                },
+                TempoSegment {
+                    start: Timestamp::from_micros(10_000_000),
+                    bpm: 60.0,
+                    first_beat: Timestamp::from_micros(10_250_000),
+                    first_beat_number: 20,
+                },
+            ],
+        };
+
+        let position = map.position(Timestamp::from_micros(2_500_000)).unwrap();
+        assert_eq!(position.beat, 4.5);
+        assert_eq!((position.bar, position.beat_in_bar), (1, 0));
+        assert_eq!(position.beat_phase, 0.5);
+
+        let position = map.position(Timestamp::from_micros(12_250_000)).unwrap();
+        assert_eq!(position.beat, 22.0);
+        assert_eq!((position.bar, position.beat_in_bar), (5, 2));
+        assert_eq!(position.bar_phase, 0.5);
+
+        assert_eq!(map.bpm(), Some(120.0));
+        assert!(map.position(Timestamp::ZERO).unwrap().beat < 0.0);
+    }
+}
@@ -0,0 +1,301 @@
+use crate::analysis::chroma::{ChromaConfig, Key, KeyAnalyzer};
+use crate::analysis::loudness::{power_to_lufs, LoudnessReport, TrackMeter, MOMENTARY_WINDOW};
+use crate::analysis::rhythm::RhythmConfig;
+use crate::analysis::scan::{scan, MonoStft};
+use crate::analysis::spectrum::{Spectrum, SpectrumConfig};
+use crate::analysis::tempo::{TempoAnalyzer, TempoConfig, TempoMap};
+use crate::analysis::window::WindowFunction;
+use crate::cancel::CancelFlag;
+use crate::ffmpeg::decode::{DecodeRange, DecodeSource};
+use crate::ffmpeg::AudioFormat;
+use crate::util::{FrameRate, Timestamp};
+use anyhow::{bail, Context};
+use ffmpeg_next::frame;
+use num_complex::Complex32;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// How long in seconds before a possible drop the track is compared against.
+const DROP_BEFORE: f64 = 4.0;
+/// How long in seconds after a possible drop the track is compared against.
+const DROP_AFTER: f64 = 2.0;
+/// How much louder in LU the track needs to get for a rise to count as a drop.
+const DROP_MIN_RISE: f64 = 6.0;
+
+/// Whether the whole input is analyzed in a pass before the render.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TrackConfig {
+    /// Whether to decode the input twice, first to analyze it as a whole and then to render it.
+    pub enabled: bool,
+}
+
+/// What the analysis needs to know to look at a whole track.
+#[derive(Debug, Clone)]
+pub struct TrackAnalyzerArgs {
+    pub fft_size: usize,
+    pub window: WindowFunction,
+    pub spectrum: SpectrumConfig,
+    pub rhythm: RhythmConfig,
+    pub tempo: TempoConfig,
+    pub chroma: ChromaConfig,
+}
+
+/// Statistics of the whole rendered part of the input, known before the first frame is drawn.
+#[derive(Debug, Clone)]
+pub struct TrackAnalysis {
+    /// Where in the input the analyzed audio starts, including any pre-roll.
+    pub start: Timestamp,
+    /// How long the analyzed audio is.
+    pub duration: Timestamp,
+    /// The largest linear amplitude each band of [AnalysisFrame::spectrum](crate::analysis::AnalysisFrame::spectrum)
+    /// reaches anywhere in any channel, where a full-scale sine reads as 1.0.
+    pub peak_spectrum: Vec<f32>,
+    pub loudness: LoudnessReport,
+    /// The beats and bars of the track.
+    pub tempo: Arc<TempoMap>,
+    /// The estimated key of the track, if the program asks for one.
+    pub key: Option<Key>,
+    /// Where the loudness rises the most, if it rises sharply anywhere.
+    pub drop: Option<Timestamp>,
+}
+
+impl TrackAnalysis {
+    /// Decodes the given part of the input once and analyzes it as a whole.
+    pub async fn analyze(
+        source: &DecodeSource,
+        range: DecodeRange,
+        cancel: CancelFlag,
+        args: &TrackAnalyzerArgs,
+    ) -> anyhow::Result<TrackAnalysis> {
+        let mut analyzer: Option<TrackAnalyzer> = None;
+
+        scan(source, range, cancel.clone(), |audio| {
+            analyzer
+                .get_or_insert_with(|| {
+                    TrackAnalyzer::new(args, audio.rate(), audio.planes(), range.start)
+                })
+                .push(audio);
+            Ok(())
+        })
+        .await
+        .context("Scanning input for track analysis")?;
+
+        let analyzer = match analyzer {
+            Some(analyzer) => analyzer,
+            // the caller notices the cancel and drops the empty analysis
+            None if cancel.is_cancelled() => {
+                TrackAnalyzer::new(args, AudioFormat::default().sample_rate, 0, range.start)
+            }
+            None => bail!("input has no audio"),
+        };
+        Ok(analyzer.finish(args))
+    }
+}
+
+/// Feeds every scanned frame into all the whole-track analyses at once.
+struct TrackAnalyzer {
+    start: Timestamp,
+    spectrum: Spectrum,
+    peak_spectrum: PeakSpectrum,
+    meter: TrackMeter,
+    tempo: TempoAnalyzer,
+    key: Option<KeyAnalyzer>,
+}
+
+impl TrackAnalyzer {
+    fn new(
+        args: &TrackAnalyzerArgs,
+        sample_rate: u32,
+        channels: usize,
+        start: Timestamp,
+    ) -> TrackAnalyzer {
+        // only the band layout is used, so the frame rate the levels would be smoothed at doesn't matter
+        let spectrum = Spectrum::new(
+            &args.spectrum,
+            args.fft_size,
+            sample_rate,
+            FrameRate::default(),
+        );
+        let bands = spectrum.band_count();
+
+        TrackAnalyzer {
+            start,
+            spectrum,
+            peak_spectrum: PeakSpectrum::new(args.fft_size, args.window, channels, bands),
+            meter: TrackMeter::new(sample_rate, channels),
+            tempo: TempoAnalyzer::new(sample_rate, start),
+            key: args
+                .chroma
+                .estimate_key
+                .then(|| KeyAnalyzer::new(&args.chroma, sample_rate)),
+        }
+    }
+
+    fn push(&mut self, audio: &frame::Audio) {
+        for channel in 0..audio.planes() {
+            self.peak_spectrum
+                .push(&self.spectrum, channel, audio.plane::<f32>(channel));
+        }
+
+        self.meter.push(audio);
+        self.tempo.push(audio);
+        if let Some(key) = &mut self.key {
+            key.push(audio);
+        }
+    }
+
+    fn finish(self, args: &TrackAnalyzerArgs) -> TrackAnalysis {
+        let drop = find_drop(self.meter.momentary_blocks(), self.meter.block_step())
+            .map(|seconds| self.start + Timestamp::from_micros((seconds * 1e6) as u64));
+        let loudness = self.meter.finish();
+
+        TrackAnalysis {
+            start: self.start,
+            duration: loudness.duration,
+            peak_spectrum: self.peak_spectrum.finish(&self.spectrum),
+            loudness,
+            tempo: Arc::new(self.tempo.finish(&args.tempo, &args.rhythm)),
+            key: self.key.and_then(KeyAnalyzer::finish),
+            drop,
+        }
+    }
+}
+
+/// Follows the largest amplitude of every band in any channel.
+///
+/// The channels are transformed one by one with the window of the render, so the peaks match the band amplitudes
+/// the render's analyzer sees, which the static gain is applied to.
+struct PeakSpectrum {
+    stfts: Vec<MonoStft>,
+    amplitudes: Vec<f32>,
+    peaks: Vec<f32>,
+}
+
+impl PeakSpectrum {
+    fn new(fft_size: usize, window: WindowFunction, channels: usize, bands: usize) -> PeakSpectrum {
+        PeakSpectrum {
+            stfts: (0..channels)
+                .map(|_| MonoStft::with_window(fft_size, fft_size / 2, window))
+                .collect(),
+            amplitudes: vec![0.0; bands],
+            peaks: vec![0.0; bands],
+        }
+    }
+
+    fn push(&mut self, spectrum: &Spectrum, channel: usize, samples: &[f32]) {
+        let (amplitudes, peaks) = (&mut self.amplitudes, &mut self.peaks);
+        self.stfts[channel].push_samples(samples, |bins| {
+            accumulate_peaks(spectrum, bins, amplitudes, peaks)
+        });
+    }
+
+    fn finish(mut self, spectrum: &Spectrum) -> Vec<f32> {
+        let (amplitudes, peaks) = (&mut self.amplitudes, &mut self.peaks);
+        for stft in &mut self.stfts {
+            stft.finish(|bins| accumulate_peaks(spectrum, bins, amplitudes, peaks));
+        }
+        self.peaks
+    }
+}
+
+fn accumulate_peaks(
+    spectrum: &Spectrum,
+    bins: &[Complex32],
+    amplitudes: &mut [f32],
+    peaks: &mut [f32],
+) {
+    spectrum.amplitudes(bins, amplitudes);
+    for (peak, &amplitude) in peaks.iter_mut().zip(amplitudes.iter()) {
+        *peak = peak.max(amplitude);
+    }
+}
+
+/// Finds the point in seconds where the loudness after it rises the most over the loudness before it.
+///
+/// `blocks` are the momentary loudness blocks of the track, `step` seconds apart.
+fn find_drop(blocks: &[f64], step: f64) -> Option<f64> {
+    let window = (MOMENTARY_WINDOW / step).round() as usize;
+    let before = ((DROP_BEFORE / step).round() as usize).max(1);
+    let after = ((DROP_AFTER / step).round() as usize).max(1);
+    let mean_lufs =
+        |blocks: &[f64]| power_to_lufs(blocks.iter().sum::<f64>() / blocks.len() as f64);
+
+    let mut best: Option<(usize, f64)> = None;
+    // block `index` ends at the drop, and the blocks after it start no earlier than the drop
+    for index in before - 1..blocks.len().saturating_sub(window + after - 1) {
+        let rise = mean_lufs(&blocks[index + window..index + window + after])
+            - mean_lufs(&blocks[index + 1 - before..=index]);
+        if rise >= DROP_MIN_RISE && best.is_none_or(|(_, best)| rise > best) {
+            best = Some((index, rise));
+        }
+    }
+
+    best.map(|(index, _)| MOMENTARY_WINDOW + index as f64 * step)
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::analysis::gain::{AutoGain, GainConfig, GainMode};
+    use std::f32::consts::TAU;
+
+    #[test]
+    fn sudden_rise_in_loudness_is_found_as_the_drop() {
+        // ten quiet seconds followed by ten seconds 20 dB louder
+        let blocks: Vec<f64> = (0..200)
+            .map(|block| {
+                let end = MOMENTARY_WINDOW + block as f64 * 0.1;
+                if end <= 10.0 + 1e-9 {
+                    0.001
+                } else if end - MOMENTARY_WINDOW >= 10.0 - 1e-9 {
+                    0.1
+                } else {
+                    0.05
+                }
+            })
+            .collect();
+
+        let drop = find_drop(&blocks, 0.1).unwrap();
+        assert!((drop - 10.0).abs() < 1e-6, "drop at {}", drop);
+
+        assert_eq!(find_drop(&[0.01; 200], 0.1), None);
+        assert_eq!(find_drop(&[], 0.1), None);
+    }
+
+    #[test]
+    fn hard_panned_full_scale_sine_needs_no_static_gain() {
+        let sample_rate = 48000;
+        let fft_size = 2048;
+        let spectrum = Spectrum::new(
+            &SpectrumConfig::default(),
+            fft_size,
+            sample_rate,
+            FrameRate::default(),
+        );
+        let mut peak_spectrum =
+            PeakSpectrum::new(fft_size, WindowFunction::Hann, 2, spectrum.band_count());
+
+        // a full-scale sine in the left channel only, centered on an FFT bin
+        let frequency = 43.0 * sample_rate as f32 / fft_size as f32;
+        let left: Vec<f32> = (0..sample_rate as usize)
+            .map(|n| (TAU * frequency * n as f32 / sample_rate as f32).sin())
+            .collect();
+        let right = vec![0.0; left.len()];
+        for (left, right) in left.chunks(1024).zip(right.chunks(1024)) {
+            peak_spectrum.push(&spectrum, 0, left);
+            peak_spectrum.push(&spectrum, 1, right);
+        }
+
+        let peak = peak_spectrum
+            .finish(&spectrum)
+            .into_iter()
+            .fold(0.0, f32::max);
+        let config = GainConfig {
+            mode: GainMode::Static,
+            ..Default::default()
+        };
+        let gain = AutoGain::new(&config, FrameRate::default(), Some(peak)).static_gain();
+        assert!((gain - 1.0).abs() < 0.01, "static gain {}", gain);
+    }
+}
@@ -0,0 +1,64 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::f64::consts::TAU;
+
+/// The window applied to each block of samples before it is transformed.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub enum WindowFunction {
+    /// No windowing. Best frequency resolution, but with heavy spectral leakage.
+    Rectangular,
+    /// A good general-purpose window.
+    #[default]
+    Hann,
+    /// Like Hann, but with a lower nearest side-lobe.
+    Hamming,
+    /// Very low leakage, at the cost of wider peaks.
+    BlackmanHarris,
+    /// Very wide peaks, but with accurate amplitudes for tones between bins.
+    FlatTop,
+}
+
+impl WindowFunction {
+    /// The coefficients of the generalized cosine window `a0 - a1 cos(x) + a2 cos(2x) - ...`.
+    fn cosine_terms(&self) -> &'static [f64] {
+        match self {
+            WindowFunction::Rectangular => &[1.0],
+            WindowFunction::Hann => &[0.5, 0.5],
+            WindowFunction::Hamming => &[0.54, 0.46],
+            WindowFunction::BlackmanHarris => &[0.35875, 0.48829, 0.14128, 0.01168],
+            WindowFunction::FlatTop => &[
+                0.21557895,
+                0.41663158,
+                0.277263158,
+                0.083578947,
+                0.006947368,
+            ],
+        }
+    }
+
+    /// Computes this window for blocks of `size` samples.
+    ///
+    /// The window is scaled to have the same coherent gain as the rectangular window, so a pure tone produces the
+    /// same peak magnitude no matter which window is selected.
+    pub fn coefficients(&self, size: usize) -> Vec<f32> {
+        let terms = self.cosine_terms();
+
+        let window: Vec<f64> = (0..size)
+            .map(|n| {
+                let x = TAU * n as f64 / size as f64;
+                terms
+                    .iter()
+                    .enumerate()
+                    .map(|(k, a)| {
+                        let sign = if k % 2 == 0 { 1.0 } else { -1.0 };
+                        sign * a * (k as f64 * x).cos()
+                    })
+                    .sum()
+            })
+            .collect();
+
+        let gain = size as f64 / window.iter().sum::<f64>();
+
+        window.into_iter().map(|w| (w * gain) as f32).collect()
+    }
+}
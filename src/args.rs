@@ -1,12 +1,29 @@
-use crate::project::{Program, Project, VisualizerEnum};
+use crate::analysis::chroma::ChromaConfig;
+use crate::analysis::gain::{GainConfig, GainMode};
+use crate::analysis::rhythm::RhythmConfig;
+use crate::analysis::spectrum::{BandScale, MagnitudeScale, SpectrumConfig};
+use crate::analysis::tempo::TempoConfig;
+use crate::analysis::track::TrackConfig;
+use crate::analysis::window::WindowFunction;
+use crate::canvas::BlendMode;
+use crate::progress::ProgressFormat;
+use crate::project::{Program, Project, TimeRange, VisualizerEnum};
+use crate::util::{FrameRate, Timestamp};
 use crate::visualizer::bars::BarsVisualizerInput;
-use crate::visualizer::cotton::CottonVisualizerInput;
+use crate::visualizer::cotton::{CottonVisualizerInput, Seed};
 use crate::visualizer::credits::CreditsVisualizerInput;
+use crate::visualizer::layers::Layer;
+use crate::visualizer::stereo::StereoVisualizerInput;
 use clap::{Args, Parser, Subcommand};
 use std::path::PathBuf;
+use std::str::FromStr;
 
 #[derive(Debug, Clone, Parser)]
 pub struct Cli {
+    /// How to report the progress of renders.
+    #[arg(long, global = true, value_enum, default_value_t)]
+    pub progress: ProgressFormat,
+
     #[command(subcommand)]
     pub subcommand: Commands,
 }
@@ -41,7 +58,23 @@ pub enum Commands {
         /// Note: this is required if the project does not specify an output file.
         #[arg(short, long)]
         output: Option<PathBuf>,
+
+        /// Override parts of the project's time range.
+        #[command(flatten)]
+        range: TimeRangeArgs,
     },
+
+    /// Renders every input with the visualization in the provided project file.
+    Batch(BatchCommandArgs),
+
+    /// Renders single frames of the provided project file to a PNG image, to check a look without a full render.
+    Preview(PreviewCommandArgs),
+
+    /// Estimates the tempo of an audio file and prints its tempo map.
+    Tempo(TempoCommandArgs),
+
+    /// Measures the loudness of an audio file and prints a report.
+    Loudness(LoudnessCommandArgs),
 }
 
 #[derive(Debug, Clone, Args)]
@@ -54,6 +87,9 @@ pub struct ProjectArgs {
     #[arg(short, long)]
     pub output: PathBuf,
 
+    #[command(flatten)]
+    pub range: TimeRangeArgs,
+
     #[command(flatten)]
     pub program: ProgramArgs,
 }
@@ -68,10 +104,51 @@ pub struct ProjectOptionArgs {
     #[arg(short, long)]
     pub output: Option<PathBuf>,
 
+    #[command(flatten)]
+    pub range: TimeRangeArgs,
+
     #[command(flatten)]
     pub program: ProgramArgs,
 }
 
+#[derive(Debug, Clone, Args)]
+pub struct TimeRangeArgs {
+    /// Where in the input to start rendering.
+    /// Accepts seconds, [[hours:]minutes:]seconds, or durations like 1m30s.
+    #[arg(long)]
+    pub start: Option<Timestamp>,
+
+    /// How much of the input to render.
+    #[arg(long, conflicts_with = "end")]
+    pub duration: Option<Timestamp>,
+
+    /// Where in the input to stop rendering.
+    #[arg(long)]
+    pub end: Option<Timestamp>,
+
+    /// How much audio before the start to feed to the visualizer without outputting it.
+    /// This lets effects that build up over time start out already filled in.
+    #[arg(long)]
+    pub pre_roll: Option<Timestamp>,
+}
+
+/// The range of the input for the analysis subcommands, which have no use for pre-roll.
+#[derive(Debug, Clone, Args)]
+pub struct AnalysisRangeArgs {
+    /// Where in the input to start analyzing.
+    /// Accepts seconds, [[hours:]minutes:]seconds, or durations like 1m30s.
+    #[arg(long)]
+    pub start: Option<Timestamp>,
+
+    /// How much of the input to analyze.
+    #[arg(long, conflicts_with = "end")]
+    pub duration: Option<Timestamp>,
+
+    /// Where in the input to stop analyzing.
+    #[arg(long)]
+    pub end: Option<Timestamp>,
+}
+
 #[derive(Debug, Clone, Args)]
 pub struct ProgramArgs {
     /// The width of the output video.
@@ -82,25 +159,331 @@ pub struct ProgramArgs {
     #[arg(long, default_value = "1080")]
     pub height: u32,
 
+    /// The frame rate of the output video.
+    /// Accepts whole numbers, fractions like 30000/1001, and NTSC shorthands like 29.97.
+    #[arg(long, default_value = "24")]
+    pub frame_rate: FrameRate,
+
+    /// The number of samples in each FFT window.
+    /// Larger windows give finer frequency resolution at the cost of temporal resolution.
+    #[arg(long, default_value = "4096")]
+    pub fft_size: usize,
+
+    /// The window function applied to the samples before each FFT.
+    #[arg(long, value_enum, default_value_t)]
+    pub window: WindowFunction,
+
+    #[command(flatten)]
+    pub spectrum: SpectrumArgs,
+
+    #[command(flatten)]
+    pub rhythm: RhythmArgs,
+
+    #[command(flatten)]
+    pub tempo: TempoArgs,
+
+    #[command(flatten)]
+    pub chroma: ChromaArgs,
+
+    #[command(flatten)]
+    pub track: TrackArgs,
+
+    #[command(flatten)]
+    pub gain: GainArgs,
+
+    /// Another visualizer to draw on top of the one of the subcommand, written like that subcommand with the
+    /// options of the layer in front, e.g. "--blend add --opacity 0.5 bars --beat-flash".
+    /// Can be given more than once, and the layers are stacked in order.
+    #[arg(long = "layer", value_name = "LAYER", allow_hyphen_values = true)]
+    pub layers: Vec<LayerArgs>,
+
     /// The visualizer to use in this program.
     #[command(subcommand)]
     pub visualizer: VisualizerArgs,
 }
 
+/// A layer on top of the visualizer of the command line program.
+#[derive(Debug, Clone, Parser)]
+#[command(no_binary_name = true)]
+pub struct LayerArgs {
+    /// How much of the blended layer shows, from 0 for invisible up to 1.
+    #[arg(long, default_value = "1.0")]
+    pub opacity: f32,
+
+    /// How the layer is put onto the ones beneath it.
+    #[arg(long, value_enum, default_value_t)]
+    pub blend: BlendMode,
+
+    /// The audio channels the layer's visualizer sees, in order. All of them if unset.
+    #[arg(long, value_delimiter = ',')]
+    pub channels: Option<Vec<usize>>,
+
+    /// The visualizer of the layer.
+    #[command(subcommand)]
+    pub visualizer: VisualizerArgs,
+}
+
+impl FromStr for LayerArgs {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        LayerArgs::try_parse_from(s.split_whitespace()).map_err(|error| {
+            // only the message itself, as the outer command prints its own hints after it
+            let message = error.to_string();
+            let message = message.trim_start_matches("error: ");
+            message.split("\n\n").next().unwrap_or_default().to_owned()
+        })
+    }
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct SpectrumArgs {
+    /// The number of frequency bands handed to the visualizer.
+    #[arg(long, default_value = "256")]
+    pub bands: usize,
+
+    /// How the frequency bands are spaced.
+    #[arg(long, value_enum, default_value = "log")]
+    pub band_scale: BandScale,
+
+    /// The lowest frequency shown, in Hz.
+    #[arg(long, default_value = "20")]
+    pub min_frequency: f32,
+
+    /// The highest frequency shown, in Hz.
+    #[arg(long, default_value = "20000")]
+    pub max_frequency: f32,
+
+    /// How band amplitudes are scaled.
+    #[arg(long, value_enum, default_value = "decibel")]
+    pub magnitude_scale: MagnitudeScale,
+
+    /// The level in dBFS that is shown as silence.
+    #[arg(long, default_value = "-70", allow_hyphen_values = true)]
+    pub floor_db: f32,
+
+    /// The level in dBFS that is shown at full brightness.
+    #[arg(long, default_value = "0", allow_hyphen_values = true)]
+    pub ceiling_db: f32,
+
+    /// How long, in seconds, a band takes to rise to a louder level.
+    #[arg(long, default_value = "0")]
+    pub attack: f32,
+
+    /// How long, in seconds, a band takes to fall to a quieter level.
+    #[arg(long, default_value = "0.15")]
+    pub release: f32,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct RhythmArgs {
+    /// How many times the recent median spectral flux must be reached to detect an onset.
+    /// Lower values detect more onsets.
+    #[arg(long, default_value = "1.5")]
+    pub onset_threshold: f32,
+
+    /// The shortest time in seconds between two onsets.
+    #[arg(long, default_value = "0.1")]
+    pub min_onset_interval: f32,
+
+    /// The slowest tempo in BPM the beat tracker will lock onto.
+    #[arg(long, default_value = "60")]
+    pub min_bpm: f32,
+
+    /// The fastest tempo in BPM the beat tracker will lock onto.
+    #[arg(long, default_value = "200")]
+    pub max_bpm: f32,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct TempoArgs {
+    /// Analyze the tempo of the input before rendering, so visualizers can follow its bars and beats.
+    #[arg(long)]
+    pub tempo_map: bool,
+
+    /// The number of beats in each bar of the tempo map.
+    #[arg(long, default_value = "4")]
+    pub beats_per_bar: u32,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct TrackArgs {
+    /// Decode the input twice, analyzing the whole track before rendering it.
+    #[arg(long)]
+    pub two_pass: bool,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct GainArgs {
+    /// How band amplitudes are scaled before they are mapped between the floor and the ceiling.
+    #[arg(long, value_enum, default_value_t)]
+    pub auto_gain: GainMode,
+
+    /// The time in seconds the adaptive gain takes to drop when the input gets louder.
+    #[arg(long, default_value = "0.05")]
+    pub gain_attack: f32,
+
+    /// The time in seconds the adaptive gain takes to rise when the input gets quieter.
+    #[arg(long, default_value = "5.0")]
+    pub gain_release: f32,
+
+    /// The most the input is ever amplified, in dB.
+    #[arg(long, default_value = "30")]
+    pub max_gain_db: f32,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct ChromaArgs {
+    /// The lowest frequency in Hz that counts towards the chromagram.
+    #[arg(long, default_value = "80")]
+    pub chroma_min_frequency: f32,
+
+    /// The highest frequency in Hz that counts towards the chromagram.
+    #[arg(long, default_value = "5000")]
+    pub chroma_max_frequency: f32,
+
+    /// Estimate the key of the input before rendering.
+    #[arg(long)]
+    pub estimate_key: bool,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct BatchCommandArgs {
+    /// The project file to load the program from.
+    #[arg(short, long)]
+    pub project_file: PathBuf,
+
+    /// The input audio files to visualize. Glob patterns like `album/*.flac` are expanded.
+    #[arg(required = true)]
+    pub inputs: Vec<String>,
+
+    /// Where to write each output video.
+    /// `{dir}`, `{stem}` and `{ext}` are replaced with the input's directory, file name without extension and
+    /// extension, and `{index}` with its position in the list of inputs, from 1.
+    #[arg(short, long)]
+    pub output: String,
+
+    /// The most inputs to render at the same time.
+    #[arg(short, long, default_value = "1", value_parser = clap::value_parser!(u32).range(1..))]
+    pub jobs: u32,
+
+    /// Skip inputs whose output file already exists.
+    #[arg(long)]
+    pub skip_existing: bool,
+
+    /// Override parts of the project's time range for every input.
+    #[command(flatten)]
+    pub range: TimeRangeArgs,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct PreviewCommandArgs {
+    /// The project file to load the program from.
+    #[arg(short, long)]
+    pub project_file: PathBuf,
+
+    /// Override the project's specified input file with this one.
+    /// Note: this is required if the project does not specify an input file.
+    #[arg(short, long)]
+    pub input: Option<PathBuf>,
+
+    /// The PNG image to write.
+    #[arg(short, long)]
+    pub output: PathBuf,
+
+    /// Where in the input to render frames.
+    /// A single timestamp renders one frame, several render a contact sheet with a frame for each.
+    #[arg(required = true)]
+    pub timestamps: Vec<Timestamp>,
+
+    /// How much audio before each timestamp to feed to the visualizer first.
+    /// Defaults to the project's pre-roll, or 5 seconds if it has none.
+    #[arg(long)]
+    pub pre_roll: Option<Timestamp>,
+
+    /// The number of frames in each row of a contact sheet.
+    /// Defaults to enough columns to make the sheet about square.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub columns: Option<u32>,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct TempoCommandArgs {
+    /// The input audio file to analyze.
+    #[arg(short, long)]
+    pub input: PathBuf,
+
+    #[command(flatten)]
+    pub range: AnalysisRangeArgs,
+
+    /// The number of beats in each bar.
+    #[arg(long, default_value = "4")]
+    pub beats_per_bar: u32,
+
+    /// The slowest tempo in BPM to look for.
+    #[arg(long, default_value = "60")]
+    pub min_bpm: f32,
+
+    /// The fastest tempo in BPM to look for.
+    #[arg(long, default_value = "200")]
+    pub max_bpm: f32,
+
+    /// Print the tempo map as JSON.
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct LoudnessCommandArgs {
+    /// The input audio file to measure.
+    #[arg(short, long)]
+    pub input: PathBuf,
+
+    #[command(flatten)]
+    pub range: AnalysisRangeArgs,
+
+    /// Print the report as JSON.
+    #[arg(long)]
+    pub json: bool,
+}
+
 #[derive(Debug, Clone, Subcommand)]
 pub enum VisualizerArgs {
     /// Runs the Bars visualizer, drawing flashing vertical bars on the screen for the different frequencies.
-    Bars,
+    Bars {
+        /// Flash the bars red on every beat.
+        #[arg(long)]
+        beat_flash: bool,
+
+        /// Draw how far through the track the render is along the bottom. Needs `--two-pass`.
+        #[arg(long)]
+        progress_bar: bool,
+
+        /// How bright the bars are, where 1 shows the spectrum levels as they are.
+        #[arg(long, default_value = "1.0")]
+        brightness: f32,
+    },
 
     /// Runs the Cotton visualizer, stuff drifting from the top of the screen like falling cotton.
     Cotton {
         /// The seed for the visualizer.
         #[arg(long)]
         seed: Option<u64>,
+
+        /// How much random color is added to every pixel as it drifts down.
+        #[arg(long, default_value = "0.01")]
+        noise: f32,
     },
 
     /// Runs the Credits visualizer, centered fft graph coming from the top of the screen.
     Credits,
+
+    /// Runs the Stereo visualizer, placing each frequency band horizontally by where it sits in the stereo image.
+    Stereo {
+        /// How much wider every band's glow is than usual.
+        #[arg(long, default_value = "1.0")]
+        spread: f32,
+    },
 }
 
 impl From<ProjectArgs> for Project {
@@ -108,7 +491,10 @@ impl From<ProjectArgs> for Project {
         Project {
             input: Some(value.input),
             output: Some(value.output),
+            range: value.range.into(),
             program: value.program.into(),
+            timeline: vec![],
+            playlist: None,
         }
     }
 }
@@ -118,7 +504,32 @@ impl From<ProjectOptionArgs> for Project {
         Project {
             input: value.input,
             output: value.output,
+            range: value.range.into(),
             program: value.program.into(),
+            timeline: vec![],
+            playlist: None,
+        }
+    }
+}
+
+impl From<TimeRangeArgs> for TimeRange {
+    fn from(value: TimeRangeArgs) -> Self {
+        TimeRange {
+            start: value.start,
+            duration: value.duration,
+            end: value.end,
+            pre_roll: value.pre_roll,
+        }
+    }
+}
+
+impl From<AnalysisRangeArgs> for TimeRange {
+    fn from(value: AnalysisRangeArgs) -> Self {
+        TimeRange {
+            start: value.start,
+            duration: value.duration,
+            end: value.end,
+            pre_roll: None,
         }
     }
 }
@@ -128,7 +539,95 @@ impl From<ProgramArgs> for Program {
         Program {
             width: value.width,
             height: value.height,
+            frame_rate: value.frame_rate,
+            fft_size: value.fft_size,
+            window: value.window,
+            spectrum: value.spectrum.into(),
+            rhythm: value.rhythm.into(),
+            tempo: value.tempo.into(),
+            chroma: value.chroma.into(),
+            track: value.track.into(),
+            gain: value.gain.into(),
+            layers: std::iter::once(Layer::from(VisualizerEnum::from(value.visualizer)))
+                .chain(value.layers.into_iter().map(Layer::from))
+                .collect(),
+            modulation: vec![],
+        }
+    }
+}
+
+impl From<SpectrumArgs> for SpectrumConfig {
+    fn from(value: SpectrumArgs) -> Self {
+        SpectrumConfig {
+            bands: value.bands,
+            band_scale: value.band_scale,
+            min_frequency: value.min_frequency,
+            max_frequency: value.max_frequency,
+            magnitude_scale: value.magnitude_scale,
+            floor_db: value.floor_db,
+            ceiling_db: value.ceiling_db,
+            attack: value.attack,
+            release: value.release,
+        }
+    }
+}
+
+impl From<RhythmArgs> for RhythmConfig {
+    fn from(value: RhythmArgs) -> Self {
+        RhythmConfig {
+            onset_threshold: value.onset_threshold,
+            min_onset_interval: value.min_onset_interval,
+            min_bpm: value.min_bpm,
+            max_bpm: value.max_bpm,
+        }
+    }
+}
+
+impl From<TempoArgs> for TempoConfig {
+    fn from(value: TempoArgs) -> Self {
+        TempoConfig {
+            enabled: value.tempo_map,
+            beats_per_bar: value.beats_per_bar,
+        }
+    }
+}
+
+impl From<TrackArgs> for TrackConfig {
+    fn from(value: TrackArgs) -> Self {
+        TrackConfig {
+            enabled: value.two_pass,
+        }
+    }
+}
+
+impl From<GainArgs> for GainConfig {
+    fn from(value: GainArgs) -> Self {
+        GainConfig {
+            mode: value.auto_gain,
+            attack: value.gain_attack,
+            release: value.gain_release,
+            max_gain_db: value.max_gain_db,
+        }
+    }
+}
+
+impl From<ChromaArgs> for ChromaConfig {
+    fn from(value: ChromaArgs) -> Self {
+        ChromaConfig {
+            min_frequency: value.chroma_min_frequency,
+            max_frequency: value.chroma_max_frequency,
+            estimate_key: value.estimate_key,
+        }
+    }
+}
+
+impl From<LayerArgs> for Layer {
+    fn from(value: LayerArgs) -> Self {
+        Layer {
             visualizer: value.visualizer.into(),
+            opacity: value.opacity.into(),
+            blend: value.blend,
+            channels: value.channels,
         }
     }
 }
@@ -136,11 +635,25 @@ impl From<ProgramArgs> for Program {
 impl From<VisualizerArgs> for VisualizerEnum {
     fn from(value: VisualizerArgs) -> Self {
         match value {
-            VisualizerArgs::Bars => VisualizerEnum::Bars(BarsVisualizerInput {}),
-            VisualizerArgs::Cotton { seed } => {
-                VisualizerEnum::Cotton(CottonVisualizerInput { seed })
+            VisualizerArgs::Bars {
+                beat_flash,
+                progress_bar,
+                brightness,
+            } => VisualizerEnum::Bars(BarsVisualizerInput {
+                beat_flash,
+                progress_bar,
+                brightness: brightness.into(),
+            }),
+            VisualizerArgs::Cotton { seed, noise } => {
+                VisualizerEnum::Cotton(CottonVisualizerInput {
+                    seed: seed.map(Seed::Constant),
+                    noise: noise.into(),
+                })
             }
             VisualizerArgs::Credits => VisualizerEnum::Credits(CreditsVisualizerInput {}),
+            VisualizerArgs::Stereo { spread } => VisualizerEnum::Stereo(StereoVisualizerInput {
+                spread: spread.into(),
+            }),
         }
     }
 }
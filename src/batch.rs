@@ -0,0 +1,279 @@
+//! This module renders many inputs with the same project
+
+use crate::args::BatchCommandArgs;
+use crate::progress::{report, JobStatus, ProgressEvent, ProgressFormat};
+use crate::project::{Project, RenderOptions, RenderOutcome};
+use anyhow::{bail, Context};
+use futures::{stream, StreamExt};
+use std::collections::HashSet;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use tokio::runtime::Handle;
+
+/// One input of a batch and where its video goes.
+#[derive(Debug, Clone)]
+struct BatchJob {
+    input: PathBuf,
+    output: PathBuf,
+}
+
+/// Renders every input of a batch, reporting all the renders that failed at the end.
+pub async fn run_batch(
+    project: Project,
+    args: &BatchCommandArgs,
+    options: &RenderOptions,
+) -> anyhow::Result<RenderOutcome> {
+    if project.playlist.is_some() {
+        bail!("Batches render every input on its own, so the project can't have a playlist");
+    }
+
+    let inputs = expand_inputs(&args.inputs)?;
+    if inputs.is_empty() {
+        bail!("None of the input patterns match any files");
+    }
+
+    let mut outputs = HashSet::new();
+    let mut jobs = vec![];
+    for (index, input) in inputs.into_iter().enumerate() {
+        let output = output_path(&args.output, &input, index + 1)
+            .with_context(|| format!("Building output path for {:?}", input))?;
+        if !outputs.insert(output.clone()) {
+            bail!("More than one input would be rendered to {:?}", output);
+        }
+        jobs.push(BatchJob { input, output });
+    }
+
+    let total = jobs.len();
+    let mut skipped = 0;
+    if args.skip_existing {
+        jobs.retain(|job| {
+            let exists = job.output.exists();
+            if exists {
+                info!("Skipping {:?}, {:?} already exists", job.input, job.output);
+                skipped += 1;
+            }
+            !exists
+        });
+    }
+
+    info!(
+        "Rendering {} of {} inputs, {} at a time",
+        jobs.len(),
+        total,
+        args.jobs
+    );
+
+    // progress lines of renders running side by side would interleave, so only the end of each one is reported
+    let render_options = RenderOptions {
+        progress: if args.jobs > 1 {
+            ProgressFormat::None
+        } else {
+            options.progress
+        },
+        ..options.clone()
+    };
+
+    let handle = Handle::current();
+    let mut renders = stream::iter(jobs)
+        .map(|job| {
+            // renders go to a partial file first, so a cancelled or failed one is never skipped as done later
+            let partial = partial_path(&job.output);
+            let project = Project {
+                input: Some(job.input.clone()),
+                output: Some(partial.clone()),
+                ..project.clone()
+            };
+            let options = render_options.clone();
+            let handle = handle.clone();
+
+            async move {
+                if options.cancel.is_cancelled() {
+                    return (job, Ok(RenderOutcome::Cancelled));
+                }
+
+                info!("Rendering {:?} to {:?}", job.input, job.output);
+
+                // the render future isn't Send, so each one is driven on a thread of its own
+                let result = tokio::task::spawn_blocking(move || {
+                    handle.block_on(project.visualize(&options))
+                })
+                .await
+                .context("Joining render")
+                .and_then(|result| result);
+
+                let result = settle_output(&partial, &job.output, result);
+                (job, result)
+            }
+        })
+        .buffer_unordered(args.jobs as usize);
+
+    let mut finished = 0;
+    let mut cancelled = false;
+    let mut failures = vec![];
+    let mut done = 0;
+    while let Some((job, result)) = renders.next().await {
+        done += 1;
+        let status = JobStatus {
+            input: job.input.clone(),
+            output: job.output.clone(),
+            done,
+            total: total - skipped,
+        };
+
+        match result {
+            Ok(RenderOutcome::Finished) => {
+                report(options.progress, ProgressEvent::JobFinished(status));
+                finished += 1;
+            }
+            Ok(RenderOutcome::Cancelled) => {
+                report(options.progress, ProgressEvent::JobCancelled(status));
+                cancelled = true;
+            }
+            Err(err) => {
+                error!("Rendering {:?} failed: {:#}", job.input, err);
+                report(options.progress, ProgressEvent::JobFailed(status));
+                failures.push((job, err));
+            }
+        }
+    }
+
+    info!(
+        "Batch: {} rendered, {} skipped, {} failed",
+        finished,
+        skipped,
+        failures.len()
+    );
+    for (job, err) in &failures {
+        error!("Failed: {:?}: {:#}", job.input, err);
+    }
+
+    if cancelled {
+        return Ok(RenderOutcome::Cancelled);
+    }
+    if !failures.is_empty() {
+        bail!(
+            "{} of {} inputs failed to render",
+            failures.len(),
+            total - skipped
+        );
+    }
+
+    Ok(RenderOutcome::Finished)
+}
+
+/// Gets where an output is rendered to before it is finished, keeping its extension for the muxer to go by.
+fn partial_path(output: &Path) -> PathBuf {
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+    match output.extension() {
+        Some(ext) => output.with_file_name(format!("{}.partial.{}", stem, ext.to_string_lossy())),
+        None => output.with_file_name(format!("{}.partial", stem)),
+    }
+}
+
+/// Moves a finished render into place, or removes what a cancelled or failed one left behind.
+fn settle_output(
+    partial: &Path,
+    output: &Path,
+    result: anyhow::Result<RenderOutcome>,
+) -> anyhow::Result<RenderOutcome> {
+    if let Ok(RenderOutcome::Finished) = result {
+        std::fs::rename(partial, output)
+            .with_context(|| format!("Moving {:?} to {:?}", partial, output))?;
+    } else if let Err(err) = std::fs::remove_file(partial) {
+        if err.kind() != ErrorKind::NotFound {
+            warn!("Error removing {:?}: {}", partial, err);
+        }
+    }
+
+    result
+}
+
+/// Expands the glob patterns among the inputs, keeping the order they were given in.
+fn expand_inputs(patterns: &[String]) -> anyhow::Result<Vec<PathBuf>> {
+    let mut inputs = vec![];
+    for pattern in patterns {
+        if !pattern.contains(['*', '?', '[']) {
+            inputs.push(PathBuf::from(pattern));
+            continue;
+        }
+
+        let start = inputs.len();
+        for path in glob::glob(pattern).with_context(|| format!("Parsing pattern {}", pattern))? {
+            inputs.push(path.with_context(|| format!("Expanding pattern {}", pattern))?);
+        }
+        if inputs.len() == start {
+            warn!("Pattern {} does not match any files", pattern);
+        }
+    }
+
+    Ok(inputs)
+}
+
+/// Fills in the placeholders of an output template for the given input.
+fn output_path(template: &str, input: &Path, index: usize) -> anyhow::Result<PathBuf> {
+    let dir = match input.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_string_lossy(),
+        _ => ".".into(),
+    };
+    let stem = input.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = input.extension().unwrap_or_default().to_string_lossy();
+
+    let mut output = String::new();
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        output.push_str(&rest[..open]);
+
+        let Some(close) = rest[open..].find('}') else {
+            bail!("Unclosed placeholder in output template {}", template);
+        };
+        match &rest[open + 1..open + close] {
+            "dir" => output.push_str(&dir),
+            "stem" => output.push_str(&stem),
+            "ext" => output.push_str(&ext),
+            "index" => output.push_str(&index.to_string()),
+            placeholder => bail!("Unknown placeholder {{{}}} in output template", placeholder),
+        }
+
+        rest = &rest[open + close + 1..];
+    }
+    output.push_str(rest);
+
+    Ok(output.into())
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    #[test]
+    fn output_paths_are_built_from_the_template() {
+        let input = Path::new("album/01 Intro.flac");
+        assert_eq!(
+            output_path("{dir}/{stem}.webm", input, 1).unwrap(),
+            PathBuf::from("album/01 Intro.webm")
+        );
+        assert_eq!(
+            output_path("out/{index}-{stem}.{ext}.mp4", input, 3).unwrap(),
+            PathBuf::from("out/3-01 Intro.flac.mp4")
+        );
+        assert_eq!(
+            output_path("{dir}/{stem}.webm", Path::new("track.wav"), 1).unwrap(),
+            PathBuf::from("./track.webm")
+        );
+
+        assert!(output_path("{name}.webm", input, 1).is_err());
+        assert!(output_path("{stem.webm", input, 1).is_err());
+    }
+
+    #[test]
+    fn partial_outputs_keep_their_extension() {
+        assert_eq!(
+            partial_path(Path::new("album/01 Intro.webm")),
+            PathBuf::from("album/01 Intro.partial.webm")
+        );
+        assert_eq!(
+            partial_path(Path::new("out/video")),
+            PathBuf::from("out/video.partial")
+        );
+    }
+}
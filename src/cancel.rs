@@ -0,0 +1,58 @@
+//! This module contains the cancellation of renders by signals
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// The exit status of a render that was cancelled, following the shell convention for SIGINT.
+pub const CANCELLED_EXIT_CODE: u8 = 130;
+
+/// A flag that tells a running render to wind down early.
+#[derive(Debug, Clone, Default)]
+pub struct CancelFlag(Arc<AtomicBool>);
+
+impl CancelFlag {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Sets the flag on the first SIGINT or SIGTERM, and exits immediately on the second.
+pub fn cancel_on_signal(flag: CancelFlag) {
+    tokio::spawn(async move {
+        wait_for_signal().await;
+        warn!("Cancelling, finishing the output file... (signal again to exit immediately)");
+        flag.cancel();
+
+        wait_for_signal().await;
+        error!("Exiting without finishing the output file");
+        std::process::exit(CANCELLED_EXIT_CODE as i32);
+    });
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(err) => {
+            warn!("Unable to listen for SIGTERM: {}", err);
+            tokio::signal::ctrl_c().await.ok();
+            return;
+        }
+    };
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    tokio::signal::ctrl_c().await.ok();
+}
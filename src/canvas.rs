@@ -0,0 +1,273 @@
+//! This module contains the canvas that visualizers draw their frames onto
+
+use crate::util::RGB;
+use clap::ValueEnum;
+use ffmpeg_next::frame;
+use serde::{Deserialize, Serialize};
+
+/// How the pixels of one canvas are combined with those of a canvas beneath it.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub enum BlendMode {
+    /// The upper pixel replaces the lower one.
+    #[default]
+    Normal,
+    /// The pixels are added together, saturating at white.
+    Add,
+    /// The inverted pixels are multiplied, which brightens like add but never saturates.
+    Screen,
+    /// The pixels are multiplied, which darkens.
+    Multiply,
+}
+
+impl BlendMode {
+    pub fn apply(self, lower: RGB, upper: RGB) -> RGB {
+        let channel = |lower: f32, upper: f32| match self {
+            BlendMode::Normal => upper,
+            BlendMode::Add => (lower + upper).min(1.0),
+            BlendMode::Screen => 1.0 - (1.0 - lower) * (1.0 - upper),
+            BlendMode::Multiply => lower * upper,
+        };
+
+        RGB::new(
+            channel(lower.r, upper.r),
+            channel(lower.g, upper.g),
+            channel(lower.b, upper.b),
+        )
+    }
+}
+
+/// An ARGB image whose rows may be padded, like the planes of an ffmpeg frame.
+///
+/// Each row starts `stride` bytes after the previous one, and only its first `width * 4` bytes are pixels.
+#[derive(Debug, Clone)]
+pub struct Canvas<B> {
+    data: B,
+    width: u32,
+    height: u32,
+    stride: usize,
+}
+
+impl Canvas<Vec<u8>> {
+    /// Creates a black, unpadded canvas.
+    pub fn blank(width: u32, height: u32) -> Canvas<Vec<u8>> {
+        let stride = width as usize * 4;
+        Canvas::new(vec![0u8; stride * height as usize], width, height, stride)
+    }
+}
+
+impl<'a> Canvas<&'a mut [u8]> {
+    /// Creates a canvas over the first plane of an ARGB video frame.
+    pub fn from_video(video: &'a mut frame::Video) -> Canvas<&'a mut [u8]> {
+        let width = video.width();
+        let height = video.height();
+        let stride = video.stride(0);
+        Canvas::new(video.data_mut(0), width, height, stride)
+    }
+}
+
+impl<B: AsRef<[u8]>> Canvas<B> {
+    pub fn new(data: B, width: u32, height: u32, stride: usize) -> Canvas<B> {
+        let row_len = width as usize * 4;
+        assert!(stride >= row_len, "Canvas stride is shorter than a row");
+        if height > 0 {
+            assert!(
+                data.as_ref().len() >= stride * (height as usize - 1) + row_len,
+                "Canvas buffer is too small"
+            );
+        }
+
+        Canvas {
+            data,
+            width,
+            height,
+            stride,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// The number of bytes between the starts of consecutive rows.
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        x < self.width as usize && y < self.height as usize
+    }
+
+    /// Gets the ARGB bytes of a row, without any padding.
+    pub fn row(&self, y: usize) -> &[u8] {
+        assert!(y < self.height as usize, "Row {} is outside the canvas", y);
+        let start = y * self.stride;
+        &self.data.as_ref()[start..(start + self.width as usize * 4)]
+    }
+
+    pub fn rows(&self) -> impl Iterator<Item = &[u8]> {
+        (0..self.height as usize).map(|y| self.row(y))
+    }
+
+    /// Gets the color of a pixel.
+    ///
+    /// Panics if the pixel is outside the canvas, even if it is inside the padding of the underlying buffer.
+    pub fn get_pixel(&self, x: usize, y: usize) -> RGB {
+        self.check_bounds(x, y);
+        RGB::from_argb(&self.row(y)[x * 4..(x * 4 + 4)])
+    }
+
+    fn check_bounds(&self, x: usize, y: usize) {
+        assert!(
+            self.contains(x, y),
+            "Pixel ({}, {}) is outside the {}x{} canvas",
+            x,
+            y,
+            self.width,
+            self.height
+        );
+    }
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> Canvas<B> {
+    pub fn row_mut(&mut self, y: usize) -> &mut [u8] {
+        assert!(y < self.height as usize, "Row {} is outside the canvas", y);
+        let start = y * self.stride;
+        let end = start + self.width as usize * 4;
+        &mut self.data.as_mut()[start..end]
+    }
+
+    pub fn rows_mut(&mut self) -> impl Iterator<Item = &mut [u8]> {
+        let row_len = self.width as usize * 4;
+        self.data
+            .as_mut()
+            .chunks_mut(self.stride)
+            .take(self.height as usize)
+            .map(move |row| &mut row[..row_len])
+    }
+
+    /// Sets the color of a pixel, making it opaque.
+    ///
+    /// Panics if the pixel is outside the canvas, even if it is inside the padding of the underlying buffer.
+    pub fn set_pixel(&mut self, x: usize, y: usize, color: RGB) {
+        self.check_bounds(x, y);
+        self.row_mut(y)[x * 4..(x * 4 + 4)].copy_from_slice(&color.to_argb());
+    }
+
+    pub fn fill(&mut self, color: RGB) {
+        let argb = color.to_argb();
+        for row in self.rows_mut() {
+            for pixel in row.chunks_exact_mut(4) {
+                pixel.copy_from_slice(&argb);
+            }
+        }
+    }
+
+    /// Copies every pixel of a canvas with the same dimensions into this one.
+    pub fn copy_from<C: AsRef<[u8]>>(&mut self, other: &Canvas<C>) {
+        assert_eq!(
+            (self.width, self.height),
+            (other.width, other.height),
+            "Canvas dimensions differ"
+        );
+
+        for (row, other_row) in self.rows_mut().zip(other.rows()) {
+            row.copy_from_slice(other_row);
+        }
+    }
+
+    /// Blends every pixel of a canvas with the same dimensions on top of this one.
+    ///
+    /// The blended result is mixed with the original pixels by `opacity`, from 0 for unchanged up to 1.
+    pub fn blend_from<C: AsRef<[u8]>>(&mut self, other: &Canvas<C>, mode: BlendMode, opacity: f32) {
+        assert_eq!(
+            (self.width, self.height),
+            (other.width, other.height),
+            "Canvas dimensions differ"
+        );
+
+        if mode == BlendMode::Normal && opacity >= 1.0 {
+            self.copy_from(other);
+            return;
+        }
+
+        let opacity = opacity.clamp(0.0, 1.0);
+        for (row, other_row) in self.rows_mut().zip(other.rows()) {
+            for (pixel, other_pixel) in row.chunks_exact_mut(4).zip(other_row.chunks_exact(4)) {
+                let lower = RGB::from_argb(pixel);
+                let blended = mode.apply(lower, RGB::from_argb(other_pixel));
+                pixel.copy_from_slice(
+                    &(lower.scale(1.0 - opacity) + blended.scale(opacity)).to_argb(),
+                );
+            }
+        }
+    }
+
+    /// Borrows this canvas as one over a mutable slice.
+    pub fn view_mut(&mut self) -> Canvas<&mut [u8]> {
+        Canvas {
+            data: self.data.as_mut(),
+            width: self.width,
+            height: self.height,
+            stride: self.stride,
+        }
+    }
+
+    /// Gets the whole underlying buffer, including row padding.
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        self.data.as_mut()
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    #[test]
+    fn padded_rows_are_skipped() {
+        let mut data = [0u8; 16 * 3];
+        let mut canvas = Canvas::new(&mut data[..], 3, 3, 16);
+
+        canvas.fill(RGB::new(0.5, 0.5, 0.5));
+        canvas.set_pixel(2, 1, RGB::ZERO);
+
+        assert!(canvas.rows().all(|row| row.len() == 12));
+        assert_eq!(canvas.row(1)[8..12], [0xFF, 0, 0, 0]);
+        assert_eq!(
+            canvas.get_pixel(1, 1),
+            RGB::from_argb(&[0xFF, 128, 128, 128])
+        );
+
+        // padding bytes are never written
+        assert!(data.chunks(16).all(|row| row[12..].iter().all(|&b| b == 0)));
+    }
+
+    #[test]
+    fn blend_modes_mix_canvases() {
+        let mut upper = Canvas::blank(1, 1);
+        upper.fill(RGB::new(0.5, 0.5, 0.5));
+        let blend = |mode, opacity| {
+            let mut lower = Canvas::blank(1, 1);
+            lower.fill(RGB::new(0.5, 0.25, 0.0));
+            lower.blend_from(&upper, mode, opacity);
+            lower.row(0).to_vec()
+        };
+
+        assert_eq!(blend(BlendMode::Normal, 1.0), [0xFF, 128, 128, 128]);
+        assert_eq!(blend(BlendMode::Normal, 0.0), [0xFF, 128, 64, 0]);
+        assert_eq!(blend(BlendMode::Add, 1.0), [0xFF, 255, 193, 129]);
+        assert_eq!(blend(BlendMode::Screen, 1.0), [0xFF, 192, 160, 128]);
+        assert_eq!(blend(BlendMode::Multiply, 1.0), [0xFF, 64, 32, 0]);
+        assert_eq!(blend(BlendMode::Add, 0.5), [0xFF, 192, 128, 64]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn pixels_in_padding_are_out_of_bounds() {
+        let mut data = [0u8; 16 * 3];
+        Canvas::new(&mut data[..], 3, 3, 16).set_pixel(3, 0, RGB::ZERO);
+    }
+}
@@ -1,87 +1,222 @@
-use crate::ffmpeg::{audio_filter, AudioFormat, FfmpegResult};
+use crate::cancel::CancelFlag;
+use crate::ffmpeg::{audio_filter, trim_filter_spec, AudioFormat, FfmpegResult};
 use crate::recycle::simple::RecycleProducer;
-use anyhow::Context;
+use crate::util::Timestamp;
+use anyhow::{bail, Context};
 use ffmpeg_next::format::context::Input;
-use ffmpeg_next::{codec, filter, format, frame, media, Packet, Rational};
-use std::path::PathBuf;
+use ffmpeg_next::{codec, filter, format, frame, media, rescale, Packet, Rational, Rescale};
+use std::path::{Path, PathBuf};
 use tokio::task::JoinHandle;
 
+/// The part of the input to decode, relative to the start of its audio stream.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct DecodeRange {
+    pub start: Timestamp,
+    pub end: Option<Timestamp>,
+}
+
+/// What to decode: a single file, or several played back to back.
+#[derive(Debug, Clone)]
+pub enum DecodeSource {
+    File(PathBuf),
+    /// Tracks decoded one after the other as if they were a single input.
+    Playlist(Vec<DecodeTrack>),
+}
+
+/// A track of a playlist.
+#[derive(Debug, Clone)]
+pub struct DecodeTrack {
+    pub path: PathBuf,
+    /// How long the track lasts, cut short or padded with silence to match.
+    pub length: Timestamp,
+}
+
+/// Gets the sample each track of a playlist should start on when decoded at the given rate.
+///
+/// The starts are rounded from the sum of the lengths before them, so they don't drift away from the timestamps the
+/// chapters and title cards are placed at.
+pub fn playlist_track_starts(tracks: &[DecodeTrack], sample_rate: u32) -> Vec<i64> {
+    tracks
+        .iter()
+        .scan(Timestamp::ZERO, |start, track| {
+            let track_start = *start;
+            *start = *start + track.length;
+            Some(track_start.to_samples(sample_rate))
+        })
+        .collect()
+}
+
+/// What is known about an input file without decoding it.
+#[derive(Debug, Clone, Default)]
+pub struct InputInfo {
+    pub duration: Option<Timestamp>,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+}
+
+/// Reads the duration and tags of an input file.
+pub fn probe(path: &Path) -> anyhow::Result<InputInfo> {
+    let ictx = format::input(&path).context("Opening input file")?;
+
+    let stream = ictx
+        .streams()
+        .best(media::Type::Audio)
+        .context("No audio stream")?;
+
+    // some containers keep their tags on the audio stream instead
+    let metadata = ictx.metadata();
+    let stream_metadata = stream.metadata();
+    let tag = |key: &str| {
+        metadata
+            .get(key)
+            .or_else(|| stream_metadata.get(key))
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+    };
+
+    Ok(InputInfo {
+        duration: input_duration(&ictx, &stream),
+        title: tag("title"),
+        artist: tag("artist"),
+    })
+}
+
+/// Gets the duration of an input, preferring its audio stream's own duration over the container's.
+fn input_duration(ictx: &Input, stream: &format::Stream) -> Option<Timestamp> {
+    let stream_duration = stream
+        .duration()
+        .rescale(stream.time_base(), rescale::TIME_BASE);
+    [stream_duration, ictx.duration()]
+        .into_iter()
+        .find(|&duration| duration > 0)
+        .map(|duration| Timestamp::from_micros(duration as u64))
+}
+
 pub struct DecoderHandle {
     handle: JoinHandle<anyhow::Result<()>>,
+    duration: Option<Timestamp>,
 }
 
 impl DecoderHandle {
     pub async fn spawn(
-        path: PathBuf,
+        source: DecodeSource,
         output_format: AudioFormat,
+        range: DecodeRange,
+        cancel: CancelFlag,
         producer: RecycleProducer<frame::Audio>,
     ) -> anyhow::Result<DecoderHandle> {
-        let (ictx, state) = tokio::task::spawn_blocking(move || {
-            let ictx = format::input(&path).context("Opening input file")?;
-
-            let stream = ictx
-                .streams()
-                .best(media::Type::Audio)
-                .context("No audio stream")?;
-
-            let stream_idx = stream.index();
-
-            let context = codec::context::Context::from_parameters(stream.parameters())
-                .context("Initializing input codec")?;
-            let mut decoder = context
-                .decoder()
-                .audio()
-                .context("Getting input audio codec")?;
-
-            decoder
-                .set_parameters(stream.parameters())
-                .context("Setting input codec parameters")?;
-
-            format::context::input::dump(&ictx, 0, Some(&path.to_string_lossy()));
-
-            info!("Time base: {}", decoder.time_base());
-            info!("Sample rate: {}", decoder.rate());
-            info!("Sample format: {:?}", decoder.format());
-            info!("Channel Layout: {:?}", decoder.channel_layout());
-            info!("Frame size: {}", decoder.frame_size());
-
-            let filter = audio_filter(AudioFormat::from_decoder(&decoder), output_format)
-                .context("Creating filter graph")?;
-
-            let in_time_base = decoder.time_base();
-
-            Ok::<_, anyhow::Error>((
-                ictx,
-                DecoderState {
-                    producer,
-                    stream_idx,
-                    filter,
-                    decoder,
-                    decoded: frame::Audio::empty(),
-                    in_time_base,
-                },
+        let tracks = match source {
+            DecodeSource::File(path) => {
+                let (ictx, state, duration) = tokio::task::spawn_blocking(move || {
+                    open(&path, output_format, range, None, cancel, producer)
+                })
+                .await
+                .expect("spawn_blocking error")
+                .context("Creating decoder state")?;
+
+                let handle: JoinHandle<anyhow::Result<()>> =
+                    tokio::task::spawn_blocking(move || {
+                        log_error(Self::do_decode(ictx, state).map(|_| ()))
+                    });
+
+                return Ok(DecoderHandle { handle, duration });
+            }
+            DecodeSource::Playlist(tracks) => tracks,
+        };
+
+        if range.start > Timestamp::ZERO || range.end.is_some() {
+            bail!("Playlists can only be decoded as a whole");
+        }
+
+        let duration = tracks
+            .iter()
+            .fold(Timestamp::ZERO, |total, track| total + track.length);
+
+        let handle: JoinHandle<anyhow::Result<()>> = tokio::task::spawn_blocking(move || {
+            log_error(Self::do_decode_playlist(
+                tracks,
+                output_format,
+                cancel,
+                producer,
             ))
+        });
+
+        Ok(DecoderHandle {
+            handle,
+            duration: Some(duration),
         })
-        .await
-        .expect("spawn_blocking error")
-        .context("Creating decoder state")?;
-
-        let handle: JoinHandle<anyhow::Result<()>> =
-            tokio::task::spawn_blocking(move || match Self::do_decode(ictx, state) {
-                Ok(()) => Ok(()),
-                Err(err) => {
-                    error!("Decode error: {:#}", &err);
-                    Err(err)
-                }
-            });
+    }
+
+    fn do_decode_playlist(
+        tracks: Vec<DecodeTrack>,
+        output_format: AudioFormat,
+        cancel: CancelFlag,
+        mut producer: RecycleProducer<frame::Audio>,
+    ) -> anyhow::Result<()> {
+        let starts = playlist_track_starts(&tracks, output_format.sample_rate);
+
+        let mut pts_offset = 0;
+        for (index, track) in tracks.into_iter().enumerate() {
+            if cancel.is_cancelled() {
+                info!("Decoding cancelled.");
+                break;
+            }
+
+            info!("Decoding track {}: {:?}", index + 1, track.path);
+
+            // pts follow on from the tracks before, so this only happens if one of them came out too short or long
+            if pts_offset != starts[index] {
+                warn!(
+                    "Track {} starts {} samples off from where it was placed",
+                    index + 1,
+                    pts_offset - starts[index]
+                );
+            }
+
+            // every track is cut or padded to exactly its length, so the next one starts right on its boundary
+            let range = DecodeRange {
+                start: Timestamp::ZERO,
+                end: Some(track.length),
+            };
+            let (ictx, mut state, _) = open(
+                &track.path,
+                output_format,
+                range,
+                Some(track.length),
+                cancel.clone(),
+                producer,
+            )
+            .with_context(|| format!("Opening track {:?}", track.path))?;
+            state.pts_offset = pts_offset;
 
-        Ok(DecoderHandle { handle })
+            let state = Self::do_decode(ictx, state)
+                .with_context(|| format!("Decoding track {:?}", track.path))?;
+
+            pts_offset += state.samples_out;
+            producer = state.producer;
+        }
+
+        Ok(())
     }
 
-    fn do_decode(mut ictx: Input, mut state: DecoderState) -> anyhow::Result<()> {
+    /// Decodes the rest of the input, handing back the state so the producer can be reused.
+    fn do_decode(mut ictx: Input, mut state: DecoderState) -> anyhow::Result<DecoderState> {
         for (stream, mut packet) in ictx.packets() {
+            if state.cancel.is_cancelled() {
+                info!("Decoding cancelled.");
+                break;
+            }
+
             if stream.index() == state.stream_idx {
                 packet.rescale_ts(stream.time_base(), state.in_time_base);
+
+                if let (Some(pts), Some(end_ts)) = (packet.pts(), state.end_ts) {
+                    if pts - state.origin > end_ts {
+                        // everything after this point would be trimmed anyway
+                        break;
+                    }
+                }
+
                 state.send_packet_to_decoder(&packet)?;
                 state
                     .receive_and_process_decoded_frames()
@@ -101,7 +236,12 @@ impl DecoderHandle {
 
         info!("Done decoding.");
 
-        Ok(())
+        Ok(state)
+    }
+
+    /// Gets the duration of the whole input, if the container knows it.
+    pub fn duration(&self) -> Option<Timestamp> {
+        self.duration
     }
 
     pub async fn join(self) -> anyhow::Result<()> {
@@ -109,6 +249,109 @@ impl DecoderHandle {
     }
 }
 
+fn log_error(result: anyhow::Result<()>) -> anyhow::Result<()> {
+    if let Err(err) = &result {
+        error!("Decode error: {:#}", err);
+    }
+    result
+}
+
+/// Opens an input file and sets up its decoder, seeking to the start of the range.
+///
+/// With `pad_to`, the decoded audio is padded with silence until it is at least that long.
+fn open(
+    path: &Path,
+    output_format: AudioFormat,
+    range: DecodeRange,
+    pad_to: Option<Timestamp>,
+    cancel: CancelFlag,
+    producer: RecycleProducer<frame::Audio>,
+) -> anyhow::Result<(Input, DecoderState, Option<Timestamp>)> {
+    let mut ictx = format::input(&path).context("Opening input file")?;
+
+    let stream = ictx
+        .streams()
+        .best(media::Type::Audio)
+        .context("No audio stream")?;
+
+    let stream_idx = stream.index();
+    let stream_time_base = stream.time_base();
+    // streams without a start time report AV_NOPTS_VALUE
+    let stream_start = match stream.start_time() {
+        i64::MIN => 0,
+        start_time => start_time,
+    };
+
+    let duration = input_duration(&ictx, &stream);
+
+    let context = codec::context::Context::from_parameters(stream.parameters())
+        .context("Initializing input codec")?;
+    let mut decoder = context
+        .decoder()
+        .audio()
+        .context("Getting input audio codec")?;
+
+    decoder
+        .set_parameters(stream.parameters())
+        .context("Setting input codec parameters")?;
+
+    format::context::input::dump(&ictx, 0, Some(&path.to_string_lossy()));
+
+    info!("Time base: {}", decoder.time_base());
+    info!("Sample rate: {}", decoder.rate());
+    info!("Sample format: {:?}", decoder.format());
+    info!("Channel Layout: {:?}", decoder.channel_layout());
+    info!("Frame size: {}", decoder.frame_size());
+    if let Some(duration) = duration {
+        info!("Duration: {}", duration);
+    }
+
+    let in_time_base = decoder.time_base();
+
+    let mut filter_spec = trim_filter_spec(range.start, range.end);
+    if let Some(pad_to) = pad_to {
+        filter_spec += &format!(",apad=whole_dur={}us", pad_to.as_micros());
+    }
+
+    let filter = audio_filter(
+        AudioFormat::from_decoder(&decoder),
+        output_format,
+        &filter_spec,
+    )
+    .context("Creating filter graph")?;
+
+    if range.start > Timestamp::ZERO {
+        info!("Seeking to {}", range.start);
+
+        // seek to at or before the start, the filter trims the rest
+        let target = stream_start.rescale(stream_time_base, rescale::TIME_BASE)
+            + range.start.as_micros() as i64;
+        ictx.seek(target, ..target).context("Seeking input")?;
+    }
+
+    let end_ts = range
+        .end
+        .map(|end| (end.as_micros() as i64).rescale(rescale::TIME_BASE, in_time_base));
+
+    Ok((
+        ictx,
+        DecoderState {
+            producer,
+            stream_idx,
+            filter,
+            decoder,
+            decoded: frame::Audio::empty(),
+            in_time_base,
+            origin: stream_start.rescale(stream_time_base, in_time_base),
+            end_ts,
+            pts_offset: 0,
+            samples_out: 0,
+            cancel,
+        },
+        duration,
+    ))
+}
+
 struct DecoderState {
     producer: RecycleProducer<frame::Audio>,
     stream_idx: usize,
@@ -116,6 +359,16 @@ struct DecoderState {
     decoder: codec::decoder::Audio,
     decoded: frame::Audio,
     in_time_base: Rational,
+    /// The timestamp of the start of the stream, in the input time base.
+    origin: i64,
+    /// The timestamp after which no audio is needed, relative to `origin`.
+    end_ts: Option<i64>,
+    /// What is added to the timestamps of the output, for tracks that come after others. Output timestamps count
+    /// samples.
+    pts_offset: i64,
+    /// The number of samples handed on so far.
+    samples_out: i64,
+    cancel: CancelFlag,
 }
 
 impl DecoderState {
@@ -137,7 +390,8 @@ impl DecoderState {
             .context("Receive frame from audio decoder")?
         {
             let timestamp = self.decoded.timestamp();
-            self.decoded.set_pts(timestamp);
+            self.decoded
+                .set_pts(timestamp.map(|timestamp| timestamp - self.origin));
             self.add_frame_to_filter()?;
             self.get_and_process_filtered_frames()
                 .context("Processing filtered frames")?;
@@ -183,6 +437,10 @@ impl DecoderState {
                 return Ok(());
             }
 
+            let pts = recycling.pts();
+            recycling.set_pts(pts.map(|pts| pts + self.pts_offset));
+            self.samples_out += recycling.samples() as i64;
+
             recycling.blocking_send().context("Sending frame")?;
         }
     }
@@ -1,6 +1,7 @@
 use crate::ffmpeg::extra::SourceExtra;
 use crate::ffmpeg::{audio_filter, AudioFormat, FfmpegResult};
 use crate::recycle::r#enum::EnumRecycleConsumer;
+use crate::util::{FrameRate, Timestamp};
 use anyhow::Context;
 use enum_key::KeyableEnum;
 use ffmpeg_next::{codec, encoder, filter, format, frame, software, Dictionary, Packet, Rational};
@@ -14,6 +15,19 @@ pub struct EncoderArgs {
     pub in_audio_format: AudioFormat,
     pub width: u32,
     pub height: u32,
+    pub frame_rate: FrameRate,
+    /// The number of leading audio samples that only serve as pre-roll and must not end up in the output.
+    pub skip_samples: usize,
+    /// The chapter markers to write into the container.
+    pub chapters: Vec<Chapter>,
+}
+
+/// A named part of the output, relative to its start.
+#[derive(Debug, Clone)]
+pub struct Chapter {
+    pub start: Timestamp,
+    pub end: Timestamp,
+    pub title: String,
 }
 
 #[derive(KeyableEnum)]
@@ -55,12 +69,12 @@ impl EncoderState {
 
                 video_encoder.set_width(args.width);
                 video_encoder.set_height(args.height);
-                video_encoder.set_frame_rate(Some((1, 24)));
-                video_encoder.set_time_base((1, 24));
+                video_encoder.set_frame_rate(Some(args.frame_rate));
+                video_encoder.set_time_base(args.frame_rate.time_base());
                 video_encoder.set_format(format::Pixel::YUV420P);
                 video_encoder.set_bit_rate(500000);
 
-                vost.set_time_base((1, 24));
+                vost.set_time_base(args.frame_rate.time_base());
 
                 if global_header {
                     video_encoder.set_flags(codec::Flags::GLOBAL_HEADER);
@@ -119,10 +133,36 @@ impl EncoderState {
                 (audio_encoder, aost.index())
             };
 
+            for (index, chapter) in args.chapters.iter().enumerate() {
+                octx.add_chapter(
+                    index as i64,
+                    Rational::new(1, 1_000_000),
+                    chapter.start.as_micros() as i64,
+                    chapter.end.as_micros() as i64,
+                    &chapter.title,
+                )
+                .context("Adding chapter")?;
+            }
+
             format::context::output::dump(&octx, 0, Some(&path.to_string_lossy()));
 
-            let audio_filter = audio_filter(args.in_audio_format, output_audio_format)
-                .context("Creating encoder audio filter")?;
+            let audio_filter_spec = if args.skip_samples > 0 {
+                format!(
+                    "atrim=start_sample={},asetpts=PTS-STARTPTS",
+                    args.skip_samples
+                )
+            } else {
+                "anull".to_string()
+            };
+
+            let audio_filter = audio_filter(
+                args.in_audio_format,
+                output_audio_format,
+                &audio_filter_spec,
+            )
+            .context("Creating encoder audio filter")?;
+
+            let in_video_tb = args.frame_rate.time_base();
 
             Ok::<_, anyhow::Error>(EncoderState {
                 args,
@@ -133,7 +173,7 @@ impl EncoderState {
                 audio_encoder,
                 video_encoder,
                 in_audio_tb: Rational::new(1, 48000),
-                in_video_tb: Rational::new(1, 24),
+                in_video_tb,
                 audio_filtered: frame::Audio::empty(),
                 packet: Packet::empty(),
             })
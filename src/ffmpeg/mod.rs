@@ -1,3 +1,4 @@
+use crate::util::Timestamp;
 use anyhow::Context;
 use ffmpeg_next::{codec, filter, format, frame, util, Error, Rational};
 use std::num::NonZeroU32;
@@ -31,7 +32,7 @@ impl Default for AudioFormat {
             sample_format: format::Sample::F32(format::sample::Type::Planar),
             channel_layout: util::channel_layout::ChannelLayout::default(2),
             sample_rate: 48000,
-            frame_size: NonZeroU32::new(2000),
+            frame_size: NonZeroU32::new(1024),
         }
     }
 }
@@ -120,9 +121,26 @@ impl From<AudioFormat> for (format::Sample, util::channel_layout::ChannelLayout,
     }
 }
 
+/// Builds a filter spec that keeps only the audio between `start` and `end` and restarts its timestamps at zero.
+///
+/// Trimming goes by the timestamps of the incoming frames rather than by counting samples, so it stays
+/// sample-accurate after seeking.
+pub fn trim_filter_spec(start: Timestamp, end: Option<Timestamp>) -> String {
+    if start == Timestamp::ZERO && end.is_none() {
+        return "anull".to_string();
+    }
+
+    let mut spec = format!("atrim=start={}us", start.as_micros());
+    if let Some(end) = end {
+        spec += &format!(":end={}us", end.as_micros());
+    }
+    spec + ",asetpts=PTS-STARTPTS"
+}
+
 pub fn audio_filter(
     input_format: AudioFormat,
     output_format: AudioFormat,
+    spec: &str,
 ) -> anyhow::Result<filter::Graph> {
     let mut filter = filter::Graph::new();
 
@@ -145,7 +163,7 @@ pub fn audio_filter(
         .context("Setting input")?
         .input("out", 0)
         .context("Setting output")?
-        .parse("anull")
+        .parse(spec)
         .context("Setting filter spec")?;
 
     info!("Filter:\n{}", filter.dump());
@@ -1,33 +1,65 @@
 #[macro_use]
 extern crate tracing;
 
+use crate::analysis::loudness::LoudnessReport;
+use crate::analysis::rhythm::RhythmConfig;
+use crate::analysis::tempo::{TempoConfig, TempoMap};
 use crate::args::Commands;
-use crate::project::Project;
+use crate::cancel::{CancelFlag, CANCELLED_EXIT_CODE};
+use crate::ffmpeg::decode::{DecodeRange, DecodeSource};
+use crate::progress::ProgressFormat;
+use crate::project::{Project, RenderOptions, RenderOutcome, TimeRange};
 use anyhow::{bail, Context};
 use args::Cli;
 use clap::Parser;
+use std::process::ExitCode;
 use tokio::fs::OpenOptions;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::AsyncWriteExt;
 
+mod analysis;
 mod args;
+mod batch;
+mod cancel;
+mod canvas;
 mod ffmpeg;
+mod playlist;
+mod preview;
+mod progress;
 mod project;
 mod recycle;
 mod visualizer;
 mod util;
 
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt().init();
+async fn main() -> anyhow::Result<ExitCode> {
+    let args = Cli::parse();
+
+    // keep stdout free for the JSON progress events
+    if args.progress == ProgressFormat::Json {
+        tracing_subscriber::fmt()
+            .with_writer(std::io::stderr)
+            .init();
+    } else {
+        tracing_subscriber::fmt().init();
+    }
     ffmpeg::init_ffmpeg()?;
 
-    let args = Cli::parse();
+    let cancel = CancelFlag::default();
+    cancel::cancel_on_signal(cancel.clone());
+
+    let options = RenderOptions {
+        progress: args.progress,
+        cancel,
+    };
 
-    match args.subcommand {
+    let outcome = match args.subcommand {
         Commands::Run(args) => {
             let project: Project = args.into();
 
-            project.visualize().await.context("Running visualization")?;
+            project
+                .visualize(&options)
+                .await
+                .context("Running visualization")?
         }
         Commands::CreateProject {
             project_file,
@@ -51,35 +83,25 @@ async fn main() -> anyhow::Result<()> {
                 .context("Writing project file")?;
 
             info!("Project file written to {:?}", &project_file);
+
+            RenderOutcome::Finished
         }
         Commands::RunProject {
             project_file,
             input,
             output,
+            range,
         } => {
-            info!("Loading project file from: {:?}", &project_file);
-
-            let mut open_project_file = OpenOptions::new()
-                .read(true)
-                .open(&project_file)
-                .await
-                .context("Opening project file")?;
-            let mut project_str = String::new();
-            open_project_file
-                .read_to_string(&mut project_str)
-                .await
-                .context("Reading project file")?;
-
-            let project_from_file: Project =
-                serde_json::from_str(&project_str).context("Deserializing project")?;
+            let project_from_file = Project::load(&project_file).await?;
 
             let project = Project {
                 input: input.or(project_from_file.input),
                 output: output.or(project_from_file.output),
+                range: project_from_file.range.merge(range.into()),
                 ..project_from_file
             };
 
-            if project.input.is_none() {
+            if project.input.is_none() && project.playlist.is_none() {
                 bail!("Neither project nor arguments provide an input file");
             }
 
@@ -87,11 +109,108 @@ async fn main() -> anyhow::Result<()> {
                 bail!("Neither project nor arguemnts provide an output file");
             }
 
-            project.visualize().await.context("Running visualization")?;
+            project
+                .visualize(&options)
+                .await
+                .context("Running visualization")?
+        }
+        Commands::Batch(args) => {
+            let project = Project::load(&args.project_file).await?;
+
+            let project = Project {
+                range: project.range.merge(args.range.clone().into()),
+                ..project
+            };
+
+            batch::run_batch(project, &args, &options).await?
+        }
+        Commands::Preview(args) => {
+            let project_from_file = Project::load(&args.project_file).await?;
+
+            let project = Project {
+                input: args.input.clone().or(project_from_file.input),
+                ..project_from_file
+            };
+
+            preview::run_preview(project, &args, &options).await?
+        }
+        Commands::Tempo(args) => {
+            let range: TimeRange = args.range.into();
+            let decode_range = DecodeRange {
+                start: range.start(),
+                end: range.end()?,
+            };
+
+            let tempo = TempoMap::analyze(
+                &DecodeSource::File(args.input),
+                decode_range,
+                options.cancel.clone(),
+                &TempoConfig {
+                    enabled: true,
+                    beats_per_bar: args.beats_per_bar,
+                },
+                &RhythmConfig {
+                    min_bpm: args.min_bpm,
+                    max_bpm: args.max_bpm,
+                    ..Default::default()
+                },
+            )
+            .await
+            .context("Analyzing tempo")?;
+
+            if args.json {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&tempo).context("Serializing tempo map")?
+                );
+            } else {
+                print!("{}", tempo);
+            }
+
+            if options.cancel.is_cancelled() {
+                RenderOutcome::Cancelled
+            } else {
+                RenderOutcome::Finished
+            }
+        }
+        Commands::Loudness(args) => {
+            let range: TimeRange = args.range.into();
+            let decode_range = DecodeRange {
+                start: range.start(),
+                end: range.end()?,
+            };
+
+            let report = LoudnessReport::analyze(
+                &DecodeSource::File(args.input),
+                decode_range,
+                options.cancel.clone(),
+            )
+            .await
+            .context("Measuring loudness")?;
+
+            if args.json {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&report).context("Serializing loudness report")?
+                );
+            } else {
+                print!("{}", report);
+            }
+
+            if options.cancel.is_cancelled() {
+                RenderOutcome::Cancelled
+            } else {
+                RenderOutcome::Finished
+            }
         }
+    };
+
+    if outcome == RenderOutcome::Cancelled {
+        warn!("Cancelled.");
+        return Ok(ExitCode::from(CANCELLED_EXIT_CODE));
     }
 
     info!("Done.");
 
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }
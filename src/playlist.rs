@@ -0,0 +1,202 @@
+//! This module lets a project play several input files back to back
+
+use crate::ffmpeg::decode::{probe, DecodeSource, DecodeTrack};
+use crate::ffmpeg::encode::Chapter;
+use crate::util::Timestamp;
+use crate::visualizer::title::{TitleCard, TitleCardConfig};
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// Input files rendered one after the other into a single output.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Playlist {
+    /// The input files, in the order they are played.
+    pub tracks: Vec<PathBuf>,
+    /// The silence between consecutive tracks. Tracks play gaplessly by default.
+    #[serde(default)]
+    pub gap: Timestamp,
+    /// Whether to mark where each track starts as a chapter of the output.
+    #[serde(default = "default_chapters")]
+    pub chapters: bool,
+    /// Shows the title of each track as it starts, if set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title_cards: Option<TitleCardConfig>,
+}
+
+fn default_chapters() -> bool {
+    true
+}
+
+/// A track of a playlist, as it is placed in the output.
+#[derive(Debug, Clone)]
+pub struct PlaylistTrack {
+    pub path: PathBuf,
+    pub start: Timestamp,
+    pub duration: Timestamp,
+    /// The title tag of the track, or its file name without an extension.
+    pub title: String,
+    pub artist: Option<String>,
+}
+
+impl Playlist {
+    /// Reads the duration and tags of every track and lays them out one after the other.
+    pub async fn probe(&self) -> anyhow::Result<Vec<PlaylistTrack>> {
+        if self.tracks.is_empty() {
+            bail!("A playlist needs at least one track");
+        }
+
+        let mut tracks = vec![];
+        let mut start = Timestamp::ZERO;
+        for path in &self.tracks {
+            let probe_path = path.clone();
+            let info = tokio::task::spawn_blocking(move || probe(&probe_path))
+                .await
+                .expect("spawn_blocking error")
+                .with_context(|| format!("Probing track {:?}", path))?;
+
+            let Some(duration) = info.duration else {
+                bail!("Track {:?} does not know its duration", path);
+            };
+            let title = info.title.unwrap_or_else(|| {
+                path.file_stem()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .into_owned()
+            });
+
+            tracks.push(PlaylistTrack {
+                path: path.clone(),
+                start,
+                duration,
+                title,
+                artist: info.artist,
+            });
+            start = start + duration + self.gap;
+        }
+
+        Ok(tracks)
+    }
+
+    /// Gets the tracks to decode, each one followed by the gap except for the last.
+    pub fn decode_source(&self, tracks: &[PlaylistTrack]) -> DecodeSource {
+        DecodeSource::Playlist(
+            tracks
+                .iter()
+                .enumerate()
+                .map(|(index, track)| DecodeTrack {
+                    path: track.path.clone(),
+                    length: if index + 1 < tracks.len() {
+                        track.duration + self.gap
+                    } else {
+                        track.duration
+                    },
+                })
+                .collect(),
+        )
+    }
+
+    /// Gets a chapter for every track, lasting until the next one starts.
+    pub fn chapters(&self, tracks: &[PlaylistTrack]) -> Vec<Chapter> {
+        if !self.chapters {
+            return vec![];
+        }
+
+        tracks
+            .iter()
+            .enumerate()
+            .map(|(index, track)| Chapter {
+                start: track.start,
+                end: tracks
+                    .get(index + 1)
+                    .map_or(track.start + track.duration, |next| next.start),
+                title: match &track.artist {
+                    Some(artist) => format!("{} - {}", artist, track.title),
+                    None => track.title.clone(),
+                },
+            })
+            .collect()
+    }
+
+    /// Gets a title card for every track.
+    pub fn title_cards(&self, tracks: &[PlaylistTrack]) -> Vec<TitleCard> {
+        tracks
+            .iter()
+            .map(|track| TitleCard {
+                start: track.start,
+                title: track.title.clone(),
+                subtitle: track.artist.clone(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::ffmpeg::decode::playlist_track_starts;
+
+    fn seconds(seconds: f64) -> Timestamp {
+        Timestamp::from_micros((seconds * 1e6) as u64)
+    }
+
+    #[test]
+    fn tracks_are_laid_out_with_gaps_between_them() {
+        let playlist = Playlist {
+            tracks: vec![],
+            gap: seconds(2.0),
+            chapters: true,
+            title_cards: None,
+        };
+        let track = |name: &str, start: f64, duration: f64, artist: Option<&str>| PlaylistTrack {
+            path: PathBuf::from(format!("{}.flac", name)),
+            start: seconds(start),
+            duration: seconds(duration),
+            title: name.to_string(),
+            artist: artist.map(str::to_string),
+        };
+        let tracks = [
+            track("Intro", 0.0, 60.0, Some("Band")),
+            track("Song", 62.0, 180.5, None),
+            track("Outro", 244.5, 30.0, Some("Band")),
+        ];
+
+        let DecodeSource::Playlist(decode_tracks) = playlist.decode_source(&tracks) else {
+            panic!("playlists decode as playlists");
+        };
+        let lengths: Vec<_> = decode_tracks.iter().map(|track| track.length).collect();
+        assert_eq!(lengths, [seconds(62.0), seconds(182.5), seconds(30.0)]);
+
+        // the decoded tracks start right where the playlist placed them
+        let starts: Vec<_> = tracks
+            .iter()
+            .map(|track| track.start.to_samples(48000))
+            .collect();
+        assert_eq!(playlist_track_starts(&decode_tracks, 48000), starts);
+
+        let chapters = playlist.chapters(&tracks);
+        let spans: Vec<_> = chapters
+            .iter()
+            .map(|chapter| (chapter.start, chapter.end))
+            .collect();
+        assert_eq!(
+            spans,
+            [
+                (seconds(0.0), seconds(62.0)),
+                (seconds(62.0), seconds(244.5)),
+                (seconds(244.5), seconds(274.5)),
+            ]
+        );
+        let titles: Vec<_> = chapters
+            .iter()
+            .map(|chapter| chapter.title.as_str())
+            .collect();
+        assert_eq!(titles, ["Band - Intro", "Song", "Band - Outro"]);
+
+        let playlist = Playlist {
+            chapters: false,
+            ..playlist
+        };
+        assert!(playlist.chapters(&tracks).is_empty());
+    }
+}
@@ -0,0 +1,641 @@
+//! This module renders single frames of a project to images
+
+use crate::analysis::Analyzer;
+use crate::args::PreviewCommandArgs;
+use crate::cancel::CancelFlag;
+use crate::canvas::Canvas;
+use crate::ffmpeg::decode::{DecodeRange, DecodeSource, DecoderHandle};
+use crate::ffmpeg::AudioFormat;
+use crate::project::{
+    FrameCursor, InputAnalyses, PreRoll, Project, RenderOptions, RenderOutcome, VisualizeError,
+    AUDIO_FRAMES_IN_FLIGHT,
+};
+use crate::recycle::simple::recycler;
+use crate::util::{Timestamp, RGB};
+use crate::visualizer::title::draw_text;
+use crate::visualizer::{Renderer, StatelessVisualizer};
+use anyhow::{bail, Context};
+use ffmpeg_next::frame;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::sync::Arc;
+
+/// The pre-roll used when neither the arguments nor the project have one.
+const DEFAULT_PRE_ROLL: Timestamp = Timestamp::from_micros(5_000_000);
+/// The space between the frames of a contact sheet, in pixels.
+const SHEET_GAP: usize = 8;
+/// How many font pixels of a contact sheet label fit into the height of a frame.
+const LABEL_SCALE_DIVISOR: u32 = 180;
+
+/// Renders the frames at the timestamps of the arguments and writes them to a PNG image.
+pub async fn run_preview(
+    project: Project,
+    args: &PreviewCommandArgs,
+    options: &RenderOptions,
+) -> anyhow::Result<RenderOutcome> {
+    if project.playlist.is_some() {
+        bail!("Previews seek into a single input, so the project can't have a playlist");
+    }
+    let Some(input) = &project.input else {
+        bail!(VisualizeError::NoInputFile)
+    };
+    project.check_program()?;
+
+    info!("Previewing: {:?}", input);
+
+    let source = DecodeSource::File(input.clone());
+    let pre_roll = args
+        .pre_roll
+        .or(project.range.pre_roll)
+        .unwrap_or(DEFAULT_PRE_ROLL);
+
+    // the analyses cover the same part of the input as a full render would, with its own pre-roll
+    let (decode_range, render_pre_roll) = project.decode_range()?;
+    let Some(analyses) = project
+        .analyze_input(&source, decode_range, &options.cancel)
+        .await?
+    else {
+        info!("Preview cancelled before rendering.");
+        return Ok(RenderOutcome::Cancelled);
+    };
+
+    let targets = args
+        .timestamps
+        .iter()
+        .map(|&time| render_frame_at(&project, decode_range, render_pre_roll, time))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let sample_rate = AudioFormat::default().sample_rate;
+    let pre_roll_frames = -project
+        .program
+        .frame_rate
+        .frame_at_sample(-pre_roll.to_samples(sample_rate), sample_rate);
+
+    let mut rendered = HashMap::new();
+    let mut stateless = None;
+    for run in preview_runs(&targets, pre_roll_frames) {
+        let frames = render_run(
+            &project,
+            &source,
+            &analyses,
+            decode_range,
+            &run,
+            &mut stateless,
+            &options.cancel,
+        )
+        .await
+        .context("Rendering preview frames")?;
+        let Some(frames) = frames else {
+            info!("Preview cancelled.");
+            return Ok(RenderOutcome::Cancelled);
+        };
+
+        rendered.extend(frames);
+    }
+
+    let mut frames = vec![];
+    for (&time, target) in args.timestamps.iter().zip(&targets) {
+        let Some(frame) = rendered.get(target) else {
+            bail!("{} is past the end of the input", time);
+        };
+        frames.push((time, frame.clone()));
+    }
+
+    let image = if frames.len() == 1 {
+        frames.pop().unwrap().1
+    } else {
+        let columns = match args.columns {
+            Some(columns) => columns as usize,
+            None => (1..)
+                .find(|columns| columns * columns >= frames.len())
+                .unwrap(),
+        };
+        contact_sheet(&frames, columns)
+    };
+
+    let output = args.output.clone();
+    tokio::task::spawn_blocking(move || write_png(&output, &image))
+        .await
+        .expect("spawn_blocking error")
+        .context("Writing preview image")?;
+
+    info!("Preview written to {:?}", &args.output);
+
+    Ok(RenderOutcome::Finished)
+}
+
+/// Finds the frame of the render that `time` falls into, numbered like the frames of a [FrameCursor] starting at the
+/// beginning of the render's decode range.
+fn render_frame_at(
+    project: &Project,
+    decode_range: DecodeRange,
+    pre_roll: PreRoll,
+    time: Timestamp,
+) -> anyhow::Result<i64> {
+    let sample_rate = AudioFormat::default().sample_rate;
+
+    if time < project.range.start() {
+        bail!(
+            "{} is before the start of the render at {}",
+            time,
+            project.range.start()
+        );
+    }
+    if let Some(end) = decode_range.end {
+        if time >= end {
+            bail!("{} is past the end of the render at {}", time, end);
+        }
+    }
+
+    // the render's first output frame starts exactly at the start of its range, after the pre-roll frames
+    let frame = project.program.frame_rate.frame_at_sample(
+        time.to_samples(sample_rate) - decode_range.start.to_samples(sample_rate),
+        sample_rate,
+    );
+    debug_assert!(frame >= pre_roll.frames);
+
+    Ok(frame)
+}
+
+/// A stretch of the render that is decoded in one go, rendering the frames of the preview along the way.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PreviewRun {
+    /// The frame decoding starts at.
+    first: i64,
+    /// The frames to keep, in order.
+    targets: Vec<i64>,
+}
+
+/// Groups the frames to render into runs, each starting `pre_roll_frames` before its first frame.
+///
+/// A frame that falls within the pre-roll of the frame before it continues that frame's run instead, since rendering
+/// on from there takes no longer than starting over.
+fn preview_runs(targets: &[i64], pre_roll_frames: i64) -> Vec<PreviewRun> {
+    let mut targets = targets.to_vec();
+    targets.sort_unstable();
+    targets.dedup();
+
+    let mut runs: Vec<PreviewRun> = vec![];
+    for target in targets {
+        match runs.last_mut() {
+            Some(run) if target - run.targets.last().unwrap() <= pre_roll_frames => {
+                run.targets.push(target)
+            }
+            _ => runs.push(PreviewRun {
+                // pre-roll can't reach back past the start of the render's decode range
+                first: (target - pre_roll_frames).max(0),
+                targets: vec![target],
+            }),
+        }
+    }
+
+    runs
+}
+
+/// Decodes the audio of a run and renders its frames, returning them by frame, or `None` if the render was cancelled.
+///
+/// A stateless visualizer is built once and then shared by every run through `stateless`.
+async fn render_run(
+    project: &Project,
+    source: &DecodeSource,
+    analyses: &InputAnalyses,
+    render_range: DecodeRange,
+    run: &PreviewRun,
+    stateless: &mut Option<Arc<dyn StatelessVisualizer>>,
+    cancel: &CancelFlag,
+) -> anyhow::Result<Option<Vec<(i64, Canvas<Vec<u8>>)>>> {
+    let program = &project.program;
+    let audio_format = AudioFormat::default();
+    let sample_rate = audio_format.sample_rate;
+    let frame_rate = program.frame_rate;
+
+    let grid_start = render_range.start.to_samples(sample_rate);
+    let origin = Timestamp::from_samples(
+        grid_start + frame_rate.frame_start_sample(run.first, sample_rate),
+        sample_rate,
+    );
+
+    info!(
+        "Rendering {} frames from {}",
+        run.targets.len(),
+        Timestamp::from_samples(
+            grid_start + frame_rate.frame_start_sample(run.targets[0], sample_rate),
+            sample_rate
+        )
+    );
+
+    let analyzer = project.new_analyzer(analyses.track.as_deref(), origin);
+    let renderer = match stateless {
+        Some(visualizer) => Renderer::Stateless(visualizer.clone()),
+        None => {
+            let extra = project.new_visualizer_extra(&analyzer, analyses)?;
+            project.new_renderer(extra, &[]).await?
+        }
+    };
+    if let Renderer::Stateless(visualizer) = &renderer {
+        *stateless = Some(visualizer.clone());
+    }
+
+    // decoding stops where the render's own decoding would
+    let last = *run.targets.last().unwrap();
+    let end = Timestamp::from_samples(
+        grid_start + frame_rate.frame_start_sample(last + 1, sample_rate) + analyzer.lookahead(),
+        sample_rate,
+    );
+    let decode_range = DecodeRange {
+        start: origin,
+        end: Some(
+            render_range
+                .end
+                .map_or(end, |render_end| end.min(render_end)),
+        ),
+    };
+
+    let (audio_producer, mut audio_consumer) = recycler(
+        (0..AUDIO_FRAMES_IN_FLIGHT)
+            .map(|_| frame::Audio::empty())
+            .collect(),
+    )
+    .await;
+
+    let decoder_handle = DecoderHandle::spawn(
+        source.clone(),
+        audio_format,
+        decode_range,
+        cancel.clone(),
+        audio_producer,
+    )
+    .await
+    .context("Spawning decoder handle")?;
+
+    let mut run_renderer = RunRenderer::new(
+        FrameCursor::new(frame_rate, sample_rate, run.first),
+        analyzer,
+        renderer,
+        run.targets.clone(),
+        Canvas::blank(program.width, program.height),
+    );
+    loop {
+        let mut audio_in = audio_consumer.recv_data().await;
+
+        match audio_in.as_deref() {
+            Some(audio) => {
+                let planes: Vec<&[f32]> = (0..audio.planes())
+                    .map(|plane| audio.plane::<f32>(plane))
+                    .collect();
+                run_renderer.push(Some(&planes)).await?;
+            }
+            None => run_renderer.push(None).await?,
+        }
+
+        let Some(audio_in) = &mut audio_in else {
+            break;
+        };
+
+        audio_in.send().await.ok();
+    }
+
+    decoder_handle
+        .join()
+        .await
+        .context("Waiting for decoder to finish")?;
+
+    if cancel.is_cancelled() {
+        return Ok(None);
+    }
+
+    Ok(Some(run_renderer.frames))
+}
+
+/// Renders the frames of a run as their audio comes in, keeping the ones the preview shows.
+struct RunRenderer {
+    cursor: FrameCursor,
+    analyzer: Analyzer,
+    renderer: Renderer,
+    targets: Vec<i64>,
+    canvas: Canvas<Vec<u8>>,
+    frames: Vec<(i64, Canvas<Vec<u8>>)>,
+}
+
+impl RunRenderer {
+    fn new(
+        cursor: FrameCursor,
+        analyzer: Analyzer,
+        renderer: Renderer,
+        targets: Vec<i64>,
+        canvas: Canvas<Vec<u8>>,
+    ) -> RunRenderer {
+        RunRenderer {
+            cursor,
+            analyzer,
+            renderer,
+            targets,
+            canvas,
+            frames: vec![],
+        }
+    }
+
+    /// Feeds the next audio of the run, or `None` once there is no more, and renders every frame it makes ready.
+    async fn push(&mut self, planes: Option<&[&[f32]]>) -> anyhow::Result<()> {
+        if let Some(planes) = planes {
+            self.analyzer.push_planes(planes);
+        }
+
+        while let Some(frame_index) = self.cursor.next_ready(&self.analyzer, planes.is_some()) {
+            let (frame_start, frame_end) = self.cursor.span(frame_index);
+            let frame = self.analyzer.analyze(frame_start, frame_end)?;
+
+            // stateless visualizers only need the analyzer to have caught up
+            let is_target = self.targets.binary_search(&frame_index).is_ok();
+            if is_target || matches!(self.renderer, Renderer::Stateful(_)) {
+                self.renderer
+                    .render_frame(frame, self.canvas.view_mut())
+                    .await
+                    .context("Rendering frame")?;
+            }
+            if is_target {
+                self.frames.push((frame_index, self.canvas.clone()));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Lays frames out in rows of `columns`, labelling each one with its timestamp.
+fn contact_sheet(frames: &[(Timestamp, Canvas<Vec<u8>>)], columns: usize) -> Canvas<Vec<u8>> {
+    let (_, first) = &frames[0];
+    let width = first.width() as usize;
+    let height = first.height() as usize;
+    let columns = columns.min(frames.len());
+    let rows = frames.len().div_ceil(columns);
+
+    let mut sheet = Canvas::blank(
+        (columns * (width + SHEET_GAP) + SHEET_GAP) as u32,
+        (rows * (height + SHEET_GAP) + SHEET_GAP) as u32,
+    );
+
+    let scale = (first.height() / LABEL_SCALE_DIVISOR).max(1) as usize;
+    let shadow = scale.div_ceil(3);
+    let margin = scale * 4;
+
+    for (index, (time, frame)) in frames.iter().enumerate() {
+        let left = SHEET_GAP + index % columns * (width + SHEET_GAP);
+        let top = SHEET_GAP + index / columns * (height + SHEET_GAP);
+
+        for (y, row) in frame.rows().enumerate() {
+            sheet.row_mut(top + y)[left * 4..(left + width) * 4].copy_from_slice(row);
+        }
+
+        let label = time.to_string();
+        let mut view = sheet.view_mut();
+        draw_text(
+            &mut view,
+            left + margin + shadow,
+            top + margin + shadow,
+            scale,
+            &label,
+            RGB::ZERO,
+            0.75,
+        );
+        draw_text(
+            &mut view,
+            left + margin,
+            top + margin,
+            scale,
+            &label,
+            RGB::new(1.0, 1.0, 1.0),
+            1.0,
+        );
+    }
+
+    sheet
+}
+
+fn write_png(path: &Path, canvas: &Canvas<Vec<u8>>) -> anyhow::Result<()> {
+    let file = File::create(path).context("Creating image file")?;
+
+    let mut encoder = png::Encoder::new(BufWriter::new(file), canvas.width(), canvas.height());
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    // canvases are ARGB, and their alpha is never used
+    let data: Vec<u8> = canvas
+        .rows()
+        .flat_map(|row| row.chunks_exact(4).flat_map(|pixel| &pixel[1..]))
+        .copied()
+        .collect();
+
+    let mut writer = encoder.write_header().context("Writing PNG header")?;
+    writer
+        .write_image_data(&data)
+        .context("Writing PNG image data")?;
+    writer.finish().context("Finishing PNG")
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::util::FrameRate;
+
+    /// A project whose range starts between two frames of the input, with a pre-roll before it.
+    fn project() -> Project {
+        serde_json::from_str(
+            r#"{
+                "input": "input.flac",
+                "range": {"start": "1.234", "duration": "5", "pre_roll": "0.4"},
+                "program": {
+                    "width": 64,
+                    "height": 36,
+                    "frame_rate": "29.97",
+                    "spectrum": {"release": 0.0},
+                    "visualizer": {"type": "Stereo"}
+                }
+            }"#,
+        )
+        .unwrap()
+    }
+
+    async fn new_renderer(project: &Project, origin: Timestamp) -> (Analyzer, Renderer) {
+        let analyzer = project.new_analyzer(None, origin);
+        let extra = project
+            .new_visualizer_extra(&analyzer, &InputAnalyses::default())
+            .unwrap();
+        let renderer = project.new_renderer(extra, &[]).await.unwrap();
+        (analyzer, renderer)
+    }
+
+    #[test]
+    fn previews_snap_to_the_frames_of_the_render() {
+        let project = project();
+        let (decode_range, pre_roll) = project.decode_range().unwrap();
+        let sample_rate = AudioFormat::default().sample_rate;
+        let frame_rate = project.program.frame_rate;
+        assert_eq!(frame_rate, FrameRate::new(30000, 1001).unwrap());
+        let grid_start = decode_range.start.to_samples(sample_rate);
+        let frame_start =
+            |frame: i64| grid_start + frame_rate.frame_start_sample(frame, sample_rate);
+
+        // the render's first frame starts exactly at the start of the range
+        let start = project.range.start();
+        assert_eq!(
+            render_frame_at(&project, decode_range, pre_roll, start).unwrap(),
+            pre_roll.frames
+        );
+        assert_eq!(frame_start(pre_roll.frames), start.to_samples(sample_rate));
+
+        let time = Timestamp::from_micros(3_500_000);
+        let frame = render_frame_at(&project, decode_range, pre_roll, time).unwrap();
+        let sample = time.to_samples(sample_rate);
+        assert!(frame_start(frame) <= sample && sample < frame_start(frame + 1));
+
+        // the range doesn't start on a frame of the input, so a grid from the start of the input lands elsewhere
+        let input_frame = frame_rate.frame_at_sample(sample, sample_rate);
+        assert_ne!(
+            frame_rate.frame_start_sample(input_frame, sample_rate),
+            frame_start(frame)
+        );
+
+        assert!(render_frame_at(
+            &project,
+            decode_range,
+            pre_roll,
+            Timestamp::from_micros(1_000_000)
+        )
+        .is_err());
+        assert!(render_frame_at(
+            &project,
+            decode_range,
+            pre_roll,
+            Timestamp::from_micros(6_234_000)
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn frames_within_the_pre_roll_of_each_other_share_a_run() {
+        assert_eq!(
+            preview_runs(&[40, 12, 100, 15, 12, 50], 10),
+            vec![
+                PreviewRun {
+                    first: 2,
+                    targets: vec![12, 15],
+                },
+                PreviewRun {
+                    first: 30,
+                    targets: vec![40, 50],
+                },
+                PreviewRun {
+                    first: 90,
+                    targets: vec![100],
+                },
+            ]
+        );
+        assert_eq!(preview_runs(&[5], 10)[0].first, 0);
+    }
+
+    #[tokio::test]
+    async fn previewed_frames_match_the_rendered_frames() {
+        let project = project();
+        let (decode_range, pre_roll) = project.decode_range().unwrap();
+        let sample_rate = AudioFormat::default().sample_rate;
+        let frame_rate = project.program.frame_rate;
+        let time = Timestamp::from_micros(2_500_000);
+        let target = render_frame_at(&project, decode_range, pre_roll, time).unwrap();
+
+        // audio whose stereo image keeps changing, from the start of the render's decode range
+        let length = sample_rate as usize * 3;
+        let left: Vec<f32> = (0..length).map(|i| (i as f32 * 0.05).sin() * 0.5).collect();
+        let right: Vec<f32> = (0..length)
+            .map(|i| (i as f32 * 0.031).sin() * i as f32 / length as f32)
+            .collect();
+
+        // the render steps through every frame from the start of its decode range
+        let (mut analyzer, mut renderer) = new_renderer(&project, decode_range.start).await;
+        let mut cursor = FrameCursor::new(frame_rate, sample_rate, 0);
+        let mut canvas = Canvas::blank(project.program.width, project.program.height);
+        let mut rendered = None;
+        for (left, right) in left.chunks(1024).zip(right.chunks(1024)) {
+            analyzer.push_planes(&[left, right]);
+            while let Some(frame_index) = cursor.next_ready(&analyzer, true) {
+                let (frame_start, frame_end) = cursor.span(frame_index);
+                let frame = analyzer.analyze(frame_start, frame_end).unwrap();
+                renderer
+                    .render_frame(frame, canvas.view_mut())
+                    .await
+                    .unwrap();
+                if frame_index == target {
+                    rendered = Some(canvas.clone());
+                }
+            }
+        }
+        let rendered = rendered.unwrap();
+
+        // the preview only decodes from a short pre-roll before the frame
+        let runs = preview_runs(&[target], 10);
+        let run = &runs[0];
+        assert!(run.first > 0);
+        let skipped = frame_rate.frame_start_sample(run.first, sample_rate) as usize;
+        let origin = Timestamp::from_samples(
+            decode_range.start.to_samples(sample_rate) + skipped as i64,
+            sample_rate,
+        );
+        let (analyzer, renderer) = new_renderer(&project, origin).await;
+        let mut run_renderer = RunRenderer::new(
+            FrameCursor::new(frame_rate, sample_rate, run.first),
+            analyzer,
+            renderer,
+            run.targets.clone(),
+            Canvas::blank(project.program.width, project.program.height),
+        );
+        for (left, right) in left[skipped..]
+            .chunks(1000)
+            .zip(right[skipped..].chunks(1000))
+        {
+            run_renderer.push(Some(&[left, right])).await.unwrap();
+        }
+        run_renderer.push(None).await.unwrap();
+
+        let (frame_index, previewed) = &run_renderer.frames[0];
+        assert_eq!(*frame_index, target);
+        assert!(previewed.rows().zip(rendered.rows()).all(|(a, b)| a == b));
+        assert!(previewed
+            .rows()
+            .any(|row| row.iter().any(|&value| value != 0)));
+    }
+
+    #[test]
+    fn contact_sheets_lay_frames_out_in_rows() {
+        let frames: Vec<_> = [0.25, 0.5, 0.75]
+            .into_iter()
+            .enumerate()
+            .map(|(index, level)| {
+                let mut frame = Canvas::blank(64, 36);
+                frame.fill(RGB::new(level, level, level));
+                (Timestamp::from_micros(index as u64 * 1_000_000), frame)
+            })
+            .collect();
+
+        let sheet = contact_sheet(&frames, 2);
+        assert_eq!(sheet.width() as usize, 2 * 64 + 3 * SHEET_GAP);
+        assert_eq!(sheet.height() as usize, 2 * 36 + 3 * SHEET_GAP);
+
+        // the bottom right corner of each frame is clear of its label
+        let color = |x: usize, y: usize| sheet.row(y)[x * 4 + 1..x * 4 + 4].to_vec();
+        let corner = |column: usize, row: usize| {
+            color(
+                SHEET_GAP + column * (64 + SHEET_GAP) + 63,
+                SHEET_GAP + row * (36 + SHEET_GAP) + 35,
+            )
+        };
+        assert_eq!(corner(0, 0), [64, 64, 64]);
+        assert_eq!(corner(1, 0), [128, 128, 128]);
+        assert_eq!(corner(0, 1), [192, 192, 192]);
+        assert_eq!(corner(1, 1), [0, 0, 0]);
+
+        // labels are drawn over the top left of each frame
+        assert!((0..36).any(|y| color(SHEET_GAP + 4, SHEET_GAP + y) == [255, 255, 255]));
+    }
+}
@@ -0,0 +1,183 @@
+//! This module contains the progress reporting for renders
+
+use crate::util::Timestamp;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+const HUMAN_INTERVAL: Duration = Duration::from_secs(2);
+const JSON_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How render progress is reported.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, ValueEnum)]
+pub enum ProgressFormat {
+    /// A human-readable progress line in the log.
+    #[default]
+    Human,
+    /// One JSON object per line on stdout, for other programs to consume.
+    Json,
+    /// No progress reports at all.
+    None,
+}
+
+/// A single progress report.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ProgressEvent {
+    Progress(ProgressStatus),
+    Finished(ProgressStatus),
+    Cancelled(ProgressStatus),
+    JobFinished(JobStatus),
+    JobCancelled(JobStatus),
+    JobFailed(JobStatus),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProgressStatus {
+    /// How much of the output has been rendered, in seconds.
+    pub position: f64,
+    /// The length of the whole output in seconds, if known.
+    pub duration: Option<f64>,
+    /// How much of the output has been rendered, from 0 to 100.
+    pub percent: Option<f64>,
+    /// The number of video frames rendered.
+    pub frames: i64,
+    /// How many seconds of output are rendered per second of wall-clock time.
+    pub speed: f64,
+    /// The estimated number of seconds until the render is done.
+    pub eta: Option<f64>,
+}
+
+/// How far a batch has come, reported whenever one of its renders ends.
+#[derive(Debug, Clone, Serialize)]
+pub struct JobStatus {
+    pub input: PathBuf,
+    pub output: PathBuf,
+    /// How many renders of the batch have ended, this one included.
+    pub done: usize,
+    /// The number of renders in the batch.
+    pub total: usize,
+}
+
+/// Turns the position of the render loop into progress reports at a steady rate.
+pub struct ProgressReporter {
+    format: ProgressFormat,
+    duration: Option<Timestamp>,
+    started: Instant,
+    last_report: Instant,
+}
+
+impl ProgressReporter {
+    pub fn new(format: ProgressFormat, duration: Option<Timestamp>) -> ProgressReporter {
+        let now = Instant::now();
+        ProgressReporter {
+            format,
+            duration,
+            started: now,
+            last_report: now,
+        }
+    }
+
+    /// Reports the current position if enough time has passed since the last report.
+    pub fn update(&mut self, position: Timestamp, frames: i64) {
+        let interval = match self.format {
+            ProgressFormat::Human => HUMAN_INTERVAL,
+            ProgressFormat::Json => JSON_INTERVAL,
+            ProgressFormat::None => return,
+        };
+
+        let now = Instant::now();
+        if now - self.last_report < interval {
+            return;
+        }
+        self.last_report = now;
+
+        self.report(ProgressEvent::Progress(self.status(position, frames)));
+    }
+
+    /// Reports the final position of a render.
+    pub fn finish(&mut self, position: Timestamp, frames: i64) {
+        self.report(ProgressEvent::Finished(self.status(position, frames)));
+    }
+
+    /// Reports where a cancelled render stopped.
+    pub fn cancel(&mut self, position: Timestamp, frames: i64) {
+        self.report(ProgressEvent::Cancelled(self.status(position, frames)));
+    }
+
+    fn status(&self, position: Timestamp, frames: i64) -> ProgressStatus {
+        let position = position.as_secs_f64();
+        let duration = self.duration.map(|duration| duration.as_secs_f64());
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let speed = if elapsed > 0.0 {
+            position / elapsed
+        } else {
+            0.0
+        };
+
+        ProgressStatus {
+            position,
+            duration,
+            percent: duration
+                .filter(|&duration| duration > 0.0)
+                .map(|duration| (position / duration * 100.0).min(100.0)),
+            frames,
+            speed,
+            eta: duration
+                .filter(|_| speed > 0.0)
+                .map(|duration| (duration - position).max(0.0) / speed),
+        }
+    }
+
+    fn report(&self, event: ProgressEvent) {
+        report(self.format, event);
+    }
+}
+
+/// Reports a single event in the given format.
+pub fn report(format: ProgressFormat, event: ProgressEvent) {
+    match format {
+        ProgressFormat::Human => match &event {
+            ProgressEvent::Progress(status) => info!("Progress: {}", format_status(status)),
+            ProgressEvent::Finished(status) => info!("Finished: {}", format_status(status)),
+            ProgressEvent::Cancelled(status) => info!("Cancelled: {}", format_status(status)),
+            ProgressEvent::JobFinished(job) => {
+                info!("Finished {:?} ({} of {})", job.output, job.done, job.total)
+            }
+            ProgressEvent::JobCancelled(job) => {
+                info!("Cancelled {:?} ({} of {})", job.output, job.done, job.total)
+            }
+            ProgressEvent::JobFailed(job) => {
+                info!("Failed {:?} ({} of {})", job.input, job.done, job.total)
+            }
+        },
+        ProgressFormat::Json => match serde_json::to_string(&event) {
+            Ok(line) => println!("{}", line),
+            Err(err) => warn!("Error serializing progress: {}", err),
+        },
+        ProgressFormat::None => {}
+    }
+}
+
+fn format_status(status: &ProgressStatus) -> String {
+    let timestamp = |seconds: f64| Timestamp::from_micros((seconds * 1000.0) as u64 * 1000);
+
+    let mut line = match (status.percent, status.duration) {
+        (Some(percent), Some(duration)) => format!(
+            "{:.1}% ({} / {})",
+            percent,
+            timestamp(status.position),
+            timestamp(duration)
+        ),
+        _ => timestamp(status.position).to_string(),
+    };
+
+    line += &format!(", {} frames, {:.2}x realtime", status.frames, status.speed);
+
+    if let Some(eta) = status.eta {
+        line += &format!(", ETA {}", timestamp(eta.round()));
+    }
+
+    line
+}
@@ -1,37 +1,147 @@
-use crate::ffmpeg::decode::DecoderHandle;
+use crate::analysis::chroma::{ChromaConfig, Key};
+use crate::analysis::gain::{GainConfig, GainMode};
+use crate::analysis::loudness::amplitude_to_db;
+use crate::analysis::rhythm::RhythmConfig;
+use crate::analysis::spectrum::SpectrumConfig;
+use crate::analysis::tempo::{TempoConfig, TempoMap};
+use crate::analysis::track::{TrackAnalysis, TrackAnalyzerArgs, TrackConfig};
+use crate::analysis::window::WindowFunction;
+use crate::analysis::{Analyzer, AnalyzerArgs};
+use crate::cancel::CancelFlag;
+use crate::canvas::Canvas;
+use crate::ffmpeg::decode::{DecodeRange, DecodeSource, DecoderHandle};
 use crate::ffmpeg::encode::{EncoderArgs, EncoderFrame, EncoderState};
 use crate::ffmpeg::AudioFormat;
+use crate::playlist::{Playlist, PlaylistTrack};
+use crate::progress::{ProgressFormat, ProgressReporter};
 use crate::recv_recycling;
-use crate::recycle::r#enum::enum_recycler;
+use crate::recycle::r#enum::{enum_recycler, EnumRecycleProducer};
 use crate::recycle::simple::recycler;
-use crate::util::MultiSlice;
+use crate::util::{FrameRate, Timestamp};
 use crate::visualizer::bars::BarsVisualizerInput;
 use crate::visualizer::cotton::CottonVisualizerInput;
 use crate::visualizer::credits::CreditsVisualizerInput;
-use crate::visualizer::{Visualizer, VisualizerInput, VisualizerInputExtra};
+use crate::visualizer::layers;
+use crate::visualizer::layers::Layer;
+use crate::visualizer::modulation::{Modulation, ModulationMatrix};
+use crate::visualizer::pipeline::FramePipeline;
+use crate::visualizer::stereo::StereoVisualizerInput;
+use crate::visualizer::timeline;
+use crate::visualizer::timeline::Scene;
+use crate::visualizer::title;
+use crate::visualizer::{Renderer, Visualizer, VisualizerInput, VisualizerInputExtra};
 use anyhow::{bail, Context};
 use ffmpeg_next::{format, frame};
-use realfft::RealFftPlanner;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
-use std::time::{Duration, Instant};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use thiserror::Error;
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncReadExt;
 
-const AUDIO_FRAMES_IN_FLIGHT: usize = 8;
+pub const AUDIO_FRAMES_IN_FLIGHT: usize = 8;
 const VIDEO_FRAMES_IN_FLIGHT: usize = 8;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Project {
     pub input: Option<PathBuf>,
     pub output: Option<PathBuf>,
+    #[serde(default)]
+    pub range: TimeRange,
     pub program: Program,
+    /// Scenes that replace the program's layers over the course of the track.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub timeline: Vec<Scene>,
+    /// Several input files to render back to back, instead of a single input.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub playlist: Option<Playlist>,
+}
+
+/// The part of the input track to render.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TimeRange {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start: Option<Timestamp>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<Timestamp>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end: Option<Timestamp>,
+    /// How much audio before the start to feed the visualizer without outputting it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pre_roll: Option<Timestamp>,
+}
+
+impl TimeRange {
+    pub fn start(&self) -> Timestamp {
+        self.start.unwrap_or_default()
+    }
+
+    /// Gets where rendering stops, or `None` to render until the end of the track.
+    pub fn end(&self) -> Result<Option<Timestamp>, VisualizeError> {
+        let end = match (self.duration, self.end) {
+            (Some(_), Some(_)) => return Err(VisualizeError::DurationAndEnd),
+            (Some(duration), None) => Some(self.start() + duration),
+            (None, end) => end,
+        };
+
+        match end {
+            Some(end) if end <= self.start() => Err(VisualizeError::EmptyTimeRange),
+            end => Ok(end),
+        }
+    }
+
+    /// Applies the values that are set in `overrides` on top of this range.
+    pub fn merge(self, overrides: TimeRange) -> TimeRange {
+        let (duration, end) = if overrides.duration.is_some() || overrides.end.is_some() {
+            (overrides.duration, overrides.end)
+        } else {
+            (self.duration, self.end)
+        };
+
+        TimeRange {
+            start: overrides.start.or(self.start),
+            duration,
+            end,
+            pre_roll: overrides.pre_roll.or(self.pre_roll),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Program {
     pub width: u32,
     pub height: u32,
-    pub visualizer: VisualizerEnum,
+    #[serde(default)]
+    pub frame_rate: FrameRate,
+    #[serde(default = "default_fft_size")]
+    pub fft_size: usize,
+    #[serde(default)]
+    pub window: WindowFunction,
+    #[serde(default)]
+    pub spectrum: SpectrumConfig,
+    #[serde(default)]
+    pub rhythm: RhythmConfig,
+    #[serde(default)]
+    pub tempo: TempoConfig,
+    #[serde(default)]
+    pub chroma: ChromaConfig,
+    #[serde(default)]
+    pub track: TrackConfig,
+    #[serde(default)]
+    pub gain: GainConfig,
+    /// The visualizers to composite into each frame, from the bottom up.
+    ///
+    /// Older projects have a single visualizer under `visualizer` instead, which is read as a stack of just that one.
+    #[serde(alias = "visualizer", deserialize_with = "layers::deserialize_layers")]
+    pub layers: Vec<Layer>,
+    /// Audio features driving the parameters of the visualizer.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub modulation: Vec<Modulation>,
+}
+
+fn default_fft_size() -> usize {
+    4096
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -40,26 +150,43 @@ pub enum VisualizerEnum {
     Bars(BarsVisualizerInput),
     Cotton(CottonVisualizerInput),
     Credits(CreditsVisualizerInput),
+    Stereo(StereoVisualizerInput),
 }
 
 impl VisualizerEnum {
-    async fn new_visualizer(
-        &self,
-        extra: VisualizerInputExtra,
-    ) -> anyhow::Result<Box<dyn Visualizer>> {
+    pub async fn new_visualizer(&self, extra: VisualizerInputExtra) -> anyhow::Result<Renderer> {
         match self {
             VisualizerEnum::Bars(input) => input.new_visualizer(extra).await,
             VisualizerEnum::Cotton(input) => input.new_visualizer(extra).await,
             VisualizerEnum::Credits(input) => input.new_visualizer(extra).await,
+            VisualizerEnum::Stereo(input) => input.new_visualizer(extra).await,
         }
     }
 }
 
 impl Project {
-    pub async fn visualize(&self) -> anyhow::Result<()> {
-        let Some(input_file) = self.input.as_ref() else {
+    /// Reads a project from a JSON project file.
+    pub async fn load(project_file: &Path) -> anyhow::Result<Project> {
+        info!("Loading project file from: {:?}", project_file);
+
+        let mut open_project_file = OpenOptions::new()
+            .read(true)
+            .open(project_file)
+            .await
+            .context("Opening project file")?;
+        let mut project_str = String::new();
+        open_project_file
+            .read_to_string(&mut project_str)
+            .await
+            .context("Reading project file")?;
+
+        serde_json::from_str(&project_str).context("Deserializing project")
+    }
+
+    pub async fn visualize(&self, options: &RenderOptions) -> anyhow::Result<RenderOutcome> {
+        if self.input.is_none() && self.playlist.is_none() {
             bail!(VisualizeError::NoInputFile)
-        };
+        }
         let Some(output_file) = self.output.as_ref() else {
             bail!(VisualizeError::NoOutputFile)
         };
@@ -68,32 +195,117 @@ impl Project {
 
         info!("Starting visualization...");
 
-        info!("Inputting from: {:?}", input_file);
+        let (source, tracks) = match (&self.input, &self.playlist) {
+            (Some(_), Some(_)) => bail!(VisualizeError::InputAndPlaylist),
+            (Some(input_file), None) => {
+                info!("Inputting from: {:?}", input_file);
+                (DecodeSource::File(input_file.clone()), vec![])
+            }
+            (None, Some(playlist)) => {
+                let range = &self.range;
+                if range.start.is_some()
+                    || range.duration.is_some()
+                    || range.end.is_some()
+                    || range.pre_roll.is_some()
+                {
+                    bail!(VisualizeError::PlaylistRange);
+                }
+
+                let tracks = playlist.probe().await.context("Reading playlist")?;
+                for (index, track) in tracks.iter().enumerate() {
+                    info!("Track {} at {}: {:?}", index + 1, track.start, track.path);
+                }
+
+                (playlist.decode_source(&tracks), tracks)
+            }
+            (None, None) => unreachable!(),
+        };
         info!("Outputting from: {:?}", output_file);
 
         let audio_format = AudioFormat::default();
+        let sample_rate = audio_format.sample_rate;
+        let frame_rate = program.frame_rate;
+
+        info!("Frame rate: {} fps", frame_rate);
 
-        let mut fft_planner = RealFftPlanner::<f32>::new();
-        let fft = fft_planner.plan_fft_forward(audio_format.frame_size.unwrap().get() as usize);
-        let mut fft_input = fft.make_input_vec();
-        let mut fft_output = MultiSlice::new(
-            (0..audio_format.channel_layout.channels())
-                .map(|_| fft.make_output_vec())
-                .collect(),
+        let start = self.range.start();
+        let end = self.range.end()?;
+        let (decode_range, pre_roll) = self.decode_range()?;
+        let (pre_roll_frames, pre_roll_samples) = (pre_roll.frames, pre_roll.samples);
+
+        if let Some(end) = end {
+            info!("Rendering from {} to {}", start, end);
+        } else if start > Timestamp::ZERO {
+            info!("Rendering from {}", start);
+        }
+        if pre_roll_frames > 0 {
+            info!(
+                "Pre-roll: {} frames ({})",
+                pre_roll_frames,
+                Timestamp::from_samples(pre_roll_samples, sample_rate)
+            );
+        }
+
+        self.check_program()?;
+
+        let Some(analyses) = self
+            .analyze_input(&source, decode_range, &options.cancel)
+            .await?
+        else {
+            info!("Visualization cancelled before rendering.");
+            return Ok(RenderOutcome::Cancelled);
+        };
+
+        info!("FFT size: {}", program.fft_size);
+        info!("Window function: {:?}", program.window);
+
+        let mut analyzer = self.new_analyzer(analyses.track.as_deref(), decode_range.start);
+        let extra = self.new_visualizer_extra(&analyzer, &analyses)?;
+
+        info!(
+            "Frequency resolution: {} Hz across {} bins",
+            extra.bin_frequency(1),
+            extra.fft_length
         );
-        let fft_output_len = fft_output[0].len();
-        let mut fft_scratch = fft.make_scratch_vec();
+        info!(
+            "Spectrum: {} bands from {} Hz to {} Hz",
+            extra.spectrum_bands,
+            analyzer.spectrum().band_frequency(0),
+            analyzer.spectrum().band_frequency(extra.spectrum_bands - 1)
+        );
+        match program.gain.mode {
+            GainMode::Off => {}
+            GainMode::Adaptive => {
+                info!("Auto-gain: adaptive, up to {} dB", program.gain.max_gain_db)
+            }
+            GainMode::Static => info!(
+                "Auto-gain: static, {:.1} dB",
+                amplitude_to_db(analyzer.auto_gain().static_gain() as f64)
+            ),
+        }
 
-        let (decoder_handle, encoder_handle) = {
-            let mut visualizer = program
-                .visualizer
-                .new_visualizer(VisualizerInputExtra {
-                    width: program.width,
-                    height: program.height,
-                    fft_length: fft_output_len,
-                })
-                .await
-                .context("Creating visualizer")?;
+        let (decoder_handle, encoder_handle, mut progress, position, frames, output_duration) = {
+            if !self.timeline.is_empty() {
+                info!("Timeline: {} scenes", self.timeline.len());
+            }
+            if !program.modulation.is_empty() {
+                info!("Modulation: {} routes", program.modulation.len());
+            }
+
+            let renderer = self.new_renderer(extra, &tracks).await?;
+
+            let mut renderer = match renderer {
+                Renderer::Stateful(visualizer) => ActiveRenderer::Stateful(visualizer),
+                Renderer::Stateless(visualizer) => {
+                    info!("Rendering up to {} frames at once", VIDEO_FRAMES_IN_FLIGHT);
+                    ActiveRenderer::Pipelined(FramePipeline::new(
+                        visualizer,
+                        program.width,
+                        program.height,
+                        VIDEO_FRAMES_IN_FLIGHT,
+                    ))
+                }
+            };
 
             let (audio_producer, mut audio_consumer) = recycler(
                 (0..AUDIO_FRAMES_IN_FLIGHT)
@@ -102,10 +314,15 @@ impl Project {
             )
             .await;
 
-            let decoder_handle =
-                DecoderHandle::spawn(input_file.clone(), audio_format, audio_producer)
-                    .await
-                    .context("Spawning decoder handle")?;
+            let decoder_handle = DecoderHandle::spawn(
+                source.clone(),
+                audio_format,
+                decode_range,
+                options.cancel.clone(),
+                audio_producer,
+            )
+            .await
+            .context("Spawning decoder handle")?;
 
             let encoder_state = EncoderState::new(
                 output_file.clone(),
@@ -113,6 +330,12 @@ impl Project {
                     in_audio_format: audio_format,
                     width: program.width,
                     height: program.height,
+                    frame_rate,
+                    skip_samples: pre_roll_samples as usize,
+                    chapters: self
+                        .playlist
+                        .as_ref()
+                        .map_or(vec![], |playlist| playlist.chapters(&tracks)),
                 },
             )
             .await
@@ -140,9 +363,22 @@ impl Project {
 
             let encoder_handle = encoder_state.spawn(video_consumer);
 
-            let mut last_msg = Instant::now();
-            while let Some(mut audio_in) = audio_consumer.recv_data().await {
-                {
+            let mut pre_roll_canvas = (pre_roll_frames > 0
+                && matches!(renderer, ActiveRenderer::Stateful(_)))
+            .then(|| Canvas::blank(program.width, program.height));
+
+            let output_duration = match (end, decoder_handle.duration()) {
+                (Some(end), Some(duration)) => Some(end.min(duration)),
+                (end, duration) => end.or(duration),
+            }
+            .map(|end| end.saturating_sub(start));
+
+            let mut progress = ProgressReporter::new(options.progress, output_duration);
+            let mut cursor = FrameCursor::new(frame_rate, sample_rate, 0);
+            loop {
+                let mut audio_in = audio_consumer.recv_data().await;
+
+                if let Some(audio_in) = audio_in.as_deref() {
                     recv_recycling!(video_producer, video_holder, EncoderFrame::Audio(audio_out));
 
                     if audio_out.samples() > audio_in.samples() {
@@ -154,59 +390,106 @@ impl Project {
                     audio_in.clone_into(audio_out);
 
                     video_holder.send().await.ok();
+
+                    analyzer.push(audio_in);
                 }
-                {
-                    // TODO: investigate parallelizing this
-                    for plane_index in 0..audio_in.planes() {
-                        let plane = audio_in.plane::<f32>(plane_index);
-
-                        if plane.len() == fft_input.len() {
-                            fft_input.copy_from_slice(plane);
-                        } else {
-                            fft_input.fill(0.0);
-                            fft_input[0..plane.len()].copy_from_slice(plane);
+
+                // a single audio frame may complete several video frames, or none at all
+                while let Some(frame_index) = cursor.next_ready(&analyzer, audio_in.is_some()) {
+                    let (frame_start, frame_end) = cursor.span(frame_index);
+                    let frame = analyzer.analyze(frame_start, frame_end)?;
+
+                    if frame_index < pre_roll_frames {
+                        // pre-roll frames are only rendered to build up visualizer state
+                        if let (ActiveRenderer::Stateful(visualizer), Some(canvas)) =
+                            (&mut renderer, &mut pre_roll_canvas)
+                        {
+                            visualizer
+                                .render_frame(frame, canvas.view_mut())
+                                .await
+                                .context("Rendering pre-roll frame")?;
                         }
 
-                        // planar audio should always have channels == planes
-                        fft.process_with_scratch(
-                            &mut fft_input,
-                            &mut fft_output[plane_index],
-                            &mut fft_scratch,
-                        )
-                        .context("Performing Fast Fourier Transform")?;
+                        continue;
                     }
 
-                    recv_recycling!(video_producer, video_holder, EncoderFrame::Video(video_out));
+                    let pts = frame_index - pre_roll_frames;
 
-                    let video_frame = video_out.data_mut(0);
+                    match &mut renderer {
+                        ActiveRenderer::Stateful(visualizer) => {
+                            recv_recycling!(
+                                video_producer,
+                                video_holder,
+                                EncoderFrame::Video(video_out)
+                            );
 
-                    visualizer
-                        .render_frame(&audio_in, &fft_output, video_frame)
-                        .await
-                        .context("Rendering frame")?;
+                            visualizer
+                                .render_frame(frame, Canvas::from_video(video_out))
+                                .await
+                                .context("Rendering frame")?;
 
-                    video_out.set_pts(audio_in.pts().map(|pts| pts * 24 / 48000));
+                            video_out.set_pts(Some(pts));
 
-                    video_holder.send().await.ok();
+                            video_holder.send().await.ok();
+                        }
+                        ActiveRenderer::Pipelined(pipeline) => {
+                            while !pipeline.can_submit() {
+                                let Some((pts, buffer)) = pipeline.next().await? else {
+                                    break;
+                                };
+                                send_video_frame(&mut video_producer, pts, &buffer).await?;
+                                pipeline.recycle(buffer);
+                            }
+
+                            pipeline.submit(pts, frame.clone());
+
+                            while let Some((pts, buffer)) = pipeline.try_next()? {
+                                send_video_frame(&mut video_producer, pts, &buffer).await?;
+                                pipeline.recycle(buffer);
+                            }
+                        }
+                    }
                 }
 
-                let now = Instant::now();
-                if now - last_msg > Duration::from_secs(2) {
-                    last_msg = now;
-                    info!(
-                        "Time: {}",
-                        humantime::Duration::from(Duration::from_millis(
-                            (audio_in.pts().unwrap() / 48) as u64
-                        ))
-                    );
-                }
+                let Some(audio_in) = &mut audio_in else {
+                    break;
+                };
+
+                let frames = (cursor.next_frame() - pre_roll_frames).max(0);
+                progress.update(
+                    Timestamp::from_samples(
+                        frame_rate.frame_start_sample(frames, sample_rate),
+                        sample_rate,
+                    ),
+                    frames,
+                );
 
                 audio_in.send().await.ok();
             }
 
+            if let ActiveRenderer::Pipelined(pipeline) = &mut renderer {
+                while let Some((pts, buffer)) = pipeline.next().await? {
+                    send_video_frame(&mut video_producer, pts, &buffer).await?;
+                    pipeline.recycle(buffer);
+                }
+            }
+
             info!("Closing files...");
 
-            (decoder_handle, encoder_handle)
+            let frames = (cursor.next_frame() - pre_roll_frames).max(0);
+            let position = Timestamp::from_samples(
+                frame_rate.frame_start_sample(frames, sample_rate),
+                sample_rate,
+            );
+
+            (
+                decoder_handle,
+                encoder_handle,
+                progress,
+                position,
+                frames,
+                output_duration,
+            )
         };
 
         encoder_handle
@@ -218,10 +501,378 @@ impl Project {
             .await
             .context("Waiting for decoder to finish")?;
 
+        // a cancel that arrives after the last frame was rendered doesn't cut anything short
+        let stopped_early = output_duration.is_none_or(|duration| position < duration);
+        if options.cancel.is_cancelled() && stopped_early {
+            progress.cancel(position, frames);
+            info!("Visualization cancelled, output ends at {}.", position);
+            return Ok(RenderOutcome::Cancelled);
+        }
+
+        progress.finish(position, frames);
+
         info!("Visualization complete.");
 
+        Ok(RenderOutcome::Finished)
+    }
+
+    /// Gets the part of the input a render decodes, which reaches back from the start of the range by the pre-roll.
+    pub fn decode_range(&self) -> Result<(DecodeRange, PreRoll), VisualizeError> {
+        let sample_rate = AudioFormat::default().sample_rate;
+        let frame_rate = self.program.frame_rate;
+        let start_sample = self.range.start().to_samples(sample_rate);
+
+        // pre-roll is rounded up to whole frames, but can't reach back past the beginning of the track
+        let pre_roll_samples = self
+            .range
+            .pre_roll
+            .unwrap_or_default()
+            .to_samples(sample_rate);
+        let frames = (-frame_rate.frame_at_sample(-pre_roll_samples, sample_rate))
+            .min(frame_rate.frame_at_sample(start_sample, sample_rate));
+        let samples = frame_rate.frame_start_sample(frames, sample_rate);
+
+        let decode_range = DecodeRange {
+            start: Timestamp::from_samples(start_sample - samples, sample_rate),
+            end: self.range.end()?,
+        };
+
+        Ok((decode_range, PreRoll { frames, samples }))
+    }
+
+    /// Checks the parts of the program that can't be checked while it is deserialized.
+    pub fn check_program(&self) -> Result<(), VisualizeError> {
+        if self.program.fft_size < 2 {
+            return Err(VisualizeError::InvalidFftSize(self.program.fft_size));
+        }
+
+        if self.program.spectrum.bands == 0 {
+            return Err(VisualizeError::InvalidBandCount(
+                self.program.spectrum.bands,
+            ));
+        }
+
         Ok(())
     }
+
+    /// Runs the analyses the program asks for ahead of rendering, returning `None` if they were cancelled.
+    pub async fn analyze_input(
+        &self,
+        source: &DecodeSource,
+        decode_range: DecodeRange,
+        cancel: &CancelFlag,
+    ) -> anyhow::Result<Option<InputAnalyses>> {
+        let program = &self.program;
+
+        // the static gain comes from the peak of the whole track
+        let track = if program.track.enabled || program.gain.mode == GainMode::Static {
+            info!("Analyzing track...");
+
+            let track = TrackAnalysis::analyze(
+                source,
+                decode_range,
+                cancel.clone(),
+                &TrackAnalyzerArgs {
+                    fft_size: program.fft_size,
+                    window: program.window,
+                    spectrum: program.spectrum.clone(),
+                    rhythm: program.rhythm.clone(),
+                    tempo: program.tempo.clone(),
+                    chroma: program.chroma.clone(),
+                },
+            )
+            .await
+            .context("Analyzing track")?;
+
+            if cancel.is_cancelled() {
+                return Ok(None);
+            }
+
+            info!("Track duration: {}", track.duration);
+            match track.loudness.integrated {
+                Some(integrated) => info!(
+                    "Integrated loudness: {:.1} LUFS, range {:.1} LU",
+                    integrated,
+                    track.loudness.range.unwrap_or_default()
+                ),
+                None => warn!("Track is silent"),
+            }
+            if let Some(drop) = track.drop {
+                info!("Drop at {}", drop);
+            }
+            log_tempo(&track.tempo);
+            if program.chroma.estimate_key {
+                log_key(track.key);
+            }
+
+            Some(Arc::new(track))
+        } else {
+            None
+        };
+
+        let tempo = if let Some(track) = &track {
+            Some(track.tempo.clone())
+        } else if program.tempo.enabled {
+            info!("Analyzing tempo...");
+
+            let tempo = TempoMap::analyze(
+                source,
+                decode_range,
+                cancel.clone(),
+                &program.tempo,
+                &program.rhythm,
+            )
+            .await
+            .context("Analyzing tempo")?;
+
+            if cancel.is_cancelled() {
+                return Ok(None);
+            }
+
+            log_tempo(&tempo);
+
+            Some(Arc::new(tempo))
+        } else {
+            None
+        };
+
+        let key = if let Some(track) = &track {
+            track.key
+        } else if program.chroma.estimate_key {
+            info!("Estimating key...");
+
+            let key = Key::analyze(source, decode_range, cancel.clone(), &program.chroma)
+                .await
+                .context("Estimating key")?;
+
+            if cancel.is_cancelled() {
+                return Ok(None);
+            }
+
+            log_key(key);
+
+            key
+        } else {
+            None
+        };
+
+        Ok(Some(InputAnalyses { track, tempo, key }))
+    }
+
+    /// Creates the analyzer for audio decoded from `origin` on.
+    pub fn new_analyzer(&self, track: Option<&TrackAnalysis>, origin: Timestamp) -> Analyzer {
+        let program = &self.program;
+        let audio_format = AudioFormat::default();
+
+        Analyzer::new(AnalyzerArgs {
+            channels: audio_format.channel_layout.channels() as usize,
+            sample_rate: audio_format.sample_rate,
+            frame_rate: program.frame_rate,
+            fft_size: program.fft_size,
+            window: program.window,
+            spectrum: program.spectrum.clone(),
+            rhythm: program.rhythm.clone(),
+            chroma: program.chroma.clone(),
+            gain: program.gain.clone(),
+            track_peak: track.map(|track| track.peak_spectrum.iter().copied().fold(0.0, f32::max)),
+            origin,
+            history: program
+                .frame_rate
+                .max_frame_samples(audio_format.sample_rate)
+                + audio_format.frame_size.unwrap().get() as usize,
+        })
+    }
+
+    /// Gets what the visualizers need to know about the input and the analyzer, setting up the modulations.
+    pub fn new_visualizer_extra(
+        &self,
+        analyzer: &Analyzer,
+        analyses: &InputAnalyses,
+    ) -> anyhow::Result<VisualizerInputExtra> {
+        let program = &self.program;
+        let audio_format = AudioFormat::default();
+
+        let modulation = ModulationMatrix::new(&program.modulation, analyzer.spectrum())
+            .context("Setting up modulation")?;
+
+        Ok(VisualizerInputExtra {
+            width: program.width,
+            height: program.height,
+            channels: audio_format.channel_layout.channels() as usize,
+            fft_length: analyzer.bins(),
+            fft_size: analyzer.fft_size(),
+            sample_rate: audio_format.sample_rate,
+            spectrum_bands: analyzer.spectrum().band_count(),
+            tempo: analyses.tempo.clone(),
+            key: analyses.key,
+            track: analyses.track.clone(),
+            modulation: Arc::new(modulation),
+            param_prefix: String::new(),
+        })
+    }
+
+    /// Creates the visualizer of the project, or of its timeline, with the title cards of the playlist on top.
+    pub async fn new_renderer(
+        &self,
+        extra: VisualizerInputExtra,
+        tracks: &[PlaylistTrack],
+    ) -> anyhow::Result<Renderer> {
+        let modulation = extra.modulation.clone();
+
+        let renderer = if self.timeline.is_empty() {
+            layers::new_visualizer(&self.program.layers, extra).await
+        } else {
+            timeline::new_visualizer(&self.timeline, extra).await
+        };
+
+        let renderer = renderer.context("Creating visualizer")?;
+
+        if let Some(target) = modulation.unbound_targets().first() {
+            bail!(
+                "Modulation target {} is not a parameter of the visualizer",
+                target
+            );
+        }
+
+        let renderer = match &self.playlist {
+            Some(
+                playlist @ Playlist {
+                    title_cards: Some(config),
+                    ..
+                },
+            ) => title::with_title_cards(renderer, playlist.title_cards(tracks), config.clone()),
+            _ => renderer,
+        };
+
+        Ok(renderer)
+    }
+}
+
+/// The audio decoded before the start of a render, only to build up the visualizer.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct PreRoll {
+    pub frames: i64,
+    /// The length of the pre-roll frames, in samples.
+    pub samples: i64,
+}
+
+/// Steps through the video frames of a render in order, as far as the analyzer has the audio for them.
+///
+/// Frames are numbered from the start of the render's decode range, pre-roll included, so audio decoded from a later
+/// frame on is split into frames exactly the way the render splits it.
+#[derive(Debug, Clone)]
+pub struct FrameCursor {
+    frame_rate: FrameRate,
+    sample_rate: u32,
+    /// The frame the decoded audio starts at.
+    first: i64,
+    next: i64,
+}
+
+impl FrameCursor {
+    pub fn new(frame_rate: FrameRate, sample_rate: u32, first: i64) -> FrameCursor {
+        FrameCursor {
+            frame_rate,
+            sample_rate,
+            first,
+            next: first,
+        }
+    }
+
+    /// The frame after the last one that was ready.
+    pub fn next_frame(&self) -> i64 {
+        self.next
+    }
+
+    /// Gets the samples of a frame, counted from the start of the decoded audio.
+    pub fn span(&self, frame: i64) -> (i64, i64) {
+        let origin = self.frame_rate.frame_start_sample(self.first, self.sample_rate);
+        (
+            self.frame_rate.frame_start_sample(frame, self.sample_rate) - origin,
+            self.frame_rate.frame_start_sample(frame + 1, self.sample_rate) - origin,
+        )
+    }
+
+    /// Gets the next frame if the analyzer has all the audio it needs for it.
+    ///
+    /// Once the decoder has nothing more to send, every frame starting before the end of the audio is ready.
+    pub fn next_ready(&mut self, analyzer: &Analyzer, more_audio: bool) -> Option<i64> {
+        let (frame_start, frame_end) = self.span(self.next);
+
+        let ready = if more_audio {
+            analyzer.end() >= frame_end.max(frame_start + analyzer.lookahead())
+        } else {
+            frame_start < analyzer.end()
+        };
+        if !ready {
+            return None;
+        }
+
+        self.next += 1;
+        Some(self.next - 1)
+    }
+}
+
+/// What is known about the input before rendering starts.
+#[derive(Debug, Clone, Default)]
+pub struct InputAnalyses {
+    pub track: Option<Arc<TrackAnalysis>>,
+    pub tempo: Option<Arc<TempoMap>>,
+    pub key: Option<Key>,
+}
+
+/// Settings that affect how a render runs but not what it produces.
+#[derive(Debug, Clone, Default)]
+pub struct RenderOptions {
+    pub progress: ProgressFormat,
+    /// Set to stop the render early. The output is still finalized, ending wherever the render stopped.
+    pub cancel: CancelFlag,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RenderOutcome {
+    Finished,
+    Cancelled,
+}
+
+/// How the frames of the current visualization are being rendered.
+enum ActiveRenderer {
+    Stateful(Box<dyn Visualizer>),
+    Pipelined(FramePipeline),
+}
+
+fn log_tempo(tempo: &TempoMap) {
+    match tempo.bpm() {
+        Some(bpm) => info!(
+            "Tempo: {:.1} BPM, {} tempo changes",
+            bpm,
+            tempo.segments.len() - 1
+        ),
+        None => warn!("No tempo found"),
+    }
+}
+
+fn log_key(key: Option<Key>) {
+    match key {
+        Some(key) => info!("Key: {} (confidence {:.2})", key, key.confidence),
+        None => warn!("No key found"),
+    }
+}
+
+/// Copies a frame rendered off of the main loop into a recycled encoder frame and sends it.
+async fn send_video_frame(
+    video_producer: &mut EnumRecycleProducer<EncoderFrame>,
+    pts: i64,
+    canvas: &Canvas<Vec<u8>>,
+) -> anyhow::Result<()> {
+    recv_recycling!(video_producer, video_holder, EncoderFrame::Video(video_out));
+
+    Canvas::from_video(video_out).copy_from(canvas);
+    video_out.set_pts(Some(pts));
+
+    video_holder.send().await.ok();
+
+    Ok(())
 }
 
 #[derive(Debug, Error)]
@@ -231,4 +882,22 @@ pub enum VisualizeError {
 
     #[error("No output file specified")]
     NoOutputFile,
+
+    #[error("Invalid FFT size: {0}")]
+    InvalidFftSize(usize),
+
+    #[error("Invalid spectrum band count: {0}")]
+    InvalidBandCount(usize),
+
+    #[error("Only one of duration and end may be specified")]
+    DurationAndEnd,
+
+    #[error("The time range to render is empty")]
+    EmptyTimeRange,
+
+    #[error("A project can only have one of an input file and a playlist")]
+    InputAndPlaylist,
+
+    #[error("Playlists are always rendered whole, without a time range")]
+    PlaylistRange,
 }
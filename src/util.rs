@@ -1,7 +1,13 @@
 #![allow(dead_code)]
 
+use ffmpeg_next::Rational;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
 use std::ops::{Add, Index, IndexMut};
+use std::str::FromStr;
+use thiserror::Error;
 
+#[derive(Debug, Clone)]
 pub struct MultiSlice<T> {
     backing: Vec<Vec<T>>,
 }
@@ -19,9 +25,33 @@ impl<T> MultiSlice<T> {
         self.backing.get(index).map(|vec| &vec[..])
     }
 
+    pub fn iter(&self) -> impl Iterator<Item = &[T]> {
+        self.backing.iter().map(|vec| &vec[..])
+    }
+
     pub fn get_mut(&mut self, index: usize) -> Option<&mut [T]> {
         self.backing.get_mut(index).map(|vec| &mut vec[..])
     }
+
+    pub fn vec_mut(&mut self, index: usize) -> &mut Vec<T> {
+        &mut self.backing[index]
+    }
+
+    pub fn vecs_mut(&mut self) -> &mut [Vec<T>] {
+        &mut self.backing
+    }
+}
+
+impl<T: Clone> MultiSlice<T> {
+    /// Copies the given slices into a new multi-slice, in the given order.
+    pub fn select(&self, indices: &[usize]) -> MultiSlice<T> {
+        MultiSlice::new(
+            indices
+                .iter()
+                .map(|&index| self.backing[index].clone())
+                .collect(),
+        )
+    }
 }
 
 impl<T> Index<usize> for MultiSlice<T> {
@@ -42,10 +72,6 @@ pub fn scale_byte(a: u8, b: u8) -> u8 {
     ((a as u16 * b as u16) >> 8) as u8
 }
 
-pub fn pixel(x: usize, y: usize, width: u32) -> usize {
-    (y * (width as usize) + x) * 4
-}
-
 #[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
 pub struct RGB {
     pub r: f32,
@@ -64,22 +90,23 @@ impl RGB {
         RGB { r, g, b }
     }
 
-    pub fn from_pixel(frame: &[u8], x: usize, y: usize, width: u32) -> RGB {
-        let index = pixel(x, y, width);
+    /// Reads a color from the 4 bytes of an ARGB pixel.
+    pub fn from_argb(pixel: &[u8]) -> RGB {
         RGB {
-            r: (frame[index + 1] as f32 + 0.5) / 256.0,
-            g: (frame[index + 2] as f32 + 0.5) / 256.0,
-            b: (frame[index + 3] as f32 + 0.5) / 256.0,
+            r: (pixel[1] as f32 + 0.5) / 256.0,
+            g: (pixel[2] as f32 + 0.5) / 256.0,
+            b: (pixel[3] as f32 + 0.5) / 256.0,
         }
     }
 
-    pub fn write_pixel(&self, frame: &mut [u8], x: usize, y: usize, width: u32) {
-        let index = pixel(x, y, width);
-
-        frame[index] = 0xFF;
-        frame[index + 1] = (self.r * 256.0) as u8;
-        frame[index + 2] = (self.g * 256.0) as u8;
-        frame[index + 3] = (self.b * 256.0) as u8;
+    /// Converts this color into the bytes of an opaque ARGB pixel.
+    pub fn to_argb(self) -> [u8; 4] {
+        [
+            0xFF,
+            (self.r * 256.0) as u8,
+            (self.g * 256.0) as u8,
+            (self.b * 256.0) as u8,
+        ]
     }
 
     pub fn scale(mut self, scale: f32) -> RGB {
@@ -108,3 +135,395 @@ impl Add for RGB {
         }
     }
 }
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct FrameRate {
+    numerator: u32,
+    denominator: u32,
+}
+
+impl FrameRate {
+    pub const DEFAULT: FrameRate = FrameRate {
+        numerator: 24,
+        denominator: 1,
+    };
+
+    pub fn new(numerator: u32, denominator: u32) -> Result<FrameRate, FrameRateError> {
+        if numerator == 0 || denominator == 0 {
+            return Err(FrameRateError::Zero);
+        }
+
+        let divisor = gcd(numerator, denominator);
+        Ok(FrameRate {
+            numerator: numerator / divisor,
+            denominator: denominator / divisor,
+        })
+    }
+
+    pub fn numerator(&self) -> u32 {
+        self.numerator
+    }
+
+    pub fn denominator(&self) -> u32 {
+        self.denominator
+    }
+
+    pub fn as_f64(&self) -> f64 {
+        self.numerator as f64 / self.denominator as f64
+    }
+
+    /// The time base of a video stream with one tick per frame.
+    pub fn time_base(&self) -> Rational {
+        Rational::new(self.denominator as i32, self.numerator as i32)
+    }
+
+    /// Gets the index of the first audio sample belonging to the given video frame.
+    ///
+    /// This is computed from the frame index directly instead of accumulating per-frame sample counts, so fractional
+    /// rates like 30000/1001 never drift away from the audio.
+    pub fn frame_start_sample(&self, frame: i64, sample_rate: u32) -> i64 {
+        (frame as i128 * sample_rate as i128 * self.denominator as i128)
+            .div_euclid(self.numerator as i128) as i64
+    }
+
+    /// Gets the index of the video frame that the given audio sample belongs to.
+    ///
+    /// This is the last frame whose [FrameRate::frame_start_sample] isn't past the sample, so frame starts rounded down
+    /// to whole samples still belong to their own frame.
+    pub fn frame_at_sample(&self, sample: i64, sample_rate: u32) -> i64 {
+        ((sample as i128 + 1) * self.numerator as i128 - 1)
+            .div_euclid(sample_rate as i128 * self.denominator as i128) as i64
+    }
+
+    /// Gets the largest number of audio samples any single video frame can cover.
+    pub fn max_frame_samples(&self, sample_rate: u32) -> usize {
+        let total = sample_rate as u64 * self.denominator as u64;
+        total.div_ceil(self.numerator as u64) as usize
+    }
+}
+
+impl Default for FrameRate {
+    fn default() -> Self {
+        FrameRate::DEFAULT
+    }
+}
+
+impl From<FrameRate> for Rational {
+    fn from(value: FrameRate) -> Self {
+        Rational::new(value.numerator as i32, value.denominator as i32)
+    }
+}
+
+impl Display for FrameRate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.denominator == 1 {
+            write!(f, "{}", self.numerator)
+        } else {
+            write!(f, "{}/{}", self.numerator, self.denominator)
+        }
+    }
+}
+
+impl FromStr for FrameRate {
+    type Err = FrameRateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+
+        if let Some((numerator, denominator)) = s.split_once('/') {
+            return FrameRate::new(numerator.trim().parse()?, denominator.trim().parse()?);
+        }
+
+        let Some((whole, fraction)) = s.split_once('.') else {
+            return FrameRate::new(s.parse()?, 1);
+        };
+
+        let value: f64 = s
+            .parse()
+            .map_err(|_| FrameRateError::Invalid(s.to_string()))?;
+
+        // Rates like 23.98, 23.976, 29.97 and 59.94 are shorthand for the NTSC n*1000/1001 rates, rounded to as many
+        // decimals as they are written with. Whole rates written with a decimal point, like 10.0, are never NTSC rates.
+        let rounded = value.round();
+        let ntsc = rounded * 1000.0 / 1001.0;
+        let precision = 0.5 * 10f64.powi(-(fraction.len() as i32));
+        let is_whole = fraction.chars().all(|c| c == '0');
+        if !is_whole && rounded >= 1.0 && (value - ntsc).abs() <= precision + 1e-9 {
+            return FrameRate::new(rounded as u32 * 1000, 1001);
+        }
+
+        let denominator = 10u32
+            .checked_pow(fraction.len() as u32)
+            .ok_or_else(|| FrameRateError::Invalid(s.to_string()))?;
+        let numerator = format!("{}{}", whole, fraction).parse()?;
+
+        FrameRate::new(numerator, denominator)
+    }
+}
+
+impl TryFrom<String> for FrameRate {
+    type Error = FrameRateError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<FrameRate> for String {
+    fn from(value: FrameRate) -> Self {
+        value.to_string()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum FrameRateError {
+    #[error("Frame rate must not be zero")]
+    Zero,
+
+    #[error("Invalid frame rate: {0}")]
+    Invalid(String),
+
+    #[error("Invalid frame rate: {0}")]
+    ParseInt(#[from] std::num::ParseIntError),
+}
+
+/// A position in, or length of, a track with microsecond precision.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Default, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Timestamp {
+    micros: u64,
+}
+
+impl Timestamp {
+    pub const ZERO: Timestamp = Timestamp { micros: 0 };
+
+    pub const fn from_micros(micros: u64) -> Timestamp {
+        Timestamp { micros }
+    }
+
+    pub fn as_micros(&self) -> u64 {
+        self.micros
+    }
+
+    pub fn as_secs_f64(&self) -> f64 {
+        self.micros as f64 / 1_000_000.0
+    }
+
+    /// Gets the index of the audio sample nearest to this timestamp.
+    pub fn to_samples(self, sample_rate: u32) -> i64 {
+        ((self.micros as u128 * sample_rate as u128 + 500_000) / 1_000_000) as i64
+    }
+
+    /// Gets the timestamp of an audio sample, rounded to the nearest microsecond.
+    pub fn from_samples(samples: i64, sample_rate: u32) -> Timestamp {
+        let samples = samples.max(0) as u128;
+        Timestamp {
+            micros: ((samples * 1_000_000 + sample_rate as u128 / 2) / sample_rate as u128) as u64,
+        }
+    }
+
+    pub fn saturating_sub(self, rhs: Timestamp) -> Timestamp {
+        Timestamp {
+            micros: self.micros.saturating_sub(rhs.micros),
+        }
+    }
+}
+
+impl Add for Timestamp {
+    type Output = Timestamp;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        Timestamp {
+            micros: self.micros + rhs.micros,
+        }
+    }
+}
+
+impl From<Timestamp> for std::time::Duration {
+    fn from(value: Timestamp) -> Self {
+        std::time::Duration::from_micros(value.micros)
+    }
+}
+
+impl Display for Timestamp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let seconds = self.micros / 1_000_000;
+        let micros = self.micros % 1_000_000;
+
+        if seconds >= 3600 {
+            write!(
+                f,
+                "{}:{:02}:{:02}",
+                seconds / 3600,
+                seconds / 60 % 60,
+                seconds % 60
+            )?;
+        } else {
+            write!(f, "{}:{:02}", seconds / 60, seconds % 60)?;
+        }
+
+        if micros > 0 {
+            write!(f, "{}", format!(".{:06}", micros).trim_end_matches('0'))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Timestamp {
+    type Err = TimestampError;
+
+    /// Parses `[[hours:]minutes:]seconds[.fraction]`, or a duration like `1m 30s`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let invalid = || TimestampError::Invalid(s.to_string());
+
+        if s.ends_with(|c: char| c.is_ascii_alphabetic()) {
+            let duration = humantime::parse_duration(s).map_err(|_| invalid())?;
+            return Ok(Timestamp {
+                micros: duration.as_micros() as u64,
+            });
+        }
+
+        let mut parts = s.rsplit(':');
+        let seconds = parts.next().ok_or_else(invalid)?;
+        let (whole, fraction) = seconds.split_once('.').unwrap_or((seconds, ""));
+        if fraction.len() > 6 || !fraction.chars().all(|c| c.is_ascii_digit()) {
+            return Err(invalid());
+        }
+
+        let mut micros = whole.parse::<u64>().map_err(|_| invalid())? * 1_000_000;
+        if !fraction.is_empty() {
+            micros += format!("{:0<6}", fraction)
+                .parse::<u64>()
+                .map_err(|_| invalid())?;
+        }
+
+        for (unit, part) in [60_000_000u64, 3_600_000_000]
+            .into_iter()
+            .zip(parts.by_ref())
+        {
+            micros += part.parse::<u64>().map_err(|_| invalid())? * unit;
+        }
+
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+
+        Ok(Timestamp { micros })
+    }
+}
+
+impl TryFrom<String> for Timestamp {
+    type Error = TimestampError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Timestamp> for String {
+    fn from(value: Timestamp) -> Self {
+        value.to_string()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum TimestampError {
+    #[error("Invalid timestamp: {0}")]
+    Invalid(String),
+}
+
+fn gcd(mut a: u32, mut b: u32) -> u32 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+
+    a
+}
+
+#[cfg(test)]
+mod testing {
+    use crate::util::{FrameRate, Timestamp};
+
+    #[test]
+    fn parse_frame_rates() {
+        assert_eq!(
+            "24".parse::<FrameRate>().unwrap(),
+            FrameRate::new(24, 1).unwrap()
+        );
+        assert_eq!(
+            "60/2".parse::<FrameRate>().unwrap(),
+            FrameRate::new(30, 1).unwrap()
+        );
+        assert_eq!(
+            "29.97".parse::<FrameRate>().unwrap(),
+            FrameRate::new(30000, 1001).unwrap()
+        );
+        assert_eq!(
+            "23.976".parse::<FrameRate>().unwrap(),
+            FrameRate::new(24000, 1001).unwrap()
+        );
+        assert_eq!(
+            "23.98".parse::<FrameRate>().unwrap(),
+            FrameRate::new(24000, 1001).unwrap()
+        );
+        assert_eq!(
+            "59.94".parse::<FrameRate>().unwrap(),
+            FrameRate::new(60000, 1001).unwrap()
+        );
+        assert_eq!(
+            "119.5".parse::<FrameRate>().unwrap(),
+            FrameRate::new(239, 2).unwrap()
+        );
+        assert_eq!(
+            "12.5".parse::<FrameRate>().unwrap(),
+            FrameRate::new(25, 2).unwrap()
+        );
+        for (s, rate) in [("5.0", 5), ("10.0", 10), ("24.0", 24)] {
+            assert_eq!(
+                s.parse::<FrameRate>().unwrap(),
+                FrameRate::new(rate, 1).unwrap()
+            );
+        }
+        assert!("0".parse::<FrameRate>().is_err());
+    }
+
+    #[test]
+    fn ntsc_frames_do_not_drift() {
+        let rate = FrameRate::new(30000, 1001).unwrap();
+
+        // 30000 frames at 29.97 fps last exactly 1001 seconds
+        assert_eq!(rate.frame_start_sample(30000, 48000), 1001 * 48000);
+        assert_eq!(rate.frame_start_sample(30001, 48000), 1001 * 48000 + 1601);
+        assert_eq!(rate.max_frame_samples(48000), 1602);
+
+        // every sample belongs to the frame whose span it falls into
+        for frame in 0..1000 {
+            let start = rate.frame_start_sample(frame, 48000);
+            let end = rate.frame_start_sample(frame + 1, 48000);
+            assert_eq!(rate.frame_at_sample(start, 48000), frame);
+            assert_eq!(rate.frame_at_sample(end - 1, 48000), frame);
+        }
+    }
+
+    #[test]
+    fn parse_timestamps() {
+        let parse = |s: &str| s.parse::<Timestamp>().unwrap().as_micros();
+
+        assert_eq!(parse("90"), 90_000_000);
+        assert_eq!(parse("1:30.5"), 90_500_000);
+        assert_eq!(parse("1:02:03.000004"), 3_723_000_004);
+        assert_eq!(parse("1m30s"), 90_000_000);
+        assert!("1:2:3:4".parse::<Timestamp>().is_err());
+        assert!("1.2345678".parse::<Timestamp>().is_err());
+
+        let timestamp = Timestamp::from_micros(3_723_250_000);
+        assert_eq!(timestamp.to_string(), "1:02:03.25");
+        assert_eq!(
+            timestamp.to_string().parse::<Timestamp>().unwrap(),
+            timestamp
+        );
+    }
+}
@@ -1,56 +1,107 @@
-use crate::util::MultiSlice;
-use crate::visualizer::{Visualizer, VisualizerInput, VisualizerInputExtra};
-use ffmpeg_next::frame::Audio;
-use futures::future::LocalBoxFuture;
-use futures::FutureExt;
-use num_complex::Complex32;
+use crate::analysis::AnalysisFrame;
+use crate::canvas::Canvas;
+use crate::util::RGB;
+use crate::visualizer::param::{BoundParam, Param};
+use crate::visualizer::{Renderer, StatelessVisualizer, VisualizerInput, VisualizerInputExtra};
+use anyhow::bail;
 use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// The height in pixels of the progress bar.
+const PROGRESS_BAR_HEIGHT: usize = 4;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct BarsVisualizerInput {}
+pub struct BarsVisualizerInput {
+    /// Whether to flash the bars red on every beat.
+    #[serde(default)]
+    pub beat_flash: bool,
+    /// Whether to draw how far through the track the render is along the bottom.
+    #[serde(default)]
+    pub progress_bar: bool,
+    /// How bright the bars are, where 1 shows the spectrum levels as they are.
+    #[serde(default = "default_brightness")]
+    pub brightness: Param,
+}
+
+fn default_brightness() -> Param {
+    Param::Constant(1.0)
+}
 
 impl VisualizerInput for BarsVisualizerInput {
-    async fn new_visualizer(
-        &self,
-        extra: VisualizerInputExtra,
-    ) -> anyhow::Result<Box<dyn Visualizer>> {
-        Ok(Box::new(BarsVisualizer { extra }))
+    async fn new_visualizer(&self, extra: VisualizerInputExtra) -> anyhow::Result<Renderer> {
+        if self.progress_bar && extra.track.is_none() {
+            bail!("The progress bar needs the track length, so it only works in two-pass renders");
+        }
+        let brightness = self.brightness.bind("brightness", &extra)?;
+
+        Ok(Renderer::Stateless(Arc::new(BarsVisualizer {
+            extra,
+            beat_flash: self.beat_flash,
+            progress_bar: self.progress_bar,
+            brightness,
+        })))
     }
 }
 
 pub struct BarsVisualizer {
     extra: VisualizerInputExtra,
+    beat_flash: bool,
+    progress_bar: bool,
+    brightness: BoundParam,
 }
 
-impl Visualizer for BarsVisualizer {
-    fn render_frame<'a>(
-        &'a mut self,
-        _audio_in: &'a Audio,
-        audio_fft: &'a MultiSlice<Complex32>,
-        video_out: &'a mut [u8],
-    ) -> LocalBoxFuture<'a, anyhow::Result<()>> {
-        async {
-            // reference implementation
-            for x in 0usize..self.extra.width as usize {
-                let buf_index = x * self.extra.fft_length / (self.extra.width as usize);
-
-                let pixel_1 = (audio_fft[0][buf_index].norm() * 2.0) as u8;
-                let pixel_2 = audio_fft
-                    .get(1)
-                    .map(|out| (out[buf_index].norm() * 2.0) as u8);
-
-                for y in 0usize..self.extra.height as usize {
-                    let pixel = (y * (self.extra.width as usize) + x) * 4;
-                    video_out[pixel] = 0xFF; // alpha
-                    video_out[pixel + 3] = pixel_1; // blue
-                    if let Some(pixel_2) = pixel_2 {
-                        video_out[pixel + 2] = pixel_2; // green
-                    }
-                }
+impl StatelessVisualizer for BarsVisualizer {
+    fn render_frame(
+        &self,
+        frame: &AnalysisFrame,
+        mut canvas: Canvas<&mut [u8]>,
+    ) -> anyhow::Result<()> {
+        // the flash fades out over the course of each beat, and is brightest on the first beat of a bar
+        let flash = if self.beat_flash {
+            match &self.extra.tempo {
+                Some(tempo) => tempo.position(frame.time).map_or(0.0, |position| {
+                    let accent = if position.beat_in_bar == 0 { 1.0 } else { 0.5 };
+                    (1.0 - position.beat_phase).powi(4) * accent
+                }),
+                None => frame
+                    .rhythm
+                    .tempo
+                    .map_or(0.0, |_| (1.0 - frame.rhythm.beat_phase).powi(4)),
             }
+        } else {
+            0.0
+        };
+
+        let brightness = self.brightness.at(frame);
 
-            Ok(())
+        // reference implementation
+        for x in 0usize..self.extra.width as usize {
+            let band = x * self.extra.spectrum_bands / (self.extra.width as usize);
+
+            let color = RGB::new(
+                flash,
+                frame.spectrum.get(1).map_or(0.0, |levels| levels[band]) * brightness,
+                frame.spectrum[0][band] * brightness,
+            );
+
+            for y in 0usize..self.extra.height as usize {
+                canvas.set_pixel(x, y, color);
+            }
         }
-        .boxed_local()
+
+        if let Some(track) = self.extra.track.as_ref().filter(|_| self.progress_bar) {
+            let elapsed = frame.time.saturating_sub(track.start).as_secs_f64();
+            let progress = (elapsed / track.duration.as_secs_f64().max(f64::EPSILON)).min(1.0);
+            let filled = (progress * self.extra.width as f64).round() as usize;
+
+            let height = self.extra.height as usize;
+            for y in height.saturating_sub(PROGRESS_BAR_HEIGHT)..height {
+                for x in 0..filled {
+                    canvas.set_pixel(x, y, RGB::new(1.0, 1.0, 1.0));
+                }
+            }
+        }
+
+        Ok(())
     }
 }
@@ -1,124 +1,219 @@
-use crate::util::{MultiSlice, RGB};
-use crate::visualizer::{Visualizer, VisualizerInput, VisualizerInputExtra};
-use ffmpeg_next::frame::Audio;
+use crate::analysis::AnalysisFrame;
+use crate::canvas::Canvas;
+use crate::util::{Timestamp, RGB};
+use crate::visualizer::param::{BoundParam, Param};
+use crate::visualizer::tiles::{render_tiles, Tile};
+use crate::visualizer::{Renderer, Visualizer, VisualizerInput, VisualizerInputExtra};
+use anyhow::bail;
 use futures::future::LocalBoxFuture;
 use futures::FutureExt;
-use num_complex::Complex32;
 use rand::rngs::SmallRng;
-use rand::{Rng, SeedableRng};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CottonVisualizerInput {
-    pub seed: Option<u64>,
+    /// The seed of the random drift. A random one if unset.
+    pub seed: Option<Seed>,
+    /// How much random color is added to every pixel as it drifts down.
+    #[serde(default = "default_noise")]
+    pub noise: Param,
 }
 
-impl VisualizerInput for CottonVisualizerInput {
-    async fn new_visualizer(
-        &self,
-        extra: VisualizerInputExtra,
-    ) -> anyhow::Result<Box<dyn Visualizer>> {
-        let frame_old = vec![0u8; (extra.width * extra.height * 4) as usize];
-        let rand = if let Some(seed) = self.seed {
-            SmallRng::seed_from_u64(seed)
-        } else {
-            SmallRng::from_entropy()
+fn default_noise() -> Param {
+    Param::Constant(0.01)
+}
+
+/// A seed for the whole track, or seeds that take over from each other at keyframes.
+///
+/// In a project file it is either a plain number or a list of keyframes. Unlike a [Param] there is nothing between
+/// two seeds to ease through, so each seed holds from its keyframe until the next one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Seed {
+    Constant(u64),
+    /// Keyframes ordered by time. The first seed also holds before its keyframe.
+    Keyframes(Vec<SeedKeyframe>),
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct SeedKeyframe {
+    /// Where in the input the drift is reseeded.
+    pub time: Timestamp,
+    pub value: u64,
+}
+
+impl Seed {
+    fn validate(&self) -> anyhow::Result<()> {
+        let Seed::Keyframes(keyframes) = self else {
+            return Ok(());
         };
 
-        Ok(Box::new(CottonVisualizer {
+        if keyframes.is_empty() {
+            bail!("The seed has no keyframes");
+        }
+        if keyframes.windows(2).any(|pair| pair[1].time < pair[0].time) {
+            bail!("The keyframes of the seed are out of order");
+        }
+
+        Ok(())
+    }
+
+    /// Gets the seed at a point in the input.
+    fn at(&self, time: Timestamp) -> u64 {
+        match self {
+            Seed::Constant(seed) => *seed,
+            Seed::Keyframes(keyframes) => {
+                let next = keyframes.partition_point(|keyframe| keyframe.time <= time);
+                keyframes[next.saturating_sub(1)].value
+            }
+        }
+    }
+}
+
+impl VisualizerInput for CottonVisualizerInput {
+    async fn new_visualizer(&self, extra: VisualizerInputExtra) -> anyhow::Result<Renderer> {
+        let noise = self.noise.bind("noise", &extra)?;
+
+        let frame_old = Canvas::blank(extra.width, extra.height);
+        let seed = self
+            .seed
+            .clone()
+            .unwrap_or_else(|| Seed::Constant(rand::random()));
+        seed.validate()?;
+
+        Ok(Renderer::Stateful(Box::new(CottonVisualizer {
             extra,
-            rand,
+            seed,
+            noise,
+            frame_index: 0,
             frame_old,
-        }))
+        })))
     }
 }
 
 pub struct CottonVisualizer {
     extra: VisualizerInputExtra,
-    rand: SmallRng,
-    frame_old: Vec<u8>,
+    seed: Seed,
+    noise: BoundParam,
+    frame_index: u64,
+    frame_old: Canvas<Vec<u8>>,
 }
 
 impl Visualizer for CottonVisualizer {
     fn render_frame<'a>(
         &'a mut self,
-        _audio_in: &'a Audio,
-        audio_fft: &'a MultiSlice<Complex32>,
-        video_out: &'a mut [u8],
+        frame: &'a AnalysisFrame,
+        mut canvas: Canvas<&'a mut [u8]>,
     ) -> LocalBoxFuture<'a, anyhow::Result<()>> {
-        async {
-            for i in 0usize..(self.extra.width as usize / 2) {
-                let buf_index = i * self.extra.fft_length / (self.extra.width as usize / 2);
-
-                let b = (audio_fft[0][buf_index].norm() * 2.0) as u8;
-                let g = audio_fft
-                    .get(1)
-                    .map(|out| (out[buf_index].norm() * 2.0) as u8);
-
-                let x1 = (self.extra.width as usize / 2) + i;
-                let x2 = (self.extra.width as usize / 2) - i - 1;
-
-                let pixel_1 = x1 * 4;
-                let pixel_2 = x2 * 4;
-
-                video_out[pixel_1] = 0xFF; // alpha
-                video_out[pixel_1 + 3] = b; // blue
-                if let Some(g) = g {
-                    video_out[pixel_1 + 2] = g; // green
-                }
-
-                video_out[pixel_2] = 0xFF; // alpha
-                video_out[pixel_2 + 3] = b; // blue
-                if let Some(g) = g {
-                    video_out[pixel_2 + 2] = g; // green
-                }
-            }
-
-            for y in 1usize..(self.extra.height as usize) {
-                for x in 0usize..(self.extra.width as usize) {
-                    let up_scale: f32 = self.rand.gen();
-                    let up_left_scale: f32 = self.rand.gen();
-                    let up_right_scale: f32 = self.rand.gen();
-
-                    let mut total = up_scale;
-
-                    let up_left = if x > 0 {
-                        total += up_left_scale;
-                        RGB::from_pixel(&self.frame_old, x - 1, y - 1, self.extra.width)
-                            .scale(up_left_scale)
-                    } else {
-                        RGB::ZERO
-                    };
-                    let up_right = if x < self.extra.width as usize - 1 {
-                        total += up_right_scale;
-                        RGB::from_pixel(&self.frame_old, x + 1, y - 1, self.extra.width)
-                            .scale(up_right_scale)
-                    } else {
-                        RGB::ZERO
-                    };
-                    let pixel = RGB::from_pixel(&self.frame_old, x, y - 1, self.extra.width)
-                        .scale(up_scale)
-                        + up_left
-                        + up_right;
-
-                    let r_offset = (self.rand.gen::<f32>() - 0.5) * 0.01;
-                    let g_offset = (self.rand.gen::<f32>() - 0.5) * 0.01;
-                    let b_offset = (self.rand.gen::<f32>() - 0.5) * 0.01;
-
-                    let mut pixel = pixel.scale(1.0 / total);
-
-                    pixel.r += r_offset;
-                    pixel.g += g_offset;
-                    pixel.b += b_offset;
-
-                    pixel.write_pixel(video_out, x, y, self.extra.width);
-                }
-            }
+        async move {
+            let this = &*self;
+            let seed = self.seed.at(frame.time);
+            let noise = self.noise.at(frame);
+            render_tiles(&mut canvas, |tile| {
+                this.render_tile(frame, seed, noise, tile)
+            });
 
-            self.frame_old.copy_from_slice(video_out);
+            self.frame_old.copy_from(&canvas);
+            self.frame_index += 1;
 
             Ok(())
         }
         .boxed_local()
     }
 }
+
+impl CottonVisualizer {
+    fn render_tile(&self, frame: &AnalysisFrame, seed: u64, noise: f32, mut tile: Tile) {
+        let mut rand = tile.rng(seed, self.frame_index);
+
+        for y in tile.rows() {
+            if y == 0 {
+                self.render_spectrum_row(frame, &mut tile);
+            } else {
+                self.render_row(&mut rand, noise, y, &mut tile);
+            }
+        }
+    }
+
+    fn render_spectrum_row(&self, frame: &AnalysisFrame, tile: &mut Tile) {
+        for i in 0usize..(self.extra.width as usize / 2) {
+            let band = i * self.extra.spectrum_bands / (self.extra.width as usize / 2);
+
+            let color = RGB::new(
+                0.0,
+                frame.spectrum.get(1).map_or(0.0, |levels| levels[band]),
+                frame.spectrum[0][band],
+            );
+
+            let x1 = (self.extra.width as usize / 2) + i;
+            let x2 = (self.extra.width as usize / 2) - i - 1;
+
+            tile.set_pixel(x1, 0, color);
+            tile.set_pixel(x2, 0, color);
+        }
+    }
+
+    fn render_row(&self, rand: &mut SmallRng, noise: f32, y: usize, tile: &mut Tile) {
+        for x in 0usize..(self.extra.width as usize) {
+            let up_scale: f32 = rand.gen();
+            let up_left_scale: f32 = rand.gen();
+            let up_right_scale: f32 = rand.gen();
+
+            let mut total = up_scale;
+
+            let up_left = if x > 0 {
+                total += up_left_scale;
+                self.frame_old.get_pixel(x - 1, y - 1).scale(up_left_scale)
+            } else {
+                RGB::ZERO
+            };
+            let up_right = if x < self.extra.width as usize - 1 {
+                total += up_right_scale;
+                self.frame_old.get_pixel(x + 1, y - 1).scale(up_right_scale)
+            } else {
+                RGB::ZERO
+            };
+            let pixel = self.frame_old.get_pixel(x, y - 1).scale(up_scale) + up_left + up_right;
+
+            let r_offset = (rand.gen::<f32>() - 0.5) * noise;
+            let g_offset = (rand.gen::<f32>() - 0.5) * noise;
+            let b_offset = (rand.gen::<f32>() - 0.5) * noise;
+
+            let mut pixel = pixel.scale(1.0 / total);
+
+            pixel.r += r_offset;
+            pixel.g += g_offset;
+            pixel.b += b_offset;
+
+            tile.set_pixel(x, y, pixel);
+        }
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    #[test]
+    fn seeds_hold_until_the_next_keyframe() {
+        let seed: Seed = serde_json::from_str(
+            r#"[
+                {"time": "10", "value": 1},
+                {"time": "20", "value": 2}
+            ]"#,
+        )
+        .unwrap();
+        seed.validate().unwrap();
+
+        let at = |seconds: u64| seed.at(Timestamp::from_micros(seconds * 1_000_000));
+        assert_eq!(at(0), 1);
+        assert_eq!(at(15), 1);
+        assert_eq!(at(20), 2);
+        assert_eq!(at(100), 2);
+
+        let seed: Seed = serde_json::from_str("18446744073709551615").unwrap();
+        assert_eq!(seed.at(Timestamp::ZERO), u64::MAX);
+        assert!(Seed::Keyframes(vec![]).validate().is_err());
+    }
+}
@@ -1,92 +1,81 @@
-use crate::util::{pixel, MultiSlice};
-use crate::visualizer::{Visualizer, VisualizerInput, VisualizerInputExtra};
-use ffmpeg_next::frame::Audio;
+use crate::analysis::AnalysisFrame;
+use crate::canvas::Canvas;
+use crate::util::RGB;
+use crate::visualizer::tiles::{render_tiles, Tile};
+use crate::visualizer::{Renderer, Visualizer, VisualizerInput, VisualizerInputExtra};
 use futures::future::LocalBoxFuture;
 use futures::FutureExt;
-use num_complex::Complex32;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CreditsVisualizerInput {}
 
 impl VisualizerInput for CreditsVisualizerInput {
-    async fn new_visualizer(
-        &self,
-        extra: VisualizerInputExtra,
-    ) -> anyhow::Result<Box<dyn Visualizer>> {
-        let frame_old = vec![0u8; (extra.width * extra.height * 4) as usize];
+    async fn new_visualizer(&self, extra: VisualizerInputExtra) -> anyhow::Result<Renderer> {
+        let frame_old = Canvas::blank(extra.width, extra.height);
 
-        Ok(Box::new(CreditsVisualizer { extra, frame_old }))
+        Ok(Renderer::Stateful(Box::new(CreditsVisualizer {
+            extra,
+            frame_old,
+        })))
     }
 }
 
 pub struct CreditsVisualizer {
     extra: VisualizerInputExtra,
-    frame_old: Vec<u8>,
+    frame_old: Canvas<Vec<u8>>,
 }
 
 impl Visualizer for CreditsVisualizer {
     fn render_frame<'a>(
         &'a mut self,
-        _audio_in: &'a Audio,
-        audio_fft: &'a MultiSlice<Complex32>,
-        video_out: &'a mut [u8],
+        frame: &'a AnalysisFrame,
+        mut canvas: Canvas<&'a mut [u8]>,
     ) -> LocalBoxFuture<'a, anyhow::Result<()>> {
-        async {
-            for i in 0usize..(self.extra.width as usize / 2) {
-                let buf_index = i * self.extra.fft_length / (self.extra.width as usize / 2);
+        async move {
+            let this = &*self;
+            render_tiles(&mut canvas, |tile| this.render_tile(frame, tile));
 
-                let b = (audio_fft[0][buf_index].norm() * 2.0) as u8;
-                let g = audio_fft
-                    .get(1)
-                    .map(|out| (out[buf_index].norm() * 2.0) as u8);
+            self.frame_old.copy_from(&canvas);
 
-                let x1 = (self.extra.width as usize / 2) + i;
-                let x2 = (self.extra.width as usize / 2) - i - 1;
-
-                let pixel_1 = x1 * 4;
-                let pixel_2 = x2 * 4;
-
-                video_out[pixel_1] = 0xFF; // alpha
-                video_out[pixel_1 + 3] = b; // blue
-                if let Some(g) = g {
-                    video_out[pixel_1 + 2] = g; // green
-                }
+            Ok(())
+        }
+        .boxed_local()
+    }
+}
 
-                video_out[pixel_2] = 0xFF; // alpha
-                video_out[pixel_2 + 3] = b; // blue
-                if let Some(g) = g {
-                    video_out[pixel_2 + 2] = g; // green
-                }
+impl CreditsVisualizer {
+    fn render_tile(&self, frame: &AnalysisFrame, mut tile: Tile) {
+        for y in tile.rows() {
+            if y == 0 {
+                self.render_spectrum_row(frame, &mut tile);
+            } else {
+                self.render_row(y, &mut tile);
             }
+        }
+    }
 
-            for y in 1usize..(self.extra.height as usize) {
-                for x in 0usize..(self.extra.width as usize) {
-                    let pixel_up = self.get_old_pixel(x, y - 1);
+    fn render_spectrum_row(&self, frame: &AnalysisFrame, tile: &mut Tile) {
+        for i in 0usize..(self.extra.width as usize / 2) {
+            let band = i * self.extra.spectrum_bands / (self.extra.width as usize / 2);
 
-                    let index = pixel(x, y, self.extra.width);
-                    video_out[index] = 0xFF;
-                    video_out[index + 1] = pixel_up.0;
-                    video_out[index + 2] = pixel_up.1;
-                    video_out[index + 3] = pixel_up.2;
-                }
-            }
+            let color = RGB::new(
+                0.0,
+                frame.spectrum.get(1).map_or(0.0, |levels| levels[band]),
+                frame.spectrum[0][band],
+            );
 
-            self.frame_old.copy_from_slice(video_out);
+            let x1 = (self.extra.width as usize / 2) + i;
+            let x2 = (self.extra.width as usize / 2) - i - 1;
 
-            Ok(())
+            tile.set_pixel(x1, 0, color);
+            tile.set_pixel(x2, 0, color);
         }
-        .boxed_local()
     }
-}
 
-impl CreditsVisualizer {
-    fn get_old_pixel(&self, x: usize, y: usize) -> (u8, u8, u8) {
-        let index = pixel(x, y, self.extra.width);
-        (
-            self.frame_old[index + 1],
-            self.frame_old[index + 2],
-            self.frame_old[index + 3],
-        )
+    fn render_row(&self, y: usize, tile: &mut Tile) {
+        for x in 0usize..(self.extra.width as usize) {
+            tile.set_pixel(x, y, self.frame_old.get_pixel(x, y - 1));
+        }
     }
 }
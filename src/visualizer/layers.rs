@@ -0,0 +1,274 @@
+use crate::analysis::AnalysisFrame;
+use crate::canvas::{BlendMode, Canvas};
+use crate::project::VisualizerEnum;
+use crate::util::RGB;
+use crate::visualizer::param::{BoundParam, Param};
+use crate::visualizer::{Renderer, StatelessVisualizer, Visualizer, VisualizerInputExtra};
+use anyhow::{bail, Context};
+use futures::future::LocalBoxFuture;
+use futures::FutureExt;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::borrow::Cow;
+use std::sync::{Arc, Mutex};
+
+/// A visualizer drawn on top of the layers before it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Layer {
+    pub visualizer: VisualizerEnum,
+    /// How much of the blended layer shows, from 0 for invisible up to 1.
+    #[serde(default = "default_opacity")]
+    pub opacity: Param,
+    #[serde(default)]
+    pub blend: BlendMode,
+    /// The audio channels this layer's visualizer sees, in order. All of them if unset.
+    #[serde(default)]
+    pub channels: Option<Vec<usize>>,
+}
+
+fn default_opacity() -> Param {
+    Param::Constant(1.0)
+}
+
+impl Layer {
+    /// Whether the layer shows its visualizer as it is, so drawing it alone needs no compositing.
+    fn is_plain(&self) -> bool {
+        matches!(self.opacity, Param::Constant(opacity) if opacity >= 1.0)
+            && self.blend == BlendMode::Normal
+            && self.channels.is_none()
+    }
+}
+
+impl From<VisualizerEnum> for Layer {
+    fn from(visualizer: VisualizerEnum) -> Self {
+        Layer {
+            visualizer,
+            opacity: default_opacity(),
+            blend: BlendMode::default(),
+            channels: None,
+        }
+    }
+}
+
+/// Reads a list of layers, which can also be written as a single visualizer for a stack of just that one.
+pub fn deserialize_layers<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<Layer>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Layers {
+        Stack(Vec<Layer>),
+        Single(VisualizerEnum),
+    }
+
+    Ok(match Layers::deserialize(deserializer)? {
+        Layers::Stack(layers) => layers,
+        Layers::Single(visualizer) => vec![visualizer.into()],
+    })
+}
+
+/// Creates the visualizer of every layer, and a visualizer that composites them from the bottom up.
+///
+/// A single plain layer is just its own visualizer, whose parameters keep the names they have outside of a stack.
+pub async fn new_visualizer(
+    layers: &[Layer],
+    extra: VisualizerInputExtra,
+) -> anyhow::Result<Renderer> {
+    match layers {
+        [] => bail!("A layer stack needs at least one layer"),
+        [layer] if layer.is_plain() => return layer.visualizer.new_visualizer(extra).await,
+        _ => {}
+    }
+
+    let mut active_layers = vec![];
+    for (index, layer) in layers.iter().enumerate() {
+        let opacity = layer
+            .opacity
+            .bind(&format!("layers.{}.opacity", index), &extra)?;
+
+        let mut layer_extra = extra.clone();
+        layer_extra.param_prefix = format!("{}layers.{}.", extra.param_prefix, index);
+        if let Some(channels) = &layer.channels {
+            if let Some(&channel) = channels.iter().find(|&&channel| channel >= extra.channels) {
+                bail!(
+                    "Layer {} selects channel {}, but the input only has {} channels",
+                    index,
+                    channel,
+                    extra.channels
+                );
+            }
+            if channels.is_empty() {
+                bail!("Layer {} selects no channels", index);
+            }
+            layer_extra.channels = channels.len();
+        }
+
+        let renderer = layer
+            .visualizer
+            .new_visualizer(layer_extra)
+            .await
+            .with_context(|| format!("Creating visualizer of layer {}", index))?;
+
+        active_layers.push(ActiveLayer {
+            renderer,
+            options: LayerOptions {
+                opacity,
+                blend: layer.blend,
+                channels: layer.channels.clone(),
+            },
+        });
+    }
+
+    // the stack only needs to render frames in order if one of its layers does
+    if active_layers
+        .iter()
+        .all(|layer| matches!(layer.renderer, Renderer::Stateless(_)))
+    {
+        Ok(Renderer::Stateless(Arc::new(StatelessLayersVisualizer {
+            extra,
+            layers: active_layers
+                .into_iter()
+                .map(|layer| match layer.renderer {
+                    Renderer::Stateless(visualizer) => (visualizer, layer.options),
+                    Renderer::Stateful(_) => unreachable!(),
+                })
+                .collect(),
+            buffers: Mutex::new(vec![]),
+        })))
+    } else {
+        let buffer = Canvas::blank(extra.width, extra.height);
+        Ok(Renderer::Stateful(Box::new(LayersVisualizer {
+            layers: active_layers,
+            buffer,
+        })))
+    }
+}
+
+struct ActiveLayer {
+    renderer: Renderer,
+    options: LayerOptions,
+}
+
+/// How a rendered layer is put onto the ones beneath it.
+struct LayerOptions {
+    opacity: BoundParam,
+    blend: BlendMode,
+    channels: Option<Vec<usize>>,
+}
+
+impl LayerOptions {
+    /// Gets the frame the layer sees, copying it only if the layer selects channels.
+    fn frame<'a>(&self, frame: &'a AnalysisFrame) -> Cow<'a, AnalysisFrame> {
+        match &self.channels {
+            Some(channels) => Cow::Owned(frame.select_channels(channels)),
+            None => Cow::Borrowed(frame),
+        }
+    }
+}
+
+/// Composites layers where at least one of them has to render frames in order.
+pub struct LayersVisualizer {
+    layers: Vec<ActiveLayer>,
+    /// Where each layer is rendered before it is blended onto the output.
+    buffer: Canvas<Vec<u8>>,
+}
+
+impl Visualizer for LayersVisualizer {
+    fn render_frame<'a>(
+        &'a mut self,
+        frame: &'a AnalysisFrame,
+        mut canvas: Canvas<&'a mut [u8]>,
+    ) -> LocalBoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            canvas.fill(RGB::ZERO);
+
+            for layer in self.layers.iter_mut() {
+                let options = &layer.options;
+                let layer_frame = options.frame(frame);
+
+                layer
+                    .renderer
+                    .render_frame(&layer_frame, self.buffer.view_mut())
+                    .await?;
+
+                canvas.blend_from(&self.buffer, options.blend, options.opacity.at(frame));
+            }
+
+            Ok(())
+        }
+        .boxed_local()
+    }
+}
+
+/// Composites layers that can all render any frame at any time.
+pub struct StatelessLayersVisualizer {
+    extra: VisualizerInputExtra,
+    layers: Vec<(Arc<dyn StatelessVisualizer>, LayerOptions)>,
+    /// Spare layer buffers, shared by all frames being rendered.
+    buffers: Mutex<Vec<Canvas<Vec<u8>>>>,
+}
+
+impl StatelessVisualizer for StatelessLayersVisualizer {
+    fn render_frame(
+        &self,
+        frame: &AnalysisFrame,
+        mut canvas: Canvas<&mut [u8]>,
+    ) -> anyhow::Result<()> {
+        let mut buffer = self
+            .buffers
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| Canvas::blank(self.extra.width, self.extra.height));
+
+        canvas.fill(RGB::ZERO);
+
+        let result = self.layers.iter().try_for_each(|(visualizer, options)| {
+            visualizer.render_frame(&options.frame(frame), buffer.view_mut())?;
+            canvas.blend_from(&buffer, options.blend, options.opacity.at(frame));
+            Ok(())
+        });
+
+        self.buffers.lock().unwrap().push(buffer);
+        result
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::project::Program;
+
+    #[test]
+    fn programs_read_a_single_visualizer_as_a_stack_of_one() {
+        let program: Program = serde_json::from_str(
+            r#"{"width": 64, "height": 36, "visualizer": {"type": "Stereo", "spread": 2.0}}"#,
+        )
+        .unwrap();
+        assert_eq!(program.layers.len(), 1);
+        assert!(matches!(
+            program.layers[0].visualizer,
+            VisualizerEnum::Stereo(_)
+        ));
+        assert!(program.layers[0].is_plain());
+
+        let program: Program = serde_json::from_str(
+            r#"{"width": 64, "height": 36, "layers": [
+                {"visualizer": {"type": "Cotton"}},
+                {"visualizer": {"type": "Bars"}, "opacity": 0.5, "blend": "Screen", "channels": [1]}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(program.layers.len(), 2);
+        assert!(matches!(
+            program.layers[0].visualizer,
+            VisualizerEnum::Cotton(_)
+        ));
+        assert!(matches!(
+            program.layers[1].visualizer,
+            VisualizerEnum::Bars(_)
+        ));
+        assert_eq!(program.layers[1].blend, BlendMode::Screen);
+        assert_eq!(program.layers[1].channels, Some(vec![1]));
+        assert!(!program.layers[1].is_plain());
+    }
+}
@@ -1,33 +1,99 @@
 //! This module contains the different visualizer modules
 
-use crate::util::MultiSlice;
-use ffmpeg_next::frame;
+use crate::analysis::chroma::Key;
+use crate::analysis::tempo::TempoMap;
+use crate::analysis::track::TrackAnalysis;
+use crate::analysis::AnalysisFrame;
+use crate::canvas::Canvas;
+use crate::visualizer::modulation::ModulationMatrix;
 use futures::future::LocalBoxFuture;
-use num_complex::Complex32;
+use std::sync::Arc;
 
 pub mod bars;
 pub mod cotton;
 pub mod credits;
+pub mod layers;
+pub mod modulation;
+pub mod param;
+pub mod pipeline;
+pub mod stereo;
+pub mod tiles;
+pub mod timeline;
+pub mod title;
 
 #[derive(Debug, Clone)]
 pub struct VisualizerInputExtra {
     pub width: u32,
     pub height: u32,
+    /// The number of audio channels in each [AnalysisFrame].
+    pub channels: usize,
+    /// The number of frequency bins in each channel's spectrum.
     pub fft_length: usize,
+    /// The number of samples in each FFT window.
+    pub fft_size: usize,
+    pub sample_rate: u32,
+    /// The number of bands in each channel of [AnalysisFrame::spectrum].
+    pub spectrum_bands: usize,
+    /// The tempo map of the rendered part of the input, if the program asks for one.
+    ///
+    /// Look up [AnalysisFrame::time] in it to find where each frame falls in the bars and beats of the track.
+    pub tempo: Option<Arc<TempoMap>>,
+    /// The estimated key of the rendered part of the input, if the program asks for one. None of the built-in
+    /// visualizers are harmonic yet.
+    #[allow(dead_code)]
+    pub key: Option<Key>,
+    /// The statistics of the whole rendered part of the input, if the program renders in two passes.
+    pub track: Option<Arc<TrackAnalysis>>,
+    /// The modulations of the program, looked up by the parameters of every visualizer.
+    pub modulation: Arc<ModulationMatrix>,
+    /// What the names of this visualizer's parameters are prefixed with, for visualizers inside layers or scenes.
+    pub param_prefix: String,
+}
+
+impl VisualizerInputExtra {
+    /// Gets the center frequency in Hz of the given spectrum bin.
+    pub fn bin_frequency(&self, bin: usize) -> f32 {
+        bin as f32 * self.sample_rate as f32 / self.fft_size as f32
+    }
 }
 
 pub trait VisualizerInput {
-    async fn new_visualizer(
-        &self,
-        extra: VisualizerInputExtra,
-    ) -> anyhow::Result<Box<dyn Visualizer>>;
+    async fn new_visualizer(&self, extra: VisualizerInputExtra) -> anyhow::Result<Renderer>;
+}
+
+/// A visualizer created from a [VisualizerInput].
+pub enum Renderer {
+    /// Renders frames one at a time, in order, and may carry state from one frame to the next.
+    Stateful(Box<dyn Visualizer>),
+    /// Renders every frame from its analysis alone, so several frames can be rendered at once.
+    Stateless(Arc<dyn StatelessVisualizer>),
+}
+
+impl Renderer {
+    /// Renders a single frame, whichever way this visualizer renders.
+    pub async fn render_frame(
+        &mut self,
+        frame: &AnalysisFrame,
+        canvas: Canvas<&mut [u8]>,
+    ) -> anyhow::Result<()> {
+        match self {
+            Renderer::Stateful(visualizer) => visualizer.render_frame(frame, canvas).await,
+            Renderer::Stateless(visualizer) => visualizer.render_frame(frame, canvas),
+        }
+    }
 }
 
 pub trait Visualizer {
     fn render_frame<'a>(
         &'a mut self,
-        audio_in: &'a frame::Audio,
-        audio_fft: &'a MultiSlice<Complex32>,
-        video_out: &'a mut [u8],
+        frame: &'a AnalysisFrame,
+        canvas: Canvas<&'a mut [u8]>,
     ) -> LocalBoxFuture<'a, anyhow::Result<()>>;
 }
+
+/// A visualizer whose output only depends on the frame being rendered.
+///
+/// Frames are rendered on worker threads, possibly several at once and out of order.
+pub trait StatelessVisualizer: Send + Sync {
+    fn render_frame(&self, frame: &AnalysisFrame, canvas: Canvas<&mut [u8]>) -> anyhow::Result<()>;
+}
@@ -0,0 +1,237 @@
+use crate::analysis::spectrum::Spectrum;
+use crate::analysis::AnalysisFrame;
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+use std::sync::Mutex;
+
+/// Drives a visualizer parameter from a feature of the analyzed audio.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Modulation {
+    /// The parameter this modulation is added to, like `brightness` or `layers.0.opacity`.
+    pub target: String,
+    pub source: Feature,
+    #[serde(default)]
+    pub curve: Curve,
+    /// What is added to the parameter when the feature is at 0.
+    #[serde(default)]
+    pub min: f32,
+    /// What is added to the parameter when the feature is at 1.
+    #[serde(default = "default_max")]
+    pub max: f32,
+}
+
+fn default_max() -> f32 {
+    1.0
+}
+
+/// A per-frame value of the analysis in the range 0..1.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Feature {
+    /// The average level of the spectrum bands between two frequencies in Hz, across all channels.
+    Band { low: f32, high: f32 },
+    /// The average RMS level of all channels.
+    Rms,
+    /// How strongly a new sound starts in this frame.
+    Onset,
+    /// How far the frame is between the last beat and the next one.
+    BeatPhase,
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Curve {
+    #[default]
+    Linear,
+    /// Keeps low values low, so only strong features have much of an effect.
+    Square,
+    /// Brings low values up, so weak features are still visible.
+    SquareRoot,
+    /// Eases in and out of both ends of the range.
+    Smooth,
+}
+
+impl Curve {
+    pub fn apply(self, value: f32) -> f32 {
+        match self {
+            Curve::Linear => value,
+            Curve::Square => value * value,
+            Curve::SquareRoot => value.sqrt(),
+            Curve::Smooth => value * value * (3.0 - 2.0 * value),
+        }
+    }
+}
+
+/// All modulations of a program, resolved against its spectrum.
+///
+/// Visualizers look up their parameters in here when they are created, which also keeps track of which targets were
+/// never found.
+#[derive(Debug)]
+pub struct ModulationMatrix {
+    routes: Vec<Route>,
+    bound: Mutex<Vec<bool>>,
+}
+
+/// A modulation whose feature can be read straight from a frame.
+#[derive(Debug, Clone)]
+pub struct Route {
+    target: String,
+    source: Source,
+    curve: Curve,
+    min: f32,
+    max: f32,
+}
+
+#[derive(Debug, Clone)]
+enum Source {
+    Bands(Range<usize>),
+    Rms,
+    Onset,
+    BeatPhase,
+}
+
+impl ModulationMatrix {
+    pub fn new(
+        modulations: &[Modulation],
+        spectrum: &Spectrum,
+    ) -> anyhow::Result<ModulationMatrix> {
+        let mut routes = vec![];
+        for (index, modulation) in modulations.iter().enumerate() {
+            let source = match modulation.source {
+                Feature::Band { low, high } => {
+                    let mut bands = 0..spectrum.band_count();
+                    let in_range =
+                        |band: usize| (low..=high).contains(&spectrum.band_frequency(band));
+                    let (Some(first), Some(last)) =
+                        (bands.clone().position(in_range), bands.rposition(in_range))
+                    else {
+                        bail!(
+                            "Modulation {} reads the spectrum from {} Hz to {} Hz, which has no bands",
+                            index,
+                            low,
+                            high
+                        );
+                    };
+                    Source::Bands(first..last + 1)
+                }
+                Feature::Rms => Source::Rms,
+                Feature::Onset => Source::Onset,
+                Feature::BeatPhase => Source::BeatPhase,
+            };
+
+            routes.push(Route {
+                target: modulation.target.clone(),
+                source,
+                curve: modulation.curve,
+                min: modulation.min,
+                max: modulation.max,
+            });
+        }
+
+        Ok(ModulationMatrix {
+            bound: Mutex::new(vec![false; routes.len()]),
+            routes,
+        })
+    }
+
+    /// Gets the modulations of a parameter, marking them as bound.
+    pub fn bind(&self, target: &str) -> Vec<Route> {
+        let mut bound = self.bound.lock().unwrap();
+        self.routes
+            .iter()
+            .zip(bound.iter_mut())
+            .filter(|(route, _)| route.target == target)
+            .map(|(route, bound)| {
+                *bound = true;
+                route.clone()
+            })
+            .collect()
+    }
+
+    /// Gets the targets no visualizer has a parameter for.
+    pub fn unbound_targets(&self) -> Vec<String> {
+        self.routes
+            .iter()
+            .zip(self.bound.lock().unwrap().iter())
+            .filter(|(_, &bound)| !bound)
+            .map(|(route, _)| route.target.clone())
+            .collect()
+    }
+}
+
+impl Route {
+    /// Gets what this modulation adds to its parameter in the given frame.
+    pub fn value(&self, frame: &AnalysisFrame) -> f32 {
+        let feature = match &self.source {
+            Source::Bands(bands) => {
+                let channels = frame.spectrum.len();
+                let sum: f32 = frame
+                    .spectrum
+                    .iter()
+                    .map(|levels| levels[bands.clone()].iter().sum::<f32>())
+                    .sum();
+                sum / (bands.len() * channels).max(1) as f32
+            }
+            Source::Rms => {
+                let channels = &frame.loudness.channels;
+                channels.iter().map(|channel| channel.rms).sum::<f32>()
+                    / channels.len().max(1) as f32
+            }
+            Source::Onset => frame.rhythm.onset_strength,
+            Source::BeatPhase => frame.rhythm.beat_phase,
+        };
+
+        self.map(feature)
+    }
+
+    fn map(&self, feature: f32) -> f32 {
+        self.min + (self.max - self.min) * self.curve.apply(feature.clamp(0.0, 1.0))
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::analysis::spectrum::SpectrumConfig;
+    use crate::util::FrameRate;
+
+    #[test]
+    fn features_are_mapped_onto_their_targets() {
+        let spectrum = Spectrum::new(
+            &SpectrumConfig::default(),
+            4096,
+            48000,
+            FrameRate::new(30, 1).unwrap(),
+        );
+        let modulations: Vec<Modulation> = serde_json::from_str(
+            r#"[
+                {"target": "brightness", "source": {"type": "Band", "low": 20, "high": 60}, "curve": "Square", "min": 1, "max": 2},
+                {"target": "layers.0.opacity", "source": {"type": "BeatPhase"}, "min": 1, "max": 0}
+            ]"#,
+        )
+        .unwrap();
+        let matrix = ModulationMatrix::new(&modulations, &spectrum).unwrap();
+
+        let routes = matrix.bind("brightness");
+        assert_eq!(routes.len(), 1);
+        let Source::Bands(bands) = &routes[0].source else {
+            panic!("band feature resolved to {:?}", routes[0].source);
+        };
+        assert!(!bands.is_empty());
+        assert_eq!(routes[0].map(0.5), 1.25);
+        assert_eq!(routes[0].map(3.0), 2.0);
+
+        assert_eq!(matrix.unbound_targets(), ["layers.0.opacity"]);
+        assert_eq!(matrix.bind("layers.0.opacity")[0].map(0.25), 0.75);
+        assert!(matrix.unbound_targets().is_empty());
+
+        let silent = [Modulation {
+            source: Feature::Band {
+                low: 30000.0,
+                high: 40000.0,
+            },
+            ..modulations[0].clone()
+        }];
+        assert!(ModulationMatrix::new(&silent, &spectrum).is_err());
+    }
+}
@@ -0,0 +1,176 @@
+use crate::analysis::AnalysisFrame;
+use crate::util::Timestamp;
+use crate::visualizer::modulation::Route;
+use crate::visualizer::VisualizerInputExtra;
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
+
+/// A number in a visualizer's input that is either constant or animated over the track by keyframes.
+///
+/// In a project file it is either a plain number or a list of keyframes. Every numeric setting of the visualizers is
+/// one, except for Cotton's seed, which can't be eased between and has keyframes of its own.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Param {
+    Constant(f32),
+    /// Keyframes ordered by time. The value holds before the first keyframe and after the last one.
+    Keyframes(Vec<Keyframe>),
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct Keyframe {
+    /// Where in the input this value is reached.
+    pub time: Timestamp,
+    pub value: f32,
+    /// How the value moves from the previous keyframe to this one.
+    #[serde(default)]
+    pub easing: Easing,
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Easing {
+    /// Keeps the previous value until this keyframe, then jumps.
+    Hold,
+    #[default]
+    Linear,
+    /// Starts slowly and speeds up.
+    EaseIn,
+    /// Starts quickly and slows down.
+    EaseOut,
+    /// Starts and ends slowly.
+    EaseInOut,
+}
+
+impl Easing {
+    /// Maps how far along a transition is, from 0 up to 1, onto how far the value has moved.
+    pub fn apply(self, t: f32) -> f32 {
+        match self {
+            Easing::Hold => 0.0,
+            Easing::Linear => t,
+            Easing::EaseIn => t * t,
+            Easing::EaseOut => 1.0 - (1.0 - t) * (1.0 - t),
+            Easing::EaseInOut => (1.0 - (PI * t).cos()) / 2.0,
+        }
+    }
+}
+
+impl Param {
+    /// Checks that a keyframed parameter has keyframes and that they are in order.
+    pub fn validate(&self, name: &str) -> anyhow::Result<()> {
+        let Param::Keyframes(keyframes) = self else {
+            return Ok(());
+        };
+
+        if keyframes.is_empty() {
+            bail!("Parameter {} has no keyframes", name);
+        }
+        if keyframes.windows(2).any(|pair| pair[1].time < pair[0].time) {
+            bail!("The keyframes of parameter {} are out of order", name);
+        }
+
+        Ok(())
+    }
+
+    /// Checks this parameter and looks up the modulations targeting it.
+    ///
+    /// The name is relative to the visualizer being created, which may sit inside layers or scenes.
+    pub fn bind(&self, name: &str, extra: &VisualizerInputExtra) -> anyhow::Result<BoundParam> {
+        let name = format!("{}{}", extra.param_prefix, name);
+        self.validate(&name)?;
+
+        Ok(BoundParam {
+            param: self.clone(),
+            modulations: extra.modulation.bind(&name),
+        })
+    }
+
+    /// Gets the value of this parameter at a point in the input, without any modulation.
+    pub fn at(&self, time: Timestamp) -> f32 {
+        let keyframes = match self {
+            Param::Constant(value) => return *value,
+            Param::Keyframes(keyframes) => keyframes,
+        };
+
+        let next = keyframes.partition_point(|keyframe| keyframe.time <= time);
+        match (
+            next.checked_sub(1).map(|index| keyframes[index]),
+            keyframes.get(next),
+        ) {
+            (Some(previous), Some(next)) => {
+                let span = next.time.saturating_sub(previous.time).as_secs_f64();
+                let t = (time.saturating_sub(previous.time).as_secs_f64() / span) as f32;
+                previous.value + (next.value - previous.value) * next.easing.apply(t)
+            }
+            (Some(last), None) => last.value,
+            (None, Some(first)) => first.value,
+            (None, None) => 0.0,
+        }
+    }
+}
+
+/// A parameter together with the modulations added to it.
+#[derive(Debug, Clone)]
+pub struct BoundParam {
+    param: Param,
+    modulations: Vec<Route>,
+}
+
+impl BoundParam {
+    /// Gets the value of this parameter in the given frame.
+    pub fn at(&self, frame: &AnalysisFrame) -> f32 {
+        self.param.at(frame.time)
+            + self
+                .modulations
+                .iter()
+                .map(|modulation| modulation.value(frame))
+                .sum::<f32>()
+    }
+}
+
+impl From<f32> for Param {
+    fn from(value: f32) -> Self {
+        Param::Constant(value)
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    #[test]
+    fn keyframes_are_interpolated_with_their_easing() {
+        let keyframe = |seconds: u64, value: f32, easing: Easing| Keyframe {
+            time: Timestamp::from_micros(seconds * 1_000_000),
+            value,
+            easing,
+        };
+        let param: Param = serde_json::from_str(
+            r#"[
+                {"time": "1", "value": 0.0},
+                {"time": "3", "value": 1.0},
+                {"time": "5", "value": 3.0, "easing": "EaseIn"},
+                {"time": "6", "value": 0.0, "easing": "Hold"}
+            ]"#,
+        )
+        .unwrap();
+        param.validate("test").unwrap();
+
+        let at = |seconds: f64| param.at(Timestamp::from_micros((seconds * 1e6) as u64));
+        assert_eq!(at(0.0), 0.0);
+        assert_eq!(at(2.0), 0.5);
+        assert_eq!(at(4.0), 1.5);
+        assert_eq!(at(5.5), 3.0);
+        assert_eq!(at(6.0), 0.0);
+        assert_eq!(at(100.0), 0.0);
+
+        assert_eq!(Param::from(2.0).at(Timestamp::ZERO), 2.0);
+        assert!(serde_json::from_str::<Param>("2.5").is_ok());
+        assert!(Param::Keyframes(vec![
+            keyframe(2, 0.0, Easing::Linear),
+            keyframe(1, 1.0, Easing::Linear)
+        ])
+        .validate("test")
+        .is_err());
+    }
+}
@@ -0,0 +1,128 @@
+use crate::analysis::AnalysisFrame;
+use crate::canvas::Canvas;
+use crate::visualizer::StatelessVisualizer;
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+type RenderResult = (i64, anyhow::Result<Canvas<Vec<u8>>>);
+
+/// Renders frames of a [StatelessVisualizer] on the rayon thread pool, several at a time.
+///
+/// Frames may finish in any order, so finished frames are held until every frame before them has finished and are
+/// then handed out in PTS order.
+pub struct FramePipeline {
+    visualizer: Arc<dyn StatelessVisualizer>,
+    width: u32,
+    height: u32,
+    max_in_flight: usize,
+    rendering: usize,
+    free_buffers: Vec<Canvas<Vec<u8>>>,
+    finished: BTreeMap<i64, Canvas<Vec<u8>>>,
+    next_pts: i64,
+    result_tx: mpsc::UnboundedSender<RenderResult>,
+    result_rx: mpsc::UnboundedReceiver<RenderResult>,
+}
+
+impl FramePipeline {
+    pub fn new(
+        visualizer: Arc<dyn StatelessVisualizer>,
+        width: u32,
+        height: u32,
+        max_in_flight: usize,
+    ) -> FramePipeline {
+        let (result_tx, result_rx) = mpsc::unbounded_channel();
+
+        FramePipeline {
+            visualizer,
+            width,
+            height,
+            max_in_flight: max_in_flight.max(1),
+            rendering: 0,
+            free_buffers: vec![],
+            finished: BTreeMap::new(),
+            next_pts: 0,
+            result_tx,
+            result_rx,
+        }
+    }
+
+    /// Gets the number of frames that have been submitted but not yet handed out.
+    pub fn in_flight(&self) -> usize {
+        self.rendering + self.finished.len()
+    }
+
+    /// Whether another frame can be submitted without going over the in-flight limit.
+    pub fn can_submit(&self) -> bool {
+        self.in_flight() < self.max_in_flight
+    }
+
+    /// Starts rendering a frame. Frames must be submitted with consecutive PTS values.
+    pub fn submit(&mut self, pts: i64, frame: AnalysisFrame) {
+        let mut buffer = self
+            .free_buffers
+            .pop()
+            .unwrap_or_else(|| Canvas::blank(self.width, self.height));
+        let visualizer = self.visualizer.clone();
+        let result_tx = self.result_tx.clone();
+
+        self.rendering += 1;
+
+        rayon::spawn(move || {
+            let result = visualizer
+                .render_frame(&frame, buffer.view_mut())
+                .map(|_| buffer);
+            // the pipeline may already have been dropped because of an error elsewhere
+            result_tx.send((pts, result)).ok();
+        });
+    }
+
+    /// Gets the next frame in PTS order if it has already finished rendering.
+    pub fn try_next(&mut self) -> anyhow::Result<Option<(i64, Canvas<Vec<u8>>)>> {
+        while let Ok(result) = self.result_rx.try_recv() {
+            self.accept(result)?;
+        }
+
+        Ok(self.take_next())
+    }
+
+    /// Waits for the next frame in PTS order, or returns `None` if no frames are in flight.
+    pub async fn next(&mut self) -> anyhow::Result<Option<(i64, Canvas<Vec<u8>>)>> {
+        loop {
+            if let Some(next) = self.take_next() {
+                return Ok(Some(next));
+            }
+
+            if self.rendering == 0 {
+                return Ok(None);
+            }
+
+            let result = self
+                .result_rx
+                .recv()
+                .await
+                .context("Render workers disconnected")?;
+            self.accept(result)?;
+        }
+    }
+
+    /// Returns a buffer handed out by [FramePipeline::next] so it can be rendered into again.
+    pub fn recycle(&mut self, buffer: Canvas<Vec<u8>>) {
+        self.free_buffers.push(buffer);
+    }
+
+    fn accept(&mut self, (pts, result): RenderResult) -> anyhow::Result<()> {
+        self.rendering -= 1;
+        let buffer = result.with_context(|| format!("Rendering frame {}", pts))?;
+        self.finished.insert(pts, buffer);
+        Ok(())
+    }
+
+    fn take_next(&mut self) -> Option<(i64, Canvas<Vec<u8>>)> {
+        let buffer = self.finished.remove(&self.next_pts)?;
+        let pts = self.next_pts;
+        self.next_pts += 1;
+        Some((pts, buffer))
+    }
+}
@@ -0,0 +1,86 @@
+use crate::analysis::AnalysisFrame;
+use crate::canvas::Canvas;
+use crate::util::RGB;
+use crate::visualizer::param::{BoundParam, Param};
+use crate::visualizer::{Renderer, StatelessVisualizer, VisualizerInput, VisualizerInputExtra};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// The width of a band's glow, relative to the width of the screen, when it is completely mono.
+const MIN_SPREAD: f32 = 0.01;
+/// How much wider a band's glow gets, relative to the width of the screen, when it is all side.
+const SIDE_SPREAD: f32 = 0.2;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StereoVisualizerInput {
+    /// How much wider every band's glow is than usual.
+    #[serde(default = "default_spread")]
+    pub spread: Param,
+}
+
+fn default_spread() -> Param {
+    Param::Constant(1.0)
+}
+
+impl VisualizerInput for StereoVisualizerInput {
+    async fn new_visualizer(&self, extra: VisualizerInputExtra) -> anyhow::Result<Renderer> {
+        let spread = self.spread.bind("spread", &extra)?;
+
+        Ok(Renderer::Stateless(Arc::new(StereoVisualizer {
+            extra,
+            spread,
+        })))
+    }
+}
+
+/// Draws every band as a row of light, placed where it sits in the stereo image and blurred by how wide it is.
+pub struct StereoVisualizer {
+    extra: VisualizerInputExtra,
+    spread: BoundParam,
+}
+
+impl StatelessVisualizer for StereoVisualizer {
+    fn render_frame(
+        &self,
+        frame: &AnalysisFrame,
+        mut canvas: Canvas<&mut [u8]>,
+    ) -> anyhow::Result<()> {
+        let width = self.extra.width as usize;
+        let height = self.extra.height as usize;
+        let stereo = &frame.stereo;
+        let spread_scale = self.spread.at(frame).max(0.0);
+
+        // out of phase audio tints everything red
+        let phase_warning = (-stereo.correlation).max(0.0);
+
+        for y in 0..height {
+            // low frequencies at the bottom
+            let band = (height - 1 - y) * self.extra.spectrum_bands / height;
+            let mid = stereo.mid[band];
+            let side = stereo.side[band];
+            let level = mid.max(side);
+            let side_fraction = if mid + side > 0.0 {
+                side / (mid + side)
+            } else {
+                0.0
+            };
+
+            let center = (stereo.pan[band] + 1.0) / 2.0;
+            let spread =
+                ((MIN_SPREAD + SIDE_SPREAD * side_fraction) * spread_scale).max(f32::EPSILON);
+
+            for x in 0..width {
+                let distance = (x as f32 + 0.5) / width as f32 - center;
+                let glow = level * (-(distance * distance) / (2.0 * spread * spread)).exp();
+
+                canvas.set_pixel(
+                    x,
+                    y,
+                    RGB::new(glow * phase_warning, glow * side_fraction, glow),
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,79 @@
+//! This module contains helpers for splitting a frame into tiles that are rendered in parallel
+
+use crate::canvas::Canvas;
+use crate::util::RGB;
+use rand::rngs::SmallRng;
+use rand::SeedableRng;
+use rayon::prelude::*;
+use std::ops::Range;
+
+/// The number of rows in each tile.
+///
+/// This is fixed instead of being derived from the number of threads, so tile boundaries, and with them each tile's
+/// random stream, are the same on every machine.
+pub const TILE_ROWS: usize = 16;
+
+/// A horizontal strip of full-width rows of a frame.
+///
+/// Pixels are addressed with the same coordinates as in the whole frame.
+pub struct Tile<'a> {
+    index: usize,
+    y_start: usize,
+    canvas: Canvas<&'a mut [u8]>,
+}
+
+impl<'a> Tile<'a> {
+    /// The frame rows covered by this tile.
+    pub fn rows(&self) -> Range<usize> {
+        self.y_start..(self.y_start + self.canvas.height() as usize)
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, color: RGB) {
+        self.canvas.set_pixel(x, y - self.y_start, color);
+    }
+
+    /// Creates the random number generator for this tile in the given frame.
+    ///
+    /// The stream only depends on the seed, the frame and the tile, so seeded renders are reproducible no matter
+    /// which thread renders which tile.
+    pub fn rng(&self, seed: u64, frame: u64) -> SmallRng {
+        SmallRng::seed_from_u64(splitmix64(
+            splitmix64(splitmix64(seed).wrapping_add(frame)).wrapping_add(self.index as u64),
+        ))
+    }
+}
+
+/// Renders a frame by calling `render` for every tile on the rayon thread pool.
+pub fn render_tiles<F>(canvas: &mut Canvas<&mut [u8]>, render: F)
+where
+    F: Fn(Tile) + Send + Sync,
+{
+    let width = canvas.width();
+    let height = canvas.height() as usize;
+    let stride = canvas.stride();
+
+    canvas
+        .data_mut()
+        .par_chunks_mut(stride * TILE_ROWS)
+        .enumerate()
+        .for_each(|(index, data)| {
+            let y_start = index * TILE_ROWS;
+            if y_start >= height {
+                return;
+            }
+
+            let rows = TILE_ROWS.min(height - y_start);
+            render(Tile {
+                index,
+                y_start,
+                canvas: Canvas::new(data, width, rows as u32, stride),
+            })
+        });
+}
+
+fn splitmix64(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9E3779B97F4A7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
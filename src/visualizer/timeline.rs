@@ -0,0 +1,380 @@
+use crate::analysis::tempo::TempoMap;
+use crate::analysis::AnalysisFrame;
+use crate::canvas::{BlendMode, Canvas};
+use crate::project::VisualizerEnum;
+use crate::util::Timestamp;
+use crate::visualizer::{Renderer, StatelessVisualizer, Visualizer, VisualizerInputExtra};
+use anyhow::{bail, Context};
+use futures::future::LocalBoxFuture;
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+
+/// A visualizer that takes over from the previous scene at a point in the track.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Scene {
+    /// Where in the input this scene starts.
+    pub start: Timestamp,
+    pub visualizer: VisualizerEnum,
+    /// How the previous scene turns into this one. Ignored for the first scene.
+    #[serde(default)]
+    pub transition: Transition,
+}
+
+#[derive(Debug, Copy, Clone, Default, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Transition {
+    /// Switches at the start of the scene.
+    #[default]
+    Cut,
+    /// Fades from the previous scene into this one, starting at the start of the scene.
+    Crossfade { duration: Timestamp },
+    /// Reveals this scene from left to right, starting at the start of the scene.
+    Wipe { duration: Timestamp },
+    /// Switches on the first beat at or after the start of the scene. Needs a tempo map.
+    CutOnBeat {
+        /// Whether to wait for the first beat of a bar instead.
+        #[serde(default)]
+        downbeat: bool,
+    },
+}
+
+/// Creates every scene's visualizer up front, and a visualizer that switches between them.
+pub async fn new_visualizer(
+    scenes: &[Scene],
+    extra: VisualizerInputExtra,
+) -> anyhow::Result<Renderer> {
+    let schedule = Schedule::new(scenes, extra.tempo.as_deref())?;
+
+    let mut renderers = vec![];
+    for (index, scene) in scenes.iter().enumerate() {
+        let mut scene_extra = extra.clone();
+        scene_extra.param_prefix = format!("{}scenes.{}.", extra.param_prefix, index);
+
+        renderers.push(
+            scene
+                .visualizer
+                .new_visualizer(scene_extra)
+                .await
+                .with_context(|| format!("Creating visualizer of scene {}", index))?,
+        );
+    }
+
+    // the timeline only needs to render frames in order if one of its scenes does
+    if renderers
+        .iter()
+        .all(|renderer| matches!(renderer, Renderer::Stateless(_)))
+    {
+        Ok(Renderer::Stateless(Arc::new(StatelessTimelineVisualizer {
+            extra,
+            schedule,
+            scenes: renderers
+                .into_iter()
+                .map(|renderer| match renderer {
+                    Renderer::Stateless(visualizer) => visualizer,
+                    Renderer::Stateful(_) => unreachable!(),
+                })
+                .collect(),
+            buffers: Mutex::new(vec![]),
+        })))
+    } else {
+        let buffer = Canvas::blank(extra.width, extra.height);
+        Ok(Renderer::Stateful(Box::new(TimelineVisualizer {
+            schedule,
+            scenes: renderers,
+            buffer,
+        })))
+    }
+}
+
+/// A transition whose timing is known.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Blend {
+    Crossfade,
+    Wipe,
+}
+
+/// When each scene starts and how it is blended in.
+#[derive(Debug, Clone)]
+struct Schedule {
+    /// Where each scene starts and its transition ends.
+    starts: Vec<(Timestamp, Option<(Blend, Timestamp)>)>,
+}
+
+/// What to draw for a single frame.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Step {
+    Scene(usize),
+    /// Blending from the scene before `to` into `to`, `progress` of the way from 0 up to 1.
+    Transition {
+        to: usize,
+        blend: Blend,
+        progress: f32,
+    },
+}
+
+impl Schedule {
+    fn new(scenes: &[Scene], tempo: Option<&TempoMap>) -> anyhow::Result<Schedule> {
+        if scenes.is_empty() {
+            bail!("A timeline needs at least one scene");
+        }
+
+        let mut starts: Vec<(Timestamp, Option<(Blend, Timestamp)>)> = vec![];
+        for (index, scene) in scenes.iter().enumerate() {
+            let (start, blend) = match scene.transition {
+                Transition::Cut => (scene.start, None),
+                Transition::Crossfade { duration } => {
+                    (scene.start, Some((Blend::Crossfade, duration)))
+                }
+                Transition::Wipe { duration } => (scene.start, Some((Blend::Wipe, duration))),
+                Transition::CutOnBeat { downbeat } => {
+                    let Some(tempo) = tempo else {
+                        bail!(
+                            "Scene {} cuts on the beat, which needs a tempo map or a two-pass render",
+                            index
+                        );
+                    };
+                    // without a beat to cut on before the next scene starts, cut where this one starts
+                    let next_start = scenes.get(index + 1).map(|next| next.start);
+                    let start = tempo
+                        .next_beat(scene.start, downbeat)
+                        .filter(|&beat| next_start.is_none_or(|next_start| beat < next_start))
+                        .unwrap_or(scene.start);
+                    (start, None)
+                }
+            };
+
+            if let Some(&(previous, _)) = starts.last() {
+                if start <= previous {
+                    bail!("Scene {} does not start after the scene before it", index);
+                }
+            }
+
+            let blend = blend.filter(|&(_, duration)| index > 0 && duration > Timestamp::ZERO);
+            starts.push((start, blend));
+        }
+
+        Ok(Schedule { starts })
+    }
+
+    /// Finds what to draw at a point in time. Frames before the first scene show the first scene.
+    fn at(&self, time: Timestamp) -> Step {
+        let index = self
+            .starts
+            .iter()
+            .rposition(|&(start, _)| start <= time)
+            .unwrap_or(0);
+
+        match self.starts[index] {
+            (start, Some((blend, duration))) if time < start + duration => Step::Transition {
+                to: index,
+                blend,
+                progress: (time.saturating_sub(start).as_secs_f64() / duration.as_secs_f64())
+                    as f32,
+            },
+            _ => Step::Scene(index),
+        }
+    }
+}
+
+/// Draws the scene being transitioned to in `buffer` on top of the one being transitioned from in `canvas`.
+fn blend_transition(
+    canvas: &mut Canvas<&mut [u8]>,
+    buffer: &Canvas<Vec<u8>>,
+    blend: Blend,
+    progress: f32,
+) {
+    match blend {
+        Blend::Crossfade => canvas.blend_from(buffer, BlendMode::Normal, progress),
+        Blend::Wipe => {
+            let edge = (progress * canvas.width() as f32).round() as usize * 4;
+            for y in 0..canvas.height() as usize {
+                canvas.row_mut(y)[..edge].copy_from_slice(&buffer.row(y)[..edge]);
+            }
+        }
+    }
+}
+
+/// Switches between scenes where at least one of them has to render frames in order.
+///
+/// Scenes only see the frames they are visible in.
+pub struct TimelineVisualizer {
+    schedule: Schedule,
+    scenes: Vec<Renderer>,
+    /// Where the incoming scene of a transition is rendered before it is blended onto the output.
+    buffer: Canvas<Vec<u8>>,
+}
+
+impl Visualizer for TimelineVisualizer {
+    fn render_frame<'a>(
+        &'a mut self,
+        frame: &'a AnalysisFrame,
+        mut canvas: Canvas<&'a mut [u8]>,
+    ) -> LocalBoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            match self.schedule.at(frame.time) {
+                Step::Scene(index) => self.scenes[index].render_frame(frame, canvas).await,
+                Step::Transition {
+                    to,
+                    blend,
+                    progress,
+                } => {
+                    self.scenes[to - 1]
+                        .render_frame(frame, canvas.view_mut())
+                        .await?;
+                    self.scenes[to]
+                        .render_frame(frame, self.buffer.view_mut())
+                        .await?;
+                    blend_transition(&mut canvas, &self.buffer, blend, progress);
+                    Ok(())
+                }
+            }
+        }
+        .boxed_local()
+    }
+}
+
+/// Switches between scenes that can all render any frame at any time.
+pub struct StatelessTimelineVisualizer {
+    extra: VisualizerInputExtra,
+    schedule: Schedule,
+    scenes: Vec<Arc<dyn StatelessVisualizer>>,
+    /// Spare transition buffers, shared by all frames being rendered.
+    buffers: Mutex<Vec<Canvas<Vec<u8>>>>,
+}
+
+impl StatelessVisualizer for StatelessTimelineVisualizer {
+    fn render_frame(
+        &self,
+        frame: &AnalysisFrame,
+        mut canvas: Canvas<&mut [u8]>,
+    ) -> anyhow::Result<()> {
+        let (to, blend, progress) = match self.schedule.at(frame.time) {
+            Step::Scene(index) => return self.scenes[index].render_frame(frame, canvas),
+            Step::Transition {
+                to,
+                blend,
+                progress,
+            } => (to, blend, progress),
+        };
+
+        let mut buffer = self
+            .buffers
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| Canvas::blank(self.extra.width, self.extra.height));
+
+        let result = self.scenes[to - 1]
+            .render_frame(frame, canvas.view_mut())
+            .and_then(|_| self.scenes[to].render_frame(frame, buffer.view_mut()));
+        if result.is_ok() {
+            blend_transition(&mut canvas, &buffer, blend, progress);
+        }
+
+        self.buffers.lock().unwrap().push(buffer);
+        result
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::analysis::tempo::TempoSegment;
+    use crate::visualizer::credits::CreditsVisualizerInput;
+
+    /// 120 BPM in 4/4 with the first beat at 0.1 s.
+    fn tempo() -> TempoMap {
+        TempoMap {
+            beats_per_bar: 4,
+            end: Timestamp::from_micros(60_000_000),
+            segments: vec![TempoSegment {
+                start: Timestamp::ZERO,
+                bpm: 120.0,
+                first_beat: Timestamp::from_micros(100_000),
+                first_beat_number: 0,
+            }],
+        }
+    }
+
+    fn scene(start: u64, transition: Transition) -> Scene {
+        Scene {
+            start: Timestamp::from_micros(start),
+            visualizer: VisualizerEnum::Credits(CreditsVisualizerInput {}),
+            transition,
+        }
+    }
+
+    #[test]
+    fn scenes_switch_with_their_transitions() {
+        let tempo = tempo();
+
+        let scenes = [
+            scene(1_000_000, Transition::Cut),
+            scene(
+                10_000_000,
+                Transition::Crossfade {
+                    duration: Timestamp::from_micros(2_000_000),
+                },
+            ),
+            scene(21_000_000, Transition::CutOnBeat { downbeat: true }),
+        ];
+        let schedule = Schedule::new(&scenes, Some(&tempo)).unwrap();
+
+        assert_eq!(schedule.at(Timestamp::ZERO), Step::Scene(0));
+        assert_eq!(
+            schedule.at(Timestamp::from_micros(9_999_999)),
+            Step::Scene(0)
+        );
+        assert_eq!(
+            schedule.at(Timestamp::from_micros(11_000_000)),
+            Step::Transition {
+                to: 1,
+                blend: Blend::Crossfade,
+                progress: 0.5
+            }
+        );
+        assert_eq!(
+            schedule.at(Timestamp::from_micros(12_000_000)),
+            Step::Scene(1)
+        );
+
+        // bars start every 2 s from 0.1 s, so the first one after 21 s is at 22.1 s
+        assert_eq!(
+            schedule.at(Timestamp::from_micros(22_000_000)),
+            Step::Scene(1)
+        );
+        assert_eq!(
+            schedule.at(Timestamp::from_micros(22_100_000)),
+            Step::Scene(2)
+        );
+
+        assert!(Schedule::new(&scenes, None).is_err());
+        assert!(Schedule::new(
+            &[scene(1, Transition::Cut), scene(1, Transition::Cut)],
+            None
+        )
+        .is_err());
+    }
+    #[test]
+    fn beat_cuts_that_would_pass_the_next_scene_cut_at_the_scene_start() {
+        let tempo = tempo();
+
+        // the next beat after 10 s is at 10.1 s, after the third scene has started
+        let scenes = [
+            scene(1_000_000, Transition::Cut),
+            scene(10_000_000, Transition::CutOnBeat { downbeat: false }),
+            scene(10_050_000, Transition::Cut),
+        ];
+        let schedule = Schedule::new(&scenes, Some(&tempo)).unwrap();
+
+        assert_eq!(
+            schedule.at(Timestamp::from_micros(10_000_000)),
+            Step::Scene(1)
+        );
+        assert_eq!(
+            schedule.at(Timestamp::from_micros(10_050_000)),
+            Step::Scene(2)
+        );
+    }
+}
@@ -0,0 +1,312 @@
+use crate::analysis::AnalysisFrame;
+use crate::canvas::Canvas;
+use crate::util::{Timestamp, RGB};
+use crate::visualizer::{Renderer, StatelessVisualizer, Visualizer};
+use futures::future::LocalBoxFuture;
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+const GLYPH_WIDTH: usize = 5;
+const GLYPH_HEIGHT: usize = 7;
+/// How many font pixels of the title fit into the height of the video.
+const TITLE_SCALE_DIVISOR: u32 = 180;
+
+/// How title cards are shown.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TitleCardConfig {
+    /// How long each card stays on screen, including its fades.
+    pub duration: Timestamp,
+    /// How long each card takes to fade in and to fade out.
+    pub fade: Timestamp,
+}
+
+impl Default for TitleCardConfig {
+    fn default() -> Self {
+        TitleCardConfig {
+            duration: Timestamp::from_micros(4_000_000),
+            fade: Timestamp::from_micros(500_000),
+        }
+    }
+}
+
+/// Text shown over the visualizer from a point in the input.
+#[derive(Debug, Clone)]
+pub struct TitleCard {
+    pub start: Timestamp,
+    pub title: String,
+    /// A smaller line below the title.
+    pub subtitle: Option<String>,
+}
+
+/// Draws title cards over whatever the wrapped visualizer renders.
+pub fn with_title_cards(
+    renderer: Renderer,
+    cards: Vec<TitleCard>,
+    config: TitleCardConfig,
+) -> Renderer {
+    let cards = TitleCards { cards, config };
+    match renderer {
+        Renderer::Stateful(inner) => {
+            Renderer::Stateful(Box::new(TitledVisualizer { inner, cards }))
+        }
+        Renderer::Stateless(inner) => {
+            Renderer::Stateless(Arc::new(StatelessTitledVisualizer { inner, cards }))
+        }
+    }
+}
+
+struct TitleCards {
+    cards: Vec<TitleCard>,
+    config: TitleCardConfig,
+}
+
+impl TitleCards {
+    /// Finds the card on screen at a point in time, and how opaque it is.
+    fn at(&self, time: Timestamp) -> Option<(&TitleCard, f32)> {
+        let card = self.cards.iter().rev().find(|card| card.start <= time)?;
+
+        let shown = time.saturating_sub(card.start);
+        if shown >= self.config.duration {
+            return None;
+        }
+        let remaining = self.config.duration.saturating_sub(shown);
+
+        let fade = self.config.fade.as_secs_f64();
+        let opacity = if fade > 0.0 {
+            (shown.min(remaining).as_secs_f64() / fade).min(1.0) as f32
+        } else {
+            1.0
+        };
+
+        Some((card, opacity))
+    }
+
+    fn draw(&self, time: Timestamp, canvas: &mut Canvas<&mut [u8]>) {
+        let Some((card, opacity)) = self.at(time) else {
+            return;
+        };
+
+        let scale = (canvas.height() / TITLE_SCALE_DIVISOR).max(1) as usize;
+        let subtitle_scale = (scale * 2 / 3).max(1);
+        let margin = canvas.height() as usize / 20;
+
+        // the subtitle sits in the bottom left corner, with the title above it
+        let mut y = canvas.height() as usize - margin;
+        let mut lines = vec![];
+        if let Some(subtitle) = &card.subtitle {
+            y = y.saturating_sub(GLYPH_HEIGHT * subtitle_scale);
+            lines.push((subtitle.as_str(), subtitle_scale, y));
+            y = y.saturating_sub(GLYPH_HEIGHT * subtitle_scale / 2);
+        }
+        y = y.saturating_sub(GLYPH_HEIGHT * scale);
+        lines.push((card.title.as_str(), scale, y));
+
+        for (text, scale, y) in lines {
+            // a drop shadow keeps the text readable over bright visualizers
+            let shadow = scale.div_ceil(3);
+            draw_text(
+                canvas,
+                margin + shadow,
+                y + shadow,
+                scale,
+                text,
+                RGB::ZERO,
+                opacity * 0.75,
+            );
+            draw_text(
+                canvas,
+                margin,
+                y,
+                scale,
+                text,
+                RGB::new(1.0, 1.0, 1.0),
+                opacity,
+            );
+        }
+    }
+}
+
+/// Draws a line of text with its top left corner at `x`, `y`, cutting it off at the edges of the canvas.
+pub fn draw_text(
+    canvas: &mut Canvas<&mut [u8]>,
+    x: usize,
+    y: usize,
+    scale: usize,
+    text: &str,
+    color: RGB,
+    opacity: f32,
+) {
+    for (index, c) in text.chars().enumerate() {
+        let glyph = glyph(c);
+        let left = x + index * (GLYPH_WIDTH + 1) * scale;
+
+        for (row, bits) in glyph.iter().enumerate() {
+            for column in 0..GLYPH_WIDTH {
+                if bits & (1 << (GLYPH_WIDTH - 1 - column)) == 0 {
+                    continue;
+                }
+
+                for py in y + row * scale..y + (row + 1) * scale {
+                    for px in left + column * scale..left + (column + 1) * scale {
+                        if !canvas.contains(px, py) {
+                            continue;
+                        }
+
+                        let blended =
+                            canvas.get_pixel(px, py).scale(1.0 - opacity) + color.scale(opacity);
+                        canvas.set_pixel(px, py, blended);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Gets the rows of a character in a 5 by 7 pixel font, the leftmost pixel in the highest bit.
+///
+/// Letters are all drawn in upper case, and characters outside of the font as question marks.
+#[rustfmt::skip]
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT] {
+    match c.to_ascii_uppercase() {
+        ' ' => [0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000],
+        'A' => [0b01110, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001, 0b10001],
+        'B' => [0b11110, 0b10001, 0b10001, 0b11110, 0b10001, 0b10001, 0b11110],
+        'C' => [0b01110, 0b10001, 0b10000, 0b10000, 0b10000, 0b10001, 0b01110],
+        'D' => [0b11110, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b11110],
+        'E' => [0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b11111],
+        'F' => [0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b10000],
+        'G' => [0b01110, 0b10001, 0b10000, 0b10111, 0b10001, 0b10001, 0b01111],
+        'H' => [0b10001, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001, 0b10001],
+        'I' => [0b01110, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110],
+        'J' => [0b00111, 0b00010, 0b00010, 0b00010, 0b00010, 0b10010, 0b01100],
+        'K' => [0b10001, 0b10010, 0b10100, 0b11000, 0b10100, 0b10010, 0b10001],
+        'L' => [0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b11111],
+        'M' => [0b10001, 0b11011, 0b10101, 0b10101, 0b10001, 0b10001, 0b10001],
+        'N' => [0b10001, 0b10001, 0b11001, 0b10101, 0b10011, 0b10001, 0b10001],
+        'O' => [0b01110, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110],
+        'P' => [0b11110, 0b10001, 0b10001, 0b11110, 0b10000, 0b10000, 0b10000],
+        'Q' => [0b01110, 0b10001, 0b10001, 0b10001, 0b10101, 0b10010, 0b01101],
+        'R' => [0b11110, 0b10001, 0b10001, 0b11110, 0b10100, 0b10010, 0b10001],
+        'S' => [0b01111, 0b10000, 0b10000, 0b01110, 0b00001, 0b00001, 0b11110],
+        'T' => [0b11111, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100],
+        'U' => [0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110],
+        'V' => [0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01010, 0b00100],
+        'W' => [0b10001, 0b10001, 0b10001, 0b10101, 0b10101, 0b10101, 0b01010],
+        'X' => [0b10001, 0b10001, 0b01010, 0b00100, 0b01010, 0b10001, 0b10001],
+        'Y' => [0b10001, 0b10001, 0b10001, 0b01010, 0b00100, 0b00100, 0b00100],
+        'Z' => [0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b10000, 0b11111],
+        '0' => [0b01110, 0b10001, 0b10011, 0b10101, 0b11001, 0b10001, 0b01110],
+        '1' => [0b00100, 0b01100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110],
+        '2' => [0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0b01000, 0b11111],
+        '3' => [0b11111, 0b00010, 0b00100, 0b00010, 0b00001, 0b10001, 0b01110],
+        '4' => [0b00010, 0b00110, 0b01010, 0b10010, 0b11111, 0b00010, 0b00010],
+        '5' => [0b11111, 0b10000, 0b11110, 0b00001, 0b00001, 0b10001, 0b01110],
+        '6' => [0b00110, 0b01000, 0b10000, 0b11110, 0b10001, 0b10001, 0b01110],
+        '7' => [0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b01000, 0b01000],
+        '8' => [0b01110, 0b10001, 0b10001, 0b01110, 0b10001, 0b10001, 0b01110],
+        '9' => [0b01110, 0b10001, 0b10001, 0b01111, 0b00001, 0b00010, 0b01100],
+        '.' => [0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b01100, 0b01100],
+        ',' => [0b00000, 0b00000, 0b00000, 0b00000, 0b01100, 0b00100, 0b01000],
+        ':' => [0b00000, 0b01100, 0b01100, 0b00000, 0b01100, 0b01100, 0b00000],
+        ';' => [0b00000, 0b01100, 0b01100, 0b00000, 0b01100, 0b00100, 0b01000],
+        '\'' => [0b01100, 0b00100, 0b01000, 0b00000, 0b00000, 0b00000, 0b00000],
+        '"' => [0b01010, 0b01010, 0b01010, 0b00000, 0b00000, 0b00000, 0b00000],
+        '!' => [0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00000, 0b00100],
+        '-' => [0b00000, 0b00000, 0b00000, 0b11111, 0b00000, 0b00000, 0b00000],
+        '_' => [0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b11111],
+        '(' => [0b00010, 0b00100, 0b01000, 0b01000, 0b01000, 0b00100, 0b00010],
+        ')' => [0b01000, 0b00100, 0b00010, 0b00010, 0b00010, 0b00100, 0b01000],
+        '[' => [0b01110, 0b01000, 0b01000, 0b01000, 0b01000, 0b01000, 0b01110],
+        ']' => [0b01110, 0b00010, 0b00010, 0b00010, 0b00010, 0b00010, 0b01110],
+        '&' => [0b01100, 0b10010, 0b10100, 0b01000, 0b10101, 0b10010, 0b01101],
+        '/' => [0b00000, 0b00001, 0b00010, 0b00100, 0b01000, 0b10000, 0b00000],
+        '+' => [0b00000, 0b00100, 0b00100, 0b11111, 0b00100, 0b00100, 0b00000],
+        '#' => [0b01010, 0b01010, 0b11111, 0b01010, 0b11111, 0b01010, 0b01010],
+        '*' => [0b00000, 0b00100, 0b10101, 0b01110, 0b10101, 0b00100, 0b00000],
+        '=' => [0b00000, 0b00000, 0b11111, 0b00000, 0b11111, 0b00000, 0b00000],
+        _ => [0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0b00000, 0b00100],
+    }
+}
+
+/// Draws title cards over a visualizer that has to render frames in order.
+pub struct TitledVisualizer {
+    inner: Box<dyn Visualizer>,
+    cards: TitleCards,
+}
+
+impl Visualizer for TitledVisualizer {
+    fn render_frame<'a>(
+        &'a mut self,
+        frame: &'a AnalysisFrame,
+        mut canvas: Canvas<&'a mut [u8]>,
+    ) -> LocalBoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            self.inner.render_frame(frame, canvas.view_mut()).await?;
+            self.cards.draw(frame.time, &mut canvas);
+            Ok(())
+        }
+        .boxed_local()
+    }
+}
+
+/// Draws title cards over a visualizer that can render any frame at any time.
+pub struct StatelessTitledVisualizer {
+    inner: Arc<dyn StatelessVisualizer>,
+    cards: TitleCards,
+}
+
+impl StatelessVisualizer for StatelessTitledVisualizer {
+    fn render_frame(
+        &self,
+        frame: &AnalysisFrame,
+        mut canvas: Canvas<&mut [u8]>,
+    ) -> anyhow::Result<()> {
+        self.inner.render_frame(frame, canvas.view_mut())?;
+        self.cards.draw(frame.time, &mut canvas);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    #[test]
+    fn title_cards_fade_in_and_out_over_the_video() {
+        let seconds = |seconds: f64| Timestamp::from_micros((seconds * 1e6) as u64);
+        let cards = TitleCards {
+            cards: vec![
+                TitleCard {
+                    start: seconds(0.0),
+                    title: "Intro".to_string(),
+                    subtitle: None,
+                },
+                TitleCard {
+                    start: seconds(60.0),
+                    title: "Second Song".to_string(),
+                    subtitle: Some("Artist".to_string()),
+                },
+            ],
+            config: TitleCardConfig::default(),
+        };
+
+        assert_eq!(cards.at(seconds(0.25)).unwrap().1, 0.5);
+        assert_eq!(cards.at(seconds(2.0)).unwrap().1, 1.0);
+        assert!(cards.at(seconds(4.0)).is_none());
+        assert!(cards.at(seconds(30.0)).is_none());
+
+        let (card, opacity) = cards.at(seconds(63.75)).unwrap();
+        assert_eq!(card.title, "Second Song");
+        assert_eq!(opacity, 0.5);
+
+        let mut canvas = Canvas::blank(320, 180);
+        cards.draw(seconds(61.0), &mut canvas.view_mut());
+        let lit = (0..180)
+            .flat_map(|y| (0..320).map(move |x| (x, y)))
+            .filter(|&(x, y)| canvas.get_pixel(x, y).r > 0.9)
+            .count();
+        assert!(lit > 0, "no text was drawn");
+    }
+}